@@ -0,0 +1,107 @@
+//! Measures the prefill latency of a synthetic sequence of Copilot-like prompts, with and without reusing the KV
+//! cache of the previous prompt.
+//!
+//! Copilot sends a new prompt on every keystroke, each one is the previous prompt plus one token, or minus a few
+//! tokens after a backspace. A tiny randomly initialized llama model is used so the benchmark runs without
+//! downloading anything, the speedup on a real model is larger because prefill dominates even more.
+//!
+//! ```sh
+//! cargo bench --bench prefix_cache --features bench
+//! ```
+use std::time::{Duration, Instant};
+
+use candle_core::{Result, Tensor};
+use oxpilot::models::quantized_llama::ModelWeights;
+use oxpilot::process::prefill;
+use oxpilot::testing::{tiny_model, TinyConfig};
+
+const VOCAB_SIZE: usize = 1024;
+const PROMPT_LEN: usize = 1024;
+const KEYSTROKES: usize = 32;
+
+/// A deterministic sequence of prompts: a long initial prompt, then one token per keystroke, with a backspace
+/// removing the last 3 tokens every 8 keystrokes.
+fn synthetic_prompts() -> Vec<Vec<u32>> {
+    // a tiny linear congruential generator, we only need reproducible token ids
+    let mut state: u64 = 299792458;
+    let mut next_token = move || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((state >> 33) % VOCAB_SIZE as u64) as u32
+    };
+    let mut prompt: Vec<u32> = (0..PROMPT_LEN).map(|_| next_token()).collect();
+    let mut prompts = vec![prompt.clone()];
+    for keystroke in 1..=KEYSTROKES {
+        if keystroke % 8 == 0 {
+            prompt.truncate(prompt.len() - 3);
+        } else {
+            prompt.push(next_token());
+        }
+        prompts.push(prompt.clone());
+    }
+    prompts
+}
+
+/// Prefills every prompt, returns the total time spent on the keystroke prompts (the first prompt is always fully
+/// evaluated) and the logits of the last token of each prompt.
+fn run(
+    model: &mut ModelWeights,
+    prompts: &[Vec<u32>],
+    reuse_prefix: bool,
+) -> Result<(Duration, Vec<Tensor>)> {
+    let mut cached_tokens = vec![];
+    let mut elapsed = Duration::ZERO;
+    let mut all_logits = vec![];
+    for (index, prompt) in prompts.iter().enumerate() {
+        if !reuse_prefix {
+            cached_tokens.clear();
+        }
+        let started_at = Instant::now();
+        let logits = prefill(model, &mut cached_tokens, prompt)?;
+        if index > 0 {
+            elapsed += started_at.elapsed();
+        }
+        all_logits.push(logits);
+    }
+    Ok((elapsed, all_logits))
+}
+
+fn main() -> Result<()> {
+    let mut model = tiny_model(&TinyConfig {
+        vocab_size: VOCAB_SIZE,
+        embedding_length: 256,
+        feed_forward_length: 768,
+        head_count: 8,
+        head_count_kv: 8,
+        block_count: 4,
+        std: 0.02,
+    })?;
+    let prompts = synthetic_prompts();
+
+    let (without_cache, expected) = run(&mut model, &prompts, false)?;
+    let (with_cache, actual) = run(&mut model, &prompts, true)?;
+
+    // reusing the cache must not change the result
+    for (expected, actual) in expected.iter().zip(actual.iter()) {
+        let diff = (expected - actual)?.abs()?.max(0)?.to_scalar::<f32>()?;
+        assert!(diff < 1e-3, "logits differ by {diff}");
+    }
+
+    println!("{} keystrokes on a {} token prompt", KEYSTROKES, PROMPT_LEN);
+    println!(
+        "  full prefill:        {:>10.2?} ({:.2?}/keystroke)",
+        without_cache,
+        without_cache / KEYSTROKES as u32
+    );
+    println!(
+        "  prefix cache reuse:  {:>10.2?} ({:.2?}/keystroke)",
+        with_cache,
+        with_cache / KEYSTROKES as u32
+    );
+    println!(
+        "  speedup:             {:>10.1}x",
+        without_cache.as_secs_f64() / with_cache.as_secs_f64()
+    );
+    Ok(())
+}
@@ -0,0 +1,688 @@
+//! The cache of the hub repos, `~/.oxpilot` by default, and `Hub` which resolves the repo files through it.
+//!
+//! The cache has the layout of the cache of `huggingface_hub`: the content of a file is stored once in
+//! `models--{org}--{name}/blobs/{etag}`, `snapshots/{commit}/{file_name}` links to the blob, and `refs/{revision}`
+//! holds the commit of a revision. The etag of the files stored with Git LFS, e.g. GGUF or safetensors files, is the
+//! sha256 of their content, the other files are checked against the sha256 recorded when they were first resolved.
+//!
+//! `usage.json` records when each file was last resolved by `Hub::get`, so that `ox models gc` removes the models
+//! that haven't been used for the longest time.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::models::safetensors;
+
+/// When each file was last used, next to the repos.
+const USAGE: &str = "usage.json";
+
+/// `~/.oxpilot`, the cache of `ox` unless `LLMBuilder::cache_dir` is set.
+pub fn default_dir() -> Result<PathBuf> {
+    let mut home = dirs::home_dir().context("Failed to get user home dir")?;
+    home.push(".oxpilot");
+    Ok(home)
+}
+
+/// Resolves the files of the hub repos, downloading them to `cache_dir` when they are not cached yet.
+pub struct Hub {
+    pub cache_dir: PathBuf,
+    /// Only look into `cache_dir`, the hub is never contacted.
+    pub offline: bool,
+}
+
+impl Hub {
+    pub async fn get(&self, repo_id: &str, revision: &str, file_name: &str) -> Result<PathBuf> {
+        let repo = hf_hub::Repo::with_revision(
+            repo_id.to_owned(),
+            hf_hub::RepoType::Model,
+            revision.to_owned(),
+        );
+        let path = if self.offline {
+            // `models--{org}--{name}/refs/{revision}` points to `models--{org}--{name}/snapshots/{commit}/{file_name}`
+            hf_hub::Cache::new(self.cache_dir.clone())
+                .repo(repo)
+                .get(file_name)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{} of {} (revision {}) is not in the cache {}, run `ox models pull {} {}` or the same command once without --offline to download it",
+                        file_name,
+                        repo_id,
+                        revision,
+                        self.cache_dir.display(),
+                        repo_id,
+                        file_name
+                    )
+                })?
+        } else {
+            tokio::fs::create_dir_all(&self.cache_dir)
+                .await
+                .context("Failed to crate cache dir for hf_hub_api")?;
+            let hf_hub_api = hf_hub::api::tokio::ApiBuilder::new()
+                .with_progress(true)
+                .with_cache_dir(self.cache_dir.clone())
+                .build()
+                .map_err(|error| {
+                    anyhow!("hf_hub_api initialization failed because of {:?}", error)
+                })?;
+            hf_hub_api.repo(repo).get(file_name).await?
+        };
+        // the usage is only bookkeeping, the file is there anyway
+        if let Err(error) = Cache::new(self.cache_dir.clone()).record_use(repo_id, file_name, &path)
+        {
+            warn!(
+                "failed to record the use of {} of {}: {}",
+                file_name, repo_id, error
+            );
+        }
+        Ok(path)
+    }
+
+    /// Downloads `file_name`, and for a safetensors checkpoint the other files `LLMBuilder` needs: `config.json`,
+    /// the shards listed by the index and the tokenizer.
+    pub async fn pull(
+        &self,
+        repo_id: &str,
+        revision: &str,
+        file_name: &str,
+    ) -> Result<Vec<PathBuf>> {
+        let path = self.get(repo_id, revision, file_name).await?;
+        let mut paths = vec![path.clone()];
+        if safetensors::is_safetensors(file_name) {
+            paths.push(self.get(repo_id, revision, safetensors::CONFIG).await?);
+            if file_name.ends_with(".index.json") {
+                for shard_file_name in safetensors::shard_file_names(&path)? {
+                    paths.push(self.get(repo_id, revision, &shard_file_name).await?);
+                }
+            }
+            // the tokenizer may come from another repo
+            for tokenizer_file_name in ["tokenizer.json", "tokenizer_config.json"] {
+                if let Ok(path) = self.get(repo_id, revision, tokenizer_file_name).await {
+                    paths.push(path);
+                }
+            }
+        }
+        Ok(paths)
+    }
+}
+
+/// A file of a repo in the cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CachedFile {
+    pub repo_id: String,
+    /// The revision of the snapshot, e.g. "main", or its commit when no revision points to it anymore.
+    pub revision: String,
+    /// The path in the repo, e.g. "model.gguf" or "onnx/model.onnx".
+    pub file_name: String,
+    /// In `snapshots/`, usually a link to `blob`.
+    pub path: PathBuf,
+    pub blob: PathBuf,
+    pub size: u64,
+    /// When `Hub::get` last resolved the file, in seconds since the Unix epoch, `None` when the file was downloaded
+    /// before the usage was recorded, or by another tool.
+    pub last_used: Option<u64>,
+}
+
+impl CachedFile {
+    /// The weights, as opposed to the tokenizers and configs.
+    pub fn is_model(&self) -> bool {
+        self.file_name.ends_with(".gguf") || safetensors::is_safetensors(&self.file_name)
+    }
+
+    /// The files of one model, every GGUF file is a model, while the safetensors files of a repo are the shards of
+    /// one model.
+    fn model_key(&self) -> String {
+        if safetensors::is_safetensors(&self.file_name) {
+            self.repo_id.clone()
+        } else {
+            format!("{}/{}", self.repo_id, self.file_name)
+        }
+    }
+}
+
+/// The result of `Cache::verify`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verification {
+    /// The sha256 of the content matches the expected one.
+    Valid,
+    /// The content changed since it was downloaded, e.g. an interrupted copy or a disk error.
+    Corrupted { expected: String, actual: String },
+    /// Neither the hub nor `usage.json` provides a sha256 to compare to.
+    Unknown { actual: String },
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Usage {
+    /// Seconds since the Unix epoch.
+    last_used: Option<u64>,
+    /// The sha256 of the files that are not named by their sha256 in `blobs/`.
+    sha256: Option<String>,
+}
+
+/// The usage of the files of each repo, `BTreeMap` keeps `usage.json` sorted.
+type Usages = BTreeMap<String, BTreeMap<String, Usage>>;
+
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Cache { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Every file of every repo in the cache, sorted by repo and file name.
+    pub fn list(&self) -> Result<Vec<CachedFile>> {
+        let usages = self.read_usages()?;
+        let mut files = vec![];
+        // nothing has been downloaded yet
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return Ok(files);
+        };
+        for entry in entries {
+            let entry = entry?;
+            let directory_name = entry.file_name().to_string_lossy().to_string();
+            let Some(repo_name) = directory_name.strip_prefix("models--") else {
+                continue;
+            };
+            let repo_id = repo_name.replace("--", "/");
+            let repo_dir = entry.path();
+            // the revision pointing to each commit, e.g. "main"
+            let mut revisions = HashMap::new();
+            if let Ok(refs) = std::fs::read_dir(repo_dir.join("refs")) {
+                for reference in refs.flatten() {
+                    if let Ok(commit) = std::fs::read_to_string(reference.path()) {
+                        let revision = reference.file_name().to_string_lossy().to_string();
+                        revisions.insert(commit.trim().to_string(), revision);
+                    }
+                }
+            }
+            let Ok(snapshots) = std::fs::read_dir(repo_dir.join("snapshots")) else {
+                continue;
+            };
+            for snapshot in snapshots.flatten() {
+                let commit = snapshot.file_name().to_string_lossy().to_string();
+                let revision = revisions.get(&commit).cloned().unwrap_or(commit);
+                for path in walk(&snapshot.path())? {
+                    // a link to a blob removed by hand
+                    let Ok(metadata) = std::fs::metadata(&path) else {
+                        continue;
+                    };
+                    let file_name = path
+                        .strip_prefix(snapshot.path())?
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/");
+                    let last_used = usages
+                        .get(&repo_id)
+                        .and_then(|files| files.get(&file_name))
+                        .and_then(|usage| usage.last_used);
+                    files.push(CachedFile {
+                        repo_id: repo_id.clone(),
+                        revision: revision.clone(),
+                        file_name,
+                        blob: std::fs::canonicalize(&path)?,
+                        path,
+                        size: metadata.len(),
+                        last_used,
+                    });
+                }
+            }
+        }
+        files.sort_by(|a, b| {
+            (&a.repo_id, &a.file_name, &a.revision).cmp(&(&b.repo_id, &b.file_name, &b.revision))
+        });
+        Ok(files)
+    }
+
+    /// Removes a file of a repo, in every revision, or the whole repo when `file_name` is `None`. The blobs still
+    /// linked from another snapshot are kept.
+    pub fn remove(&self, repo_id: &str, file_name: Option<&str>) -> Result<Vec<CachedFile>> {
+        let (removed, kept): (Vec<_>, Vec<_>) = self.list()?.into_iter().partition(|file| {
+            file.repo_id == repo_id && file_name.is_none_or(|name| name == file.file_name)
+        });
+        if removed.is_empty() {
+            return Err(anyhow!(
+                "{} is not in the cache {}",
+                match file_name {
+                    Some(file_name) => format!("{} of {}", file_name, repo_id),
+                    None => repo_id.to_string(),
+                },
+                self.dir.display()
+            ));
+        }
+        for file in &removed {
+            std::fs::remove_file(&file.path)
+                .with_context(|| format!("Failed to remove {}", file.path.display()))?;
+            let is_shared = kept.iter().any(|kept| kept.blob == file.blob);
+            // several removed files may share a blob, the first one removes it
+            if !is_shared && file.blob.exists() {
+                std::fs::remove_file(&file.blob)
+                    .with_context(|| format!("Failed to remove {}", file.blob.display()))?;
+            }
+        }
+        // the refs and the empty snapshots go with the last file of the repo
+        if !kept.iter().any(|file| file.repo_id == repo_id) {
+            let repo_dir = self
+                .dir
+                .join(format!("models--{}", repo_id.replace('/', "--")));
+            std::fs::remove_dir_all(&repo_dir)
+                .with_context(|| format!("Failed to remove {}", repo_dir.display()))?;
+        }
+
+        let mut usages = self.read_usages()?;
+        if let Some(files) = usages.get_mut(repo_id) {
+            for file in &removed {
+                files.remove(&file.file_name);
+            }
+            if files.is_empty() {
+                usages.remove(repo_id);
+            }
+        }
+        self.write_usages(&usages)?;
+        Ok(removed)
+    }
+
+    /// Removes every model but the `keep` most recently used ones, the files never used by `ox` go first. Returns
+    /// the removed files, nothing is removed with `dry_run`.
+    pub fn gc(&self, keep: usize, dry_run: bool) -> Result<Vec<CachedFile>> {
+        let mut models: Vec<(Option<u64>, Vec<CachedFile>)> = vec![];
+        let mut keys: HashMap<String, usize> = HashMap::new();
+        for file in self.list()?.into_iter().filter(CachedFile::is_model) {
+            let index = *keys.entry(file.model_key()).or_insert_with(|| {
+                models.push((None, vec![]));
+                models.len() - 1
+            });
+            models[index].0 = models[index].0.max(file.last_used);
+            models[index].1.push(file);
+        }
+        // `None` is less than any `Some`, the models never used are removed first
+        models.sort_by_key(|model| std::cmp::Reverse(model.0));
+        let removed: Vec<CachedFile> = models
+            .into_iter()
+            .skip(keep)
+            .flat_map(|(_, files)| files)
+            .collect();
+        if dry_run {
+            return Ok(removed);
+        }
+        for file in &removed {
+            self.remove(&file.repo_id, Some(&file.file_name))?;
+        }
+        // a repo without weights left only holds the tokenizer and config of the removed models
+        let remaining = self.list()?;
+        let repo_ids: BTreeSet<&String> = removed.iter().map(|file| &file.repo_id).collect();
+        for repo_id in repo_ids {
+            let has_files = remaining
+                .iter()
+                .any(|remaining| &remaining.repo_id == repo_id);
+            let has_models = remaining
+                .iter()
+                .any(|remaining| &remaining.repo_id == repo_id && remaining.is_model());
+            if has_files && !has_models {
+                self.remove(repo_id, None)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Compares the sha256 of the content of `file` to the etag of Git LFS files, or to the sha256 recorded when
+    /// the file was first resolved.
+    pub fn verify(&self, file: &CachedFile) -> Result<Verification> {
+        let actual = sha256(&file.blob)?;
+        let blob_name = file
+            .blob
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let expected = if is_sha256(&blob_name) {
+            Some(blob_name)
+        } else {
+            self.read_usages()?
+                .get(&file.repo_id)
+                .and_then(|files| files.get(&file.file_name))
+                .and_then(|usage| usage.sha256.clone())
+        };
+        Ok(match expected {
+            Some(expected) if expected == actual => Verification::Valid,
+            Some(expected) => Verification::Corrupted { expected, actual },
+            None => Verification::Unknown { actual },
+        })
+    }
+
+    /// Records that `file_name` of `repo_id` was used now, and its sha256 the first time when its blob isn't named by
+    /// its sha256.
+    fn record_use(&self, repo_id: &str, file_name: &str, path: &Path) -> Result<()> {
+        let mut usages = self.read_usages()?;
+        let usage = usages
+            .entry(repo_id.to_string())
+            .or_default()
+            .entry(file_name.to_string())
+            .or_default();
+        usage.last_used = Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+        let blob = std::fs::canonicalize(path)?;
+        let is_lfs = blob
+            .file_name()
+            .map(|name| is_sha256(&name.to_string_lossy()))
+            .unwrap_or(false);
+        if usage.sha256.is_none() && !is_lfs {
+            usage.sha256 = Some(sha256(&blob)?);
+        }
+        self.write_usages(&usages)
+    }
+
+    fn read_usages(&self) -> Result<Usages> {
+        let path = self.dir.join(USAGE);
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Usages::default()),
+            Err(error) => Err(error).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Writes to a temporary file first, so that concurrent `ox` processes never read half a file.
+    fn write_usages(&self, usages: &Usages) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(USAGE);
+        let temporary = self.dir.join(format!("{}.{}", USAGE, std::process::id()));
+        let mut file = std::fs::File::create(&temporary)?;
+        file.write_all(serde_json::to_string_pretty(usages)?.as_bytes())?;
+        drop(file);
+        std::fs::rename(&temporary, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// The files under `directory`, recursively.
+fn walk(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(walk(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn is_sha256(name: &str) -> bool {
+    name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The sha256 of the content of `path`, as lowercase hex, streamed so that multi-GB files are not read in memory.
+fn sha256(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A size in bytes for humans, e.g. "4.37 GB".
+pub fn format_size(size: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.2} {}", value, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A repo id and its files, `(file_name, blob name, content)`.
+    type Repo<'a> = (&'a str, Vec<(&'a str, &'a str, &'a str)>);
+
+    /// A cache in `dir` with the layout of the hub cache, the files of each repo in the snapshot of "main".
+    /// `dir` is a `tempfile::tempdir()`, deleted when the test drops it.
+    fn cache_with(dir: &Path, repos: Vec<Repo>) -> Cache {
+        for (repo_id, files) in repos {
+            let repo_dir = dir.join(format!("models--{}", repo_id.replace('/', "--")));
+            std::fs::create_dir_all(repo_dir.join("refs")).unwrap();
+            std::fs::write(repo_dir.join("refs").join("main"), "0123abcd").unwrap();
+            let snapshot = repo_dir.join("snapshots").join("0123abcd");
+            std::fs::create_dir_all(&snapshot).unwrap();
+            std::fs::create_dir_all(repo_dir.join("blobs")).unwrap();
+            for (file_name, blob_name, content) in files {
+                let blob = repo_dir.join("blobs").join(blob_name);
+                std::fs::write(&blob, content).unwrap();
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(&blob, snapshot.join(file_name)).unwrap();
+                #[cfg(not(unix))]
+                std::fs::copy(&blob, snapshot.join(file_name)).unwrap();
+            }
+        }
+        Cache::new(dir)
+    }
+
+    #[test]
+    fn lists_repo_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache_with(
+            dir.path(),
+            vec![
+                (
+                    "TheBloke/Model-GGUF",
+                    vec![("model.Q4_K.gguf", "blob-a", "GGUF")],
+                ),
+                (
+                    "org/tokenizer",
+                    vec![
+                        ("tokenizer.json", "blob-b", "{}"),
+                        ("config.json", "blob-c", "{}"),
+                    ],
+                ),
+            ],
+        );
+        let files = cache.list().unwrap();
+        let names: Vec<_> = files
+            .iter()
+            .map(|file| (file.repo_id.as_str(), file.file_name.as_str(), file.size))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("TheBloke/Model-GGUF", "model.Q4_K.gguf", 4),
+                ("org/tokenizer", "config.json", 2),
+                ("org/tokenizer", "tokenizer.json", 2),
+            ]
+        );
+        assert_eq!(files[0].revision, "main");
+        assert!(files[0].is_model());
+        assert!(!files[1].is_model());
+        assert_eq!(files[0].last_used, None);
+    }
+
+    #[test]
+    fn removes_files_and_empty_repos() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache_with(
+            dir.path(),
+            vec![(
+                "TheBloke/Model-GGUF",
+                vec![
+                    ("model.Q4_K.gguf", "blob-a", "GGUF"),
+                    ("model.Q8_0.gguf", "blob-b", "GGUF"),
+                ],
+            )],
+        );
+        let removed = cache
+            .remove("TheBloke/Model-GGUF", Some("model.Q4_K.gguf"))
+            .unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(!removed[0].blob.exists());
+        assert_eq!(cache.list().unwrap().len(), 1);
+
+        cache.remove("TheBloke/Model-GGUF", None).unwrap();
+        assert!(cache.list().unwrap().is_empty());
+        assert!(!cache.dir().join("models--TheBloke--Model-GGUF").exists());
+        assert!(cache.remove("TheBloke/Model-GGUF", None).is_err());
+    }
+
+    #[test]
+    fn gc_keeps_the_most_recently_used_models() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache_with(
+            dir.path(),
+            vec![
+                (
+                    "TheBloke/Model-GGUF",
+                    vec![
+                        ("model.Q4_K.gguf", "blob-a", "GGUF"),
+                        ("model.Q8_0.gguf", "blob-b", "GGUF"),
+                    ],
+                ),
+                (
+                    "org/model",
+                    vec![
+                        ("model-00001-of-00002.safetensors", "blob-c", "1"),
+                        ("model-00002-of-00002.safetensors", "blob-d", "2"),
+                        ("config.json", "blob-e", "{}"),
+                    ],
+                ),
+            ],
+        );
+        let snapshot = |repo_dir: &str, file_name: &str| {
+            cache
+                .dir()
+                .join(repo_dir)
+                .join("snapshots")
+                .join("0123abcd")
+                .join(file_name)
+        };
+        cache
+            .record_use(
+                "TheBloke/Model-GGUF",
+                "model.Q8_0.gguf",
+                &snapshot("models--TheBloke--Model-GGUF", "model.Q8_0.gguf"),
+            )
+            .unwrap();
+
+        // the shards are one model, Q4_K was never used
+        let removed = cache.gc(1, true).unwrap();
+        let removed: Vec<_> = removed.iter().map(|file| file.file_name.as_str()).collect();
+        assert_eq!(removed.len(), 3);
+        assert!(removed.contains(&"model.Q4_K.gguf"));
+        assert_eq!(cache.list().unwrap().len(), 5);
+
+        cache.gc(1, false).unwrap();
+        let files = cache.list().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].file_name, "model.Q8_0.gguf");
+        assert!(files[0].last_used.is_some());
+    }
+
+    #[test]
+    fn verifies_lfs_and_recorded_hashes() {
+        // the sha256 of "GGUF"
+        let sha256_of_gguf = sha256_of("GGUF");
+        let wrong_sha256 = "0".repeat(64);
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache_with(
+            dir.path(),
+            vec![(
+                "TheBloke/Model-GGUF",
+                vec![
+                    ("model.gguf", sha256_of_gguf.as_str(), "GGUF"),
+                    ("corrupted.gguf", wrong_sha256.as_str(), "GGUF"),
+                    ("config.json", "blob-a", "{}"),
+                ],
+            )],
+        );
+        let files = cache.list().unwrap();
+        let file = |name: &str| files.iter().find(|file| file.file_name == name).unwrap();
+        assert_eq!(
+            cache.verify(file("model.gguf")).unwrap(),
+            Verification::Valid
+        );
+        assert!(matches!(
+            cache.verify(file("corrupted.gguf")).unwrap(),
+            Verification::Corrupted { .. }
+        ));
+        assert!(matches!(
+            cache.verify(file("config.json")).unwrap(),
+            Verification::Unknown { .. }
+        ));
+
+        // recorded the first time the file is used
+        cache
+            .record_use(
+                "TheBloke/Model-GGUF",
+                "config.json",
+                &file("config.json").path,
+            )
+            .unwrap();
+        assert_eq!(
+            cache.verify(file("config.json")).unwrap(),
+            Verification::Valid
+        );
+        std::fs::write(&file("config.json").blob, "{\"changed\": true}").unwrap();
+        assert!(matches!(
+            cache.verify(file("config.json")).unwrap(),
+            Verification::Corrupted { .. }
+        ));
+    }
+
+    fn sha256_of(content: &str) -> String {
+        format!("{:x}", Sha256::digest(content.as_bytes()))
+    }
+
+    #[tokio::test]
+    async fn offline_hub_resolves_from_cache_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache_with(
+            dir.path(),
+            vec![(
+                "TheBloke/Model-GGUF",
+                vec![("model.gguf", "blob-a", "GGUF")],
+            )],
+        );
+        let hub = Hub {
+            cache_dir: cache.dir().to_path_buf(),
+            offline: true,
+        };
+
+        let path = hub
+            .get("TheBloke/Model-GGUF", "main", "model.gguf")
+            .await
+            .unwrap();
+        assert!(path.ends_with("snapshots/0123abcd/model.gguf"));
+        assert!(cache.list().unwrap()[0].last_used.is_some());
+        let error = hub
+            .get("TheBloke/Model-GGUF", "main", "model.Q8_0.gguf")
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("model.Q8_0.gguf of TheBloke/Model-GGUF"));
+        assert!(error.contains("ox models pull TheBloke/Model-GGUF model.Q8_0.gguf"));
+        assert!(error.contains("without --offline"));
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(4_370_000_000), "4.37 GB");
+    }
+}
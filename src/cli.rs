@@ -1,5 +1,11 @@
 use clap::{Parser, Subcommand};
 use std::ffi::OsString;
+use std::path::PathBuf;
+
+use crate::models::safetensors::Precision;
+use crate::quantize::QuantType;
+use crate::speculative::Decoding;
+use crate::utils::fim::FimTemplate;
 
 #[derive(Parser)]
 #[command(name = "ox")]
@@ -32,9 +38,17 @@ pub struct CLI {
     /// The context size to consider for the repeat penalty, default to 64
     #[arg(long, default_value_t = 64)]
     pub repeat_last_n: usize,
-    /// HG tokenizer repo id, default to "mistralai/Mistral-7B-v0.1"
-    #[arg(long, default_value = "mistralai/Mistral-7B-Instruct-v0.2")]
-    pub tokenizer_repo_id: String,
+    /// A model preset of the config file, by name or alias, bundling the model, its tokenizer, prompt template, stop
+    /// tokens and sampling, default to the `default` preset. The flags given on the command line win over the preset.
+    #[arg(long)]
+    pub model: Option<String>,
+    /// The config file with the model presets, default to `~/.oxpilot/config.toml`
+    #[arg(long, env = "OX_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// HG tokenizer repo id, e.g. "mistralai/Mistral-7B-Instruct-v0.2", default to the tokenizer embedded in the GGUF
+    /// model file, or to the `tokenizer.json` next to a safetensors model
+    #[arg(long)]
+    pub tokenizer_repo_id: Option<String>,
     /// HG tokenizer repo revision, default to "main"
     #[arg(long, default_value = "main")]
     pub tokenizer_repo_revision: String,
@@ -47,9 +61,35 @@ pub struct CLI {
     /// HG model repo revision, default to "main"
     #[arg(long, default_value = "main")]
     pub model_repo_revision: String,
-    /// HG model repo GGMl/GGUF file, default to "openhermes-2.5-mistral-7b.Q4_K_M.gguf"
+    /// HG model repo GGUF file, or unquantized "model.safetensors" (or "model.safetensors.index.json" when sharded) with
+    /// its "config.json", default to "mistral-7b-instruct-v0.2.Q4_K_M.gguf"
     #[arg(long, default_value = "mistral-7b-instruct-v0.2.Q4_K_M.gguf")]
     pub model_file_name: String,
+    /// A local tokenizer file, used instead of `--tokenizer-repo-id` without accessing the hub
+    #[arg(long)]
+    pub tokenizer_path: Option<PathBuf>,
+    /// A local GGUF (or safetensors) model file, used instead of `--model-repo-id` and `--model-file-name` without accessing the hub
+    #[arg(long)]
+    pub model_path: Option<PathBuf>,
+    /// Resolve the repos from the cache (`~/.oxpilot`) only, without accessing the hub, also set by `HF_HUB_OFFLINE=1`
+    #[arg(long, env = "HF_HUB_OFFLINE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub offline: bool,
+    /// HG repo id of a small draft model for speculative decoding, it must share the tokenizer of the main model.
+    /// Speculative decoding is disabled unless both `--draft-model-repo-id` and `--draft-model-file-name` are set.
+    #[arg(long, requires = "draft_model_file_name")]
+    pub draft_model_repo_id: Option<String>,
+    /// HG draft model repo GGUF file, e.g. "tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf"
+    #[arg(long, requires = "draft_model_repo_id")]
+    pub draft_model_file_name: Option<String>,
+    /// The number of tokens proposed by the draft model before verifying them with the main model, default to 4
+    #[arg(long, default_value_t = 4)]
+    pub draft_tokens: usize,
+    /// The type of the weights of safetensors models, bf16 takes half the memory of f32, GGUF models keep their own
+    #[arg(long, value_enum, default_value_t = Precision::F32)]
+    pub dtype: Precision,
+    /// The maximum number of requests generated together in batched forward passes, default to 4
+    #[arg(long, default_value_t = 4)]
+    pub max_batch_size: usize,
 }
 
 #[derive(Debug, Subcommand)]
@@ -69,14 +109,114 @@ pub enum CLICommands {
         /// Same as git commit --signoff (-s)
         #[arg(long = "signoff", short = 's')]
         signoff: bool,
+        /// How tokens are generated, `prompt-lookup` speeds up commit messages that copy identifiers from the diff.
+        #[arg(long = "decoding", value_enum, default_value_t = Decoding::Standard)]
+        decoding: Decoding,
     },
     /// Start the copilot server at `--port`, default to 9090.
     Serve {
         /// The port to bind the copilot server on, default to 9090, only used if `ox serve`.
         #[arg(short = 'p', long = "port", default_value = "9090")]
         port: u16,
+        /// The fill-in-the-middle template used by the `/infill` endpoint, should match the model.
+        #[arg(long = "fim-template", value_enum, default_value_t = FimTemplate::Prefix)]
+        fim_template: FimTemplate,
+        /// How tokens are generated for all requests, `prompt-lookup` speeds up completions that copy code from the prompt.
+        #[arg(long = "decoding", value_enum, default_value_t = Decoding::Standard)]
+        decoding: Decoding,
+        /// A TOML registry of the models to serve at once instead of `--model-repo-id`, see `registry::Registry`.
+        #[arg(long = "models")]
+        models: Option<PathBuf>,
+        /// Enables the `/admin` endpoints that load and unload models at runtime, requests must send
+        /// `Authorization: Bearer <token>`.
+        #[arg(long = "admin-token")]
+        admin_token: Option<String>,
+        /// How long the generations in flight may run after Ctrl-C or SIGTERM before they are cancelled, in seconds.
+        #[arg(long = "shutdown-timeout", default_value_t = 30)]
+        shutdown_timeout: u64,
+    },
+    /// Speak the Language Server Protocol over stdio, providing inline completions to editors, e.g. Helix or Neovim.
+    Lsp {
+        /// The fill-in-the-middle template used to build the prompt from the text before and after the cursor,
+        /// should match the model, default to `prefix` which only sends the text before the cursor.
+        #[arg(long = "fim-template", value_enum, default_value_t = FimTemplate::Prefix)]
+        fim_template: FimTemplate,
+        /// The maximum number of tokens of a completion, default to 64.
+        #[arg(long = "max-tokens", default_value_t = 64)]
+        max_tokens: usize,
+        /// How tokens are generated, `prompt-lookup` speeds up completions that copy code from the open document.
+        #[arg(long = "decoding", value_enum, default_value_t = Decoding::Standard)]
+        decoding: Decoding,
+    },
+    /// Quantize a safetensors checkpoint (Llama or Mistral) or an f16/f32 GGUF file to a GGUF file, without loading a
+    /// model, e.g. `ox quantize ./my-fine-tune ./my-fine-tune.Q4_K.gguf`.
+    Quantize {
+        /// A GGUF file, a `model.safetensors` or `model.safetensors.index.json` with its `config.json` and
+        /// `tokenizer.json`, or the directory of the checkpoint.
+        input: PathBuf,
+        /// The GGUF file to write.
+        output: PathBuf,
+        /// The quantization type of the matrices, default to `q4_k`.
+        #[arg(long = "type", value_enum, default_value_t = QuantType::Q4K)]
+        quant_type: QuantType,
+        /// Keep the tensors whose GGUF name contains this in f32, can be repeated, e.g. `--skip token_embd` keeps the
+        /// embeddings unquantized.
+        #[arg(long = "skip")]
+        skip: Vec<String>,
+    },
+    /// Manage the models cached in `~/.oxpilot`, without loading a model.
+    Models {
+        #[command(subcommand)]
+        command: ModelsCommand,
+    },
+    /// Print the architecture, context length, RoPE settings, special tokens, chat template and tensor types of a GGUF
+    /// file without loading the weights, e.g. `ox inspect ./model.gguf` or `ox inspect TheBloke/Mistral-7B-Instruct-v0.2-GGUF
+    /// mistral-7b-instruct-v0.2.Q4_K_M.gguf`, default to the model set by `--model-path` or `--model-repo-id`.
+    Inspect {
+        /// A local GGUF file, or a HG model repo id followed by the file name.
+        file_or_repo: Option<String>,
+        /// The GGUF file of the repo.
+        file_name: Option<String>,
+        /// Print the summary as JSON, with every tensor.
+        #[arg(long = "json")]
+        json: bool,
+        /// List every tensor with its type and shape.
+        #[arg(long = "tensors")]
+        tensors: bool,
     },
     /// Arbitrary inputs will be parsed as prompt. e.g. `ox How are you today?` will generate the response by prompting "How are you today?".
     #[command(external_subcommand)]
     Any(Vec<OsString>),
 }
+
+#[derive(Debug, Subcommand)]
+pub enum ModelsCommand {
+    /// List the cached files with their repo, revision, size and when they were last used.
+    List,
+    /// Download a model to the cache without running it, e.g. `ox models pull TheBloke/Mistral-7B-Instruct-v0.2-GGUF
+    /// mistral-7b-instruct-v0.2.Q4_K_M.gguf`, the config, shards and tokenizer of a safetensors model are pulled too.
+    Pull {
+        repo_id: String,
+        file_name: String,
+        /// The revision of the repo, default to "main".
+        #[arg(long, default_value = "main")]
+        revision: String,
+    },
+    /// Remove a file of a repo from the cache, or the whole repo without a file.
+    Rm {
+        repo_id: String,
+        file_name: Option<String>,
+    },
+    /// Check the sha256 of the cached files, of a repo only when set, against the hub's for the files stored with
+    /// Git LFS (GGUF, safetensors...) and against the one recorded when they were downloaded for the others.
+    Verify { repo_id: Option<String> },
+    /// Remove every model but the `--keep` most recently used ones.
+    Gc {
+        /// The number of models to keep, default to 3.
+        #[arg(long, default_value_t = 3)]
+        keep: usize,
+        /// Only print the files that would be removed.
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+}
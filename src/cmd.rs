@@ -1,10 +1,77 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::speculative::Decoding;
+
 type Responder<T> = tokio::sync::mpsc::Sender<T>;
 
 pub enum Command {
     Prompt {
         prompt: String,
         responder: Responder<String>,
-        temperature: f64,
+        /// Overrides `--temperature`, or the temperature of the preset, for this prompt.
+        temperature: Option<f64>,
+        /// Overrides `--seed` for this prompt, e.g. the `seed` option of an Ollama request.
+        seed: Option<u64>,
+        /// Overrides `--top-p` for this prompt.
+        top_p: Option<f64>,
         max_sampled: usize,
+        decoding: Decoding,
+        priority: Priority,
+        /// Prompts of the same session supersede each other, a newer prompt cancels the queued or running older
+        /// ones, e.g. the completions a Copilot client requests on every keystroke. `None` is never superseded.
+        session: Option<String>,
+        /// Receives the number of tokens of the prompt once it is encoded, e.g. for Ollama's `prompt_eval_count`, or
+        /// why the prompt was rejected, the responder is dropped without sending anything in that case.
+        prompt_tokens: Option<tokio::sync::oneshot::Sender<Result<usize, PromptError>>>,
     },
 }
+
+/// Priority classes of prompts, the scheduler admits higher classes first and preempts lower classes when all KV
+/// cache slots are taken. Chosen by the route, e.g. inline completions are `Interactive`, or by the `priority`
+/// field of the request body.
+///
+/// The variants are ordered from the lowest to the highest priority, so `Priority::Interactive > Priority::Chat`.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Long-running jobs nobody is waiting on token by token.
+    Batch,
+    /// Someone is reading the answer as it streams, e.g. chat.
+    #[default]
+    Chat,
+    /// Editor completions, useless unless they arrive before the next keystroke.
+    Interactive,
+}
+
+impl Priority {
+    /// All classes, from the highest to the lowest priority.
+    pub const ALL: [Priority; 3] = [Priority::Interactive, Priority::Chat, Priority::Batch];
+}
+
+/// Why a prompt was rejected before generating anything.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PromptError {
+    /// The tokenizer failed to encode the prompt.
+    Encoding(String),
+    /// The prompt has `len` tokens, the model accepts at most `max`.
+    TooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for PromptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PromptError::Encoding(error) => write!(f, "failed to encode the prompt: {}", error),
+            PromptError::TooLong { len, max } => write!(
+                f,
+                "the prompt has {} tokens, the model accepts at most {} tokens",
+                len, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PromptError {}
@@ -0,0 +1,319 @@
+//! Named model presets, so that switching models is `--model code` instead of three flags. The presets live in
+//! `~/.oxpilot/config.toml` (or `--config`), each one bundles the model, its tokenizer, its prompt template, its stop
+//! tokens and the default sampling of every subcommand.
+//!
+//! ```toml
+//! # the preset used without `--model`, default to the flags of `ox --help`
+//! default = "mistral"
+//!
+//! [presets.mistral]
+//! model_repo_id = "TheBloke/Mistral-7B-Instruct-v0.2-GGUF"
+//! model_file_name = "mistral-7b-instruct-v0.2.Q4_K_M.gguf"
+//! tokenizer_repo_id = "mistralai/Mistral-7B-Instruct-v0.2"
+//! template = "mistral"
+//!
+//! [presets.hermes]
+//! aliases = ["chat"]
+//! model_repo_id = "TheBloke/OpenHermes-2.5-Mistral-7B-GGUF"
+//! model_file_name = "openhermes-2.5-mistral-7b.Q4_K_M.gguf"
+//! template = "chat-ml"
+//! # the file only sets `</s>`, the turns end with `<|im_end|>`
+//! eos_tokens = ["<|im_end|>"]
+//! temperature = 0.7
+//! top_p = 0.9
+//!
+//! [presets.local]
+//! # local files are loaded without accessing the hub
+//! model_path = "/models/model.Q4_K_M.gguf"
+//! ```
+//!
+//! The flags given on the command line win over the preset, e.g. `ox --model hermes --temperature 0 hi`.
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::cache;
+use crate::cli::CLI;
+use crate::utils::template::PromptTemplate;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    /// The preset used without `--model`.
+    pub default: Option<String>,
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+/// The fields left out keep the defaults of the flags.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Preset {
+    /// Other names of the preset for `--model`, e.g. "chat".
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub model_repo_id: Option<String>,
+    pub model_file_name: Option<String>,
+    pub model_repo_revision: Option<String>,
+    /// A local model file, used instead of `model_repo_id` and `model_file_name`.
+    pub model_path: Option<PathBuf>,
+    /// Without a tokenizer, the tokenizer embedded in the GGUF file is used.
+    pub tokenizer_repo_id: Option<String>,
+    pub tokenizer_file: Option<String>,
+    /// A local tokenizer file, used instead of `tokenizer_repo_id`.
+    pub tokenizer_path: Option<PathBuf>,
+    /// How the instructions are turned into a prompt, default to `mistral`.
+    pub template: Option<PromptTemplate>,
+    /// More tokens ending the generations, see `LLMBuilder::eos_tokens`.
+    #[serde(default)]
+    pub eos_tokens: Vec<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    /// The maximum number of tokens of a generation.
+    pub to_sample: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl Config {
+    /// `~/.oxpilot/config.toml`.
+    pub fn default_path() -> Result<PathBuf> {
+        Ok(cache::default_dir()?.join("config.toml"))
+    }
+
+    /// Reads and validates the config, a missing file is an empty config unless `required`, i.e. set with `--config`.
+    pub fn load(path: impl AsRef<Path>, required: bool) -> Result<Self> {
+        let path = path.as_ref();
+        if !required && !path.exists() {
+            return Ok(Config::default());
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        let config: Config = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut names: HashSet<&str> = self.presets.keys().map(|name| name.as_str()).collect();
+        for (name, preset) in &self.presets {
+            for alias in &preset.aliases {
+                if !names.insert(alias.as_str()) {
+                    return Err(anyhow!(
+                        "the alias {:?} of preset {:?} is already the name of a preset",
+                        alias,
+                        name
+                    ));
+                }
+            }
+            // the model is either a local file or a file of a repo
+            if preset.model_path.is_some()
+                && (preset.model_repo_id.is_some() || preset.model_file_name.is_some())
+            {
+                return Err(anyhow!(
+                    "preset {:?} sets both model_path and model_repo_id or model_file_name",
+                    name
+                ));
+            }
+        }
+        if let Some(default) = &self.default {
+            if self.find(default).is_none() {
+                return Err(anyhow!("the default preset {:?} is not defined", default));
+            }
+        }
+        Ok(())
+    }
+
+    /// The preset named `name`, or one of its aliases.
+    pub fn find(&self, name: &str) -> Option<(&String, &Preset)> {
+        self.presets.iter().find(|(preset_name, preset)| {
+            *preset_name == name || preset.aliases.iter().any(|alias| alias == name)
+        })
+    }
+
+    /// The preset of `--model`, otherwise the default one, an unknown name is an error listing the presets.
+    pub fn select(&self, name: Option<&str>) -> Result<Option<(&String, &Preset)>> {
+        match name.or(self.default.as_deref()) {
+            Some(name) => match self.find(name) {
+                Some(preset) => Ok(Some(preset)),
+                None => Err(anyhow!(
+                    "no preset named {:?}, the presets are: {}",
+                    name,
+                    self.presets
+                        .keys()
+                        .map(|name| name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+impl Preset {
+    /// Replaces the defaults of the flags of `cli` with the preset, the flags given on the command line are kept.
+    ///
+    /// The model and the tokenizer are replaced as a whole, e.g. `--model-path` on the command line ignores the
+    /// `model_repo_id` of the preset instead of loading a mix of both.
+    pub fn apply(&self, cli: &mut CLI, matches: &ArgMatches) {
+        // `value_source` tells a value typed by the user from the `default_value` of clap
+        let is_set = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if !["model_repo_id", "model_file_name", "model_path"]
+            .iter()
+            .any(|id| is_set(id))
+        {
+            if let Some(model_repo_id) = &self.model_repo_id {
+                cli.model_repo_id = model_repo_id.clone();
+            }
+            if let Some(model_file_name) = &self.model_file_name {
+                cli.model_file_name = model_file_name.clone();
+            }
+            if self.model_path.is_some() {
+                cli.model_path = self.model_path.clone();
+            }
+        }
+        if !is_set("model_repo_revision") {
+            if let Some(model_repo_revision) = &self.model_repo_revision {
+                cli.model_repo_revision = model_repo_revision.clone();
+            }
+        }
+        if !["tokenizer_repo_id", "tokenizer_path"]
+            .iter()
+            .any(|id| is_set(id))
+        {
+            if self.tokenizer_repo_id.is_some() {
+                cli.tokenizer_repo_id = self.tokenizer_repo_id.clone();
+            }
+            if self.tokenizer_path.is_some() {
+                cli.tokenizer_path = self.tokenizer_path.clone();
+            }
+        }
+        if !is_set("tokenizer_file") {
+            if let Some(tokenizer_file) = &self.tokenizer_file {
+                cli.tokenizer_file = tokenizer_file.clone();
+            }
+        }
+
+        if let (false, Some(temperature)) = (is_set("temperature"), self.temperature) {
+            cli.temperature = temperature;
+        }
+        if let (false, Some(top_p)) = (is_set("top_p"), self.top_p) {
+            cli.top_p = Some(top_p);
+        }
+        if let (false, Some(repeat_penalty)) = (is_set("repeat_penalty"), self.repeat_penalty) {
+            cli.repeat_penalty = repeat_penalty;
+        }
+        if let (false, Some(repeat_last_n)) = (is_set("repeat_last_n"), self.repeat_last_n) {
+            cli.repeat_last_n = repeat_last_n;
+        }
+        if let (false, Some(to_sample)) = (is_set("to_sample"), self.to_sample) {
+            cli.to_sample = to_sample;
+        }
+        if let (false, Some(seed)) = (is_set("seed"), self.seed) {
+            cli.seed = seed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    const CONFIG: &str = r#"
+        default = "mistral"
+
+        [presets.mistral]
+        model_repo_id = "TheBloke/Mistral-7B-Instruct-v0.2-GGUF"
+        model_file_name = "mistral-7b-instruct-v0.2.Q4_K_M.gguf"
+
+        [presets.hermes]
+        aliases = ["chat"]
+        model_repo_id = "TheBloke/OpenHermes-2.5-Mistral-7B-GGUF"
+        model_file_name = "openhermes-2.5-mistral-7b.Q4_K_M.gguf"
+        tokenizer_repo_id = "teknium/OpenHermes-2.5-Mistral-7B"
+        template = "chat-ml"
+        eos_tokens = ["<|im_end|>"]
+        temperature = 0.7
+        top_p = 0.9
+    "#;
+
+    fn parse(args: &[&str]) -> (CLI, ArgMatches) {
+        let matches = CLI::command().get_matches_from(args);
+        let cli = CLI::from_arg_matches(&matches).unwrap();
+        (cli, matches)
+    }
+
+    #[test]
+    fn selects_presets_by_name_alias_or_default() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        config.validate().unwrap();
+        let (name, preset) = config.select(Some("chat")).unwrap().unwrap();
+        assert_eq!(name, "hermes");
+        assert_eq!(preset.template, Some(PromptTemplate::ChatMl));
+        assert_eq!(preset.eos_tokens, vec!["<|im_end|>"]);
+        let (name, _) = config.select(None).unwrap().unwrap();
+        assert_eq!(name, "mistral");
+        let error = config.select(Some("gpt")).unwrap_err().to_string();
+        assert!(error.contains("hermes, mistral"));
+        assert!(Config::default().select(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        let config: Config = toml::from_str(&CONFIG.replace("\"chat\"", "\"mistral\"")).unwrap();
+        assert!(config.validate().is_err());
+        let config: Config =
+            toml::from_str(&CONFIG.replace("default = \"mistral\"", "default = \"gpt\"")).unwrap();
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str(
+            r#"
+            [presets.local]
+            model_path = "/models/model.gguf"
+            model_file_name = "model.gguf"
+        "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn command_line_flags_win_over_the_preset() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let (_, preset) = config.find("hermes").unwrap();
+
+        let (mut cli, matches) = parse(&["ox", "hi"]);
+        preset.apply(&mut cli, &matches);
+        assert_eq!(cli.model_repo_id, "TheBloke/OpenHermes-2.5-Mistral-7B-GGUF");
+        assert_eq!(cli.model_file_name, "openhermes-2.5-mistral-7b.Q4_K_M.gguf");
+        assert_eq!(
+            cli.tokenizer_repo_id.as_deref(),
+            Some("teknium/OpenHermes-2.5-Mistral-7B")
+        );
+        assert_eq!(cli.temperature, 0.7);
+        assert_eq!(cli.top_p, Some(0.9));
+        // not in the preset
+        assert_eq!(cli.repeat_penalty, 1.1);
+
+        let (mut cli, matches) = parse(&[
+            "ox",
+            "--temperature",
+            "0.2",
+            "--model-path",
+            "/models/model.gguf",
+            "hi",
+        ]);
+        preset.apply(&mut cli, &matches);
+        assert_eq!(cli.temperature, 0.2);
+        assert_eq!(cli.model_path, Some(PathBuf::from("/models/model.gguf")));
+        // the model of the preset is ignored as a whole
+        assert_eq!(cli.model_file_name, "mistral-7b-instruct-v0.2.Q4_K_M.gguf");
+        assert_eq!(cli.top_p, Some(0.9));
+    }
+}
@@ -0,0 +1,561 @@
+//! `ox inspect`: what a GGUF file says about the model, to debug a model that misbehaves, e.g. a wrong context length,
+//! RoPE base or EOS token, without building the model.
+//!
+//! `gguf_file::Content::read` only reads the header of the file: the metadata and where each tensor is, the weights are
+//! never loaded, so inspecting a 7B model takes milliseconds. The keys of the hyperparameters are prefixed with the
+//! architecture, e.g. `llama.context_length` or `qwen2.rope.freq_base`, see
+//! https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#llm
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file::{self, Value};
+use serde::Serialize;
+
+use crate::cache::format_size;
+use crate::models::quantized_llama::MAX_SEQ_LEN;
+use crate::models::special_tokens::SpecialTokens;
+use crate::models::{self, gguf_tokenizer};
+
+/// Everything `ox inspect` prints, `Serialize` for `--json`.
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub file: String,
+    /// `general.name`, e.g. "mistralai_mistral-7b-instruct-v0.2".
+    pub name: Option<String>,
+    pub architecture: String,
+    /// The number of positions the model was trained on.
+    pub context_length: Option<u64>,
+    pub embedding_length: Option<u64>,
+    pub block_count: Option<u64>,
+    pub head_count: Option<u64>,
+    /// Fewer KV heads than heads means grouped-query attention, and a smaller KV cache.
+    pub head_count_kv: Option<u64>,
+    pub rope: Rope,
+    pub tokenizer: TokenizerInfo,
+    /// The Jinja template of the chat format, `tokenizer.chat_template`.
+    pub chat_template: Option<String>,
+    /// The tensors of each type, e.g. `Q4K`, `Q6K` and `F32` for a Q4_K_M file.
+    pub quant_types: BTreeMap<String, QuantTypeInfo>,
+    pub tensors: Vec<TensorInfo>,
+    pub parameters: u64,
+    /// The size of the weights, in bytes.
+    pub weights_size: u64,
+    /// The positions of the KV cache of one sequence, the context length, capped to `MAX_SEQ_LEN` for Llama models.
+    pub kv_cache_length: Option<u64>,
+    /// The size of the f32 KV cache of one sequence of `kv_cache_length` positions, in bytes.
+    pub kv_cache_size: u64,
+    /// The weights and the KV cache of one sequence, in bytes.
+    pub estimated_ram: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Rope {
+    /// The number of dimensions of each head that are rotated.
+    pub dimension_count: Option<u64>,
+    /// θ, 10000 for Llama 2, 1000000 for Mistral v0.2 and CodeLlama.
+    pub freq_base: Option<f64>,
+    /// e.g. "linear" or "yarn", to stretch the context of the model.
+    pub scaling_type: Option<String>,
+    pub scaling_factor: Option<f64>,
+    pub original_context_length: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TokenizerInfo {
+    /// `tokenizer.ggml.model`, "llama" for SentencePiece vocabularies, "gpt2" for byte-level BPE ones.
+    pub model: Option<String>,
+    pub vocab_size: usize,
+    pub bos: Option<Token>,
+    pub eos: Option<Token>,
+    pub eot: Option<Token>,
+    pub padding: Option<Token>,
+    pub unknown: Option<Token>,
+    /// The tokens ending a generation, see `SpecialTokens`, `None` when the embedded tokenizer can't be built.
+    pub stop_tokens: Option<Vec<Token>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Token {
+    pub id: u32,
+    /// The text of the token in the vocabulary.
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct QuantTypeInfo {
+    pub tensors: usize,
+    pub parameters: u64,
+    /// In bytes.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TensorInfo {
+    pub name: String,
+    /// The GGML type, e.g. `Q4K`.
+    #[serde(rename = "type")]
+    pub dtype: String,
+    pub shape: Vec<usize>,
+}
+
+/// Reads the header of the GGUF file at `path`.
+pub fn inspect(path: &Path) -> Result<Inspection> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let content = gguf_file::Content::read(&mut file)
+        .with_context(|| format!("{} is not a GGUF file", path.display()))?;
+    from_content(&path.display().to_string(), &content)
+}
+
+fn from_content(file: &str, content: &gguf_file::Content) -> Result<Inspection> {
+    let metadata = &content.metadata;
+    let architecture = models::architecture(content)?;
+    // e.g. `llama.context_length`
+    let get = |key: &str| metadata.get(&format!("{}.{}", architecture, key));
+    let get_u64 = |key: &str| get(key).and_then(number).map(|value| value as u64);
+
+    let head_count = get_u64("attention.head_count");
+    // without the key, every head has its keys and values
+    let head_count_kv = get_u64("attention.head_count_kv").or(head_count);
+    let embedding_length = get_u64("embedding_length");
+    let block_count = get_u64("block_count");
+    let context_length = get_u64("context_length");
+
+    let mut tensors = vec![];
+    let mut quant_types: BTreeMap<String, QuantTypeInfo> = BTreeMap::new();
+    for (name, info) in &content.tensor_infos {
+        let dtype = format!("{:?}", info.ggml_dtype);
+        let parameters = info.shape.elem_count() as u64;
+        // the values are stored in blocks of `block_size` values of `type_size` bytes, e.g. 256 values in 144 bytes for Q4K
+        let size =
+            parameters / info.ggml_dtype.block_size() as u64 * info.ggml_dtype.type_size() as u64;
+        let quant_type = quant_types.entry(dtype.clone()).or_default();
+        quant_type.tensors += 1;
+        quant_type.parameters += parameters;
+        quant_type.size += size;
+        tensors.push(TensorInfo {
+            name: name.clone(),
+            dtype,
+            shape: info.shape.dims().to_vec(),
+        });
+    }
+    // in the order of the layers, `blk.2` before `blk.10`
+    tensors.sort_by_key(|tensor| tensor_sort_key(&tensor.name));
+    let parameters = quant_types.values().map(|info| info.parameters).sum();
+    let weights_size = quant_types.values().map(|info| info.size).sum();
+
+    // our Llama implementation never caches more than `MAX_SEQ_LEN` positions
+    let kv_cache_length = match architecture.as_str() {
+        "llama" => context_length.map(|length| length.min(MAX_SEQ_LEN as u64)),
+        _ => context_length,
+    };
+    // the keys and the values of each block, `key_length` is set when the heads are not `embedding_length / head_count`
+    // wide, e.g. Gemma 3
+    let head_dim = get_u64("attention.key_length").or(match (embedding_length, head_count) {
+        (Some(embedding_length), Some(head_count)) if head_count > 0 => {
+            Some(embedding_length / head_count)
+        }
+        _ => None,
+    });
+    let kv_cache_size = match (kv_cache_length, block_count, head_count_kv, head_dim) {
+        (Some(length), Some(block_count), Some(head_count_kv), Some(head_dim)) => {
+            2 * block_count * length * head_count_kv * head_dim * std::mem::size_of::<f32>() as u64
+        }
+        _ => 0,
+    };
+
+    Ok(Inspection {
+        file: file.to_string(),
+        name: metadata
+            .get("general.name")
+            .and_then(|name| name.to_string().ok().cloned()),
+        context_length,
+        embedding_length,
+        block_count,
+        head_count,
+        head_count_kv,
+        rope: Rope {
+            dimension_count: get_u64("rope.dimension_count"),
+            freq_base: get("rope.freq_base").and_then(number),
+            scaling_type: get("rope.scaling.type")
+                .and_then(|scaling_type| scaling_type.to_string().ok().cloned()),
+            scaling_factor: get("rope.scaling.factor").and_then(number),
+            original_context_length: get_u64("rope.scaling.original_context_length"),
+        },
+        tokenizer: tokenizer_info(metadata),
+        chat_template: metadata
+            .get("tokenizer.chat_template")
+            .and_then(|template| template.to_string().ok().cloned()),
+        architecture,
+        quant_types,
+        tensors,
+        parameters,
+        weights_size,
+        kv_cache_length,
+        kv_cache_size,
+        estimated_ram: weights_size + kv_cache_size,
+    })
+}
+
+/// The special tokens of the `tokenizer.ggml.*` keys, with the stop tokens `LLM` would use.
+fn tokenizer_info(metadata: &HashMap<String, Value>) -> TokenizerInfo {
+    let vocabulary = metadata
+        .get("tokenizer.ggml.tokens")
+        .and_then(|tokens| tokens.to_vec().ok());
+    let token = |id: u32| Token {
+        id,
+        text: vocabulary
+            .and_then(|vocabulary| vocabulary.get(id as usize))
+            .and_then(|text| text.to_string().ok().cloned()),
+    };
+    let token_of = |key: &str| {
+        metadata
+            .get(key)
+            .and_then(number)
+            .map(|id| token(id as u32))
+    };
+    let stop_tokens = gguf_tokenizer::from_metadata(metadata)
+        .and_then(|tokenizer| SpecialTokens::read(metadata, None, &tokenizer))
+        .ok()
+        .map(|special_tokens| {
+            special_tokens
+                .stop_token_ids
+                .into_iter()
+                .map(&token)
+                .collect()
+        });
+    TokenizerInfo {
+        model: metadata
+            .get("tokenizer.ggml.model")
+            .and_then(|model| model.to_string().ok().cloned()),
+        vocab_size: vocabulary.map(|vocabulary| vocabulary.len()).unwrap_or(0),
+        bos: token_of("tokenizer.ggml.bos_token_id"),
+        eos: token_of("tokenizer.ggml.eos_token_id"),
+        eot: token_of("tokenizer.ggml.eot_token_id"),
+        padding: token_of("tokenizer.ggml.padding_token_id"),
+        unknown: token_of("tokenizer.ggml.unknown_token_id"),
+        stop_tokens,
+    }
+}
+
+/// The value of a numeric key, whatever the integer or float type the converter picked.
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::U8(value) => Some(*value as f64),
+        Value::I8(value) => Some(*value as f64),
+        Value::U16(value) => Some(*value as f64),
+        Value::I16(value) => Some(*value as f64),
+        Value::U32(value) => Some(*value as f64),
+        Value::I32(value) => Some(*value as f64),
+        Value::U64(value) => Some(*value as f64),
+        Value::I64(value) => Some(*value as f64),
+        Value::F32(value) => Some(*value as f64),
+        Value::F64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// `blk.<n>.*` sorted by `n` after the other tensors, e.g. `token_embd.weight`.
+fn tensor_sort_key(name: &str) -> (usize, String) {
+    let block = name
+        .strip_prefix("blk.")
+        .and_then(|rest| rest.split('.').next())
+        .and_then(|block| block.parse::<usize>().ok());
+    match block {
+        Some(block) => (block + 1, name.to_string()),
+        None => (0, name.to_string()),
+    }
+}
+
+/// "7.24 B" parameters.
+fn format_count(count: u64) -> String {
+    let units = ["", " K", " M", " B", " T"];
+    let mut value = count as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        count.to_string()
+    } else {
+        format!("{:.2}{}", value, units[unit])
+    }
+}
+
+impl Inspection {
+    /// The summary for humans, every tensor is listed with `with_tensors`.
+    pub fn to_text(&self, with_tensors: bool) -> String {
+        // `write!` to a `String` can't fail
+        let mut text = String::new();
+        let unknown = || "unknown".to_string();
+        let or_unknown =
+            |value: Option<u64>| value.map(|value| value.to_string()).unwrap_or_else(unknown);
+        let token = |token: Option<&Token>| match token {
+            Some(Token {
+                id,
+                text: Some(text),
+            }) => format!("{} {:?}", id, text),
+            Some(Token { id, text: None }) => id.to_string(),
+            None => "none".to_string(),
+        };
+
+        let _ = writeln!(text, "file            {}", self.file);
+        if let Some(name) = &self.name {
+            let _ = writeln!(text, "name            {}", name);
+        }
+        let _ = writeln!(text, "architecture    {}", self.architecture);
+        let _ = writeln!(text, "context length  {}", or_unknown(self.context_length));
+        let _ = writeln!(
+            text,
+            "layers          {} blocks, {} embedding, {} heads, {} KV heads",
+            or_unknown(self.block_count),
+            or_unknown(self.embedding_length),
+            or_unknown(self.head_count),
+            or_unknown(self.head_count_kv)
+        );
+        let mut rope = format!(
+            "{} dimensions, freq base {}",
+            or_unknown(self.rope.dimension_count),
+            self.rope
+                .freq_base
+                .map(|freq_base| freq_base.to_string())
+                .unwrap_or_else(unknown)
+        );
+        if let Some(scaling_type) = &self.rope.scaling_type {
+            let _ = write!(rope, ", {} scaling", scaling_type);
+            if let Some(scaling_factor) = self.rope.scaling_factor {
+                let _ = write!(rope, " x{}", scaling_factor);
+            }
+            if let Some(original_context_length) = self.rope.original_context_length {
+                let _ = write!(rope, " of {} positions", original_context_length);
+            }
+        }
+        let _ = writeln!(text, "rope            {}", rope);
+        let _ = writeln!(
+            text,
+            "tokenizer       {}, {} tokens",
+            self.tokenizer.model.clone().unwrap_or_else(unknown),
+            self.tokenizer.vocab_size
+        );
+        let _ = writeln!(
+            text,
+            "bos             {}",
+            token(self.tokenizer.bos.as_ref())
+        );
+        let _ = writeln!(
+            text,
+            "eos             {}",
+            token(self.tokenizer.eos.as_ref())
+        );
+        if self.tokenizer.eot.is_some() {
+            let _ = writeln!(
+                text,
+                "eot             {}",
+                token(self.tokenizer.eot.as_ref())
+            );
+        }
+        if self.tokenizer.padding.is_some() {
+            let _ = writeln!(
+                text,
+                "padding         {}",
+                token(self.tokenizer.padding.as_ref())
+            );
+        }
+        if self.tokenizer.unknown.is_some() {
+            let _ = writeln!(
+                text,
+                "unknown         {}",
+                token(self.tokenizer.unknown.as_ref())
+            );
+        }
+        let stop_tokens = match &self.tokenizer.stop_tokens {
+            Some(stop_tokens) => stop_tokens
+                .iter()
+                .map(|stop_token| token(Some(stop_token)))
+                .collect::<Vec<_>>()
+                .join(", "),
+            None => "unknown, the embedded tokenizer is not supported".to_string(),
+        };
+        let _ = writeln!(text, "stop tokens     {}", stop_tokens);
+        let _ = writeln!(text, "parameters      {}", format_count(self.parameters));
+        let _ = writeln!(text, "tensors");
+        for (dtype, info) in &self.quant_types {
+            let _ = writeln!(
+                text,
+                "  {:6} {:>5} tensors  {:>10} parameters  {:>10}",
+                dtype,
+                info.tensors,
+                format_count(info.parameters),
+                format_size(info.size)
+            );
+        }
+        let kv_cache = match self.kv_cache_length {
+            Some(length) => format!(
+                "{} KV cache of {} positions",
+                format_size(self.kv_cache_size),
+                length
+            ),
+            None => "KV cache unknown".to_string(),
+        };
+        let _ = writeln!(
+            text,
+            "estimated RAM   {} ({} weights + {})",
+            format_size(self.estimated_ram),
+            format_size(self.weights_size),
+            kv_cache
+        );
+        match &self.chat_template {
+            Some(chat_template) => {
+                let _ = writeln!(text, "chat template\n{}", chat_template);
+            }
+            None => {
+                let _ = writeln!(text, "chat template   none");
+            }
+        }
+        if with_tensors {
+            let _ = writeln!(text, "\n{:40} {:6} SHAPE", "NAME", "TYPE");
+            for tensor in &self.tensors {
+                let _ = writeln!(
+                    text,
+                    "{:40} {:6} {:?}",
+                    tensor.name, tensor.dtype, tensor.shape
+                );
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::chat_ml_metadata;
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use candle_core::{Device, Tensor};
+
+    /// A Llama file with a Q4K matrix and an f32 norm, and a ChatML vocabulary.
+    fn write_gguf(path: &Path) -> Result<()> {
+        let mut metadata = chat_ml_metadata();
+        metadata.extend(
+            [
+                ("general.architecture", Value::String("llama".to_string())),
+                ("general.name", Value::String("tiny".to_string())),
+                ("llama.context_length", Value::U32(32768)),
+                ("llama.embedding_length", Value::U32(256)),
+                ("llama.block_count", Value::U32(1)),
+                ("llama.attention.head_count", Value::U32(4)),
+                ("llama.attention.head_count_kv", Value::U32(2)),
+                ("llama.rope.dimension_count", Value::U32(64)),
+                ("llama.rope.freq_base", Value::F32(1000000.0)),
+                (
+                    "tokenizer.chat_template",
+                    Value::String(
+                        "{% for message in messages %}<|im_start|>{% endfor %}".to_string(),
+                    ),
+                ),
+            ]
+            .map(|(key, value)| (key.to_string(), value)),
+        );
+        let metadata: Vec<(&str, &Value)> = metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+            .collect();
+        let weight = QTensor::quantize(
+            &Tensor::randn(0f32, 0.02, (8, 256), &Device::Cpu)?,
+            GgmlDType::Q4K,
+        )?;
+        let norm = QTensor::quantize(
+            &Tensor::ones(256, candle_core::DType::F32, &Device::Cpu)?,
+            GgmlDType::F32,
+        )?;
+        let mut file = std::fs::File::create(path)?;
+        gguf_file::write(
+            &mut file,
+            &metadata,
+            &[
+                ("blk.10.ffn_up.weight", &weight),
+                ("blk.2.attn_norm.weight", &norm),
+            ],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn inspects_a_gguf_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("inspect.gguf");
+        write_gguf(&path)?;
+        let inspection = inspect(&path)?;
+
+        assert_eq!(inspection.architecture, "llama");
+        assert_eq!(inspection.name.as_deref(), Some("tiny"));
+        assert_eq!(inspection.context_length, Some(32768));
+        assert_eq!(inspection.head_count_kv, Some(2));
+        assert_eq!(inspection.rope.freq_base, Some(1000000.0));
+        assert_eq!(inspection.tokenizer.vocab_size, 8);
+        assert_eq!(
+            inspection.tokenizer.eos,
+            Some(Token {
+                id: 2,
+                text: Some("</s>".to_string())
+            })
+        );
+        // `<|im_end|>` ends the turns of ChatML models even if the file only sets `</s>`
+        let stop_tokens = inspection.tokenizer.stop_tokens.as_ref().unwrap();
+        assert_eq!(
+            stop_tokens.iter().map(|token| token.id).collect::<Vec<_>>(),
+            vec![2, 7]
+        );
+        assert!(inspection.chat_template.unwrap().contains("<|im_start|>"));
+
+        // 8 * 256 values in Q4K blocks of 256 values in 144 bytes, 256 f32 values
+        assert_eq!(
+            inspection.quant_types["Q4K"],
+            QuantTypeInfo {
+                tensors: 1,
+                parameters: 2048,
+                size: 8 * 144
+            }
+        );
+        assert_eq!(inspection.quant_types["F32"].size, 256 * 4);
+        assert_eq!(inspection.parameters, 2048 + 256);
+        assert_eq!(
+            inspection
+                .tensors
+                .iter()
+                .map(|tensor| tensor.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["blk.2.attn_norm.weight", "blk.10.ffn_up.weight"]
+        );
+
+        // the KV cache of Llama models is capped to `MAX_SEQ_LEN`: keys and values, 2 heads of 64 values, 1 block
+        assert_eq!(inspection.kv_cache_length, Some(MAX_SEQ_LEN as u64));
+        assert_eq!(
+            inspection.kv_cache_size,
+            2 * MAX_SEQ_LEN as u64 * 2 * 64 * 4
+        );
+        assert_eq!(
+            inspection.estimated_ram,
+            inspection.weights_size + inspection.kv_cache_size
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inspect.json");
+        std::fs::write(&path, "{}").unwrap();
+        let error = inspect(&path).unwrap_err().to_string();
+        assert!(error.contains("is not a GGUF file"));
+    }
+
+    #[test]
+    fn formats_counts() {
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(7_241_732_096), "7.24 B");
+    }
+}
@@ -1,7 +1,18 @@
+pub mod cache;
 pub mod cli;
 pub mod cmd;
+pub mod config;
+pub mod inspect;
 pub mod llm;
+pub mod lsp;
+pub mod models;
 pub mod process;
+pub mod quantize;
+pub mod registry;
+pub mod scheduler;
+pub mod speculative;
+#[cfg(any(test, feature = "bench"))]
+pub mod testing;
 pub mod token;
 pub mod types;
 pub mod utils;
@@ -1,14 +1,22 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
+use tracing::debug;
 
+use crate::cache::{self, Hub};
+use crate::models::safetensors::{self, Precision};
+use crate::models::special_tokens::SpecialTokens;
+use crate::models::{self, gguf_tokenizer, Model};
+use crate::speculative::{DraftModel, SpeculativeStats};
 use crate::utils::spinner::SilentableSpinner;
 
 /// In this file we are using the "Builder" pattern to create `LLM` struc instances. "Builder" pattern is a common design
 /// pattern that used in Rust. It is a creational pattern that lets you construct complex objects step by step.
 /// See https://github.com/chenhunghan/oxpilot/pull/1
 ///
-/// ```
+/// ```no_run
 /// use oxpilot::llm::LLMBuilder;
 /// #[tokio::main]
 /// async fn main() {
@@ -16,21 +24,36 @@ use crate::utils::spinner::SilentableSpinner;
 ///         .tokenizer_repo_id("hf-internal-testing/llama-tokenizer")
 ///         .model_repo_id("TheBloke/CodeLlama-7B-GGU")
 ///         .model_file_name("codellama-7b.Q2_K.gguf");
-///    let llm = llm_builder.build().await;
+///    let llm = llm_builder.build(false).await;
+///    // GGUF files embed their tokenizer, the tokenizer repo can be skipped
+///    let llm_builder = LLMBuilder::new()
+///         .model_repo_id("TheBloke/CodeLlama-7B-GGU")
+///         .model_file_name("codellama-7b.Q2_K.gguf");
 /// }
 /// ```
 /// See [The Ultimate Builder Pattern Tutorial](https://www.youtube.com/watch?v=Z_3WOSiYYFY)
 /// See https://www.lurklurk.org/effective-rust/builders.html
-
 pub struct LLM {
+    /// The model repo and file when the tokenizer was read from the GGUF metadata.
     pub tokenizer_repo_id: String,
     pub tokenizer_repo_revision: String,
     pub tokenizer_file_name: String,
     pub model_repo_id: String,
     pub model_repo_revision: String,
     pub model_file_name: String,
-    pub model_weights: candle_transformers::models::quantized_llama::ModelWeights,
+    /// Where the model file is stored on disk, usually in the hf_hub cache dir.
+    pub model_file_path: PathBuf,
+    /// The `general.architecture` of the GGUF file, or the `model_type` of the `config.json` of a safetensors
+    /// checkpoint, e.g. "llama" or "qwen2".
+    pub architecture: String,
+    pub model_weights: Box<dyn Model>,
     pub tokenizer: tokenizers::Tokenizer,
+    /// The BOS, EOS, EOT and other tokens ending a generation, from the GGUF metadata and `tokenizer_config.json`.
+    pub special_tokens: SpecialTokens,
+    /// A small model sharing the tokenizer, used for speculative decoding when set.
+    pub draft: Option<DraftModel>,
+    /// The draft tokens proposed and accepted since the LLM was built.
+    pub speculative_stats: SpeculativeStats,
 }
 
 /// `Default` is a trait for giving a type a useful default value.
@@ -49,12 +72,26 @@ pub struct LLMBuilder<State> {
     model_repo_revision: Option<String>,
     model_file_name: Option<String>,
     cache_dir: Option<PathBuf>,
+    /// A local tokenizer file used instead of the tokenizer repo.
+    tokenizer_path: Option<PathBuf>,
+    /// A local GGUF file (or safetensors checkpoint) used instead of the model repo.
+    model_path: Option<PathBuf>,
+    /// Resolve the repo files from `cache_dir` only, see `offline()`.
+    offline: bool,
+    /// The type of the weights of safetensors checkpoints, GGUF files keep their own.
+    precision: Precision,
+    /// More tokens ending a generation, e.g. `<|im_end|>` when the metadata only sets `</s>`.
+    eos_tokens: Vec<String>,
+    draft_model_repo_id: Option<String>,
+    draft_model_file_name: Option<String>,
+    num_draft_tokens: Option<usize>,
     state: State,
 }
 
 /// Type state for LLMBuilder
 /// See https://github.com/chenhunghan/oxpilot/pull/5
-/// Init state when `::new()` is called.
+/// Init state when `::new()` is called, ready to accept a tokenizer, or directly a model when the tokenizer should be
+/// read from the GGUF file.
 ///
 /// `pub struct StrucName` is a unit struct. A unit struct is a struct that has no fields.
 /// They are most commonly used as marker types.
@@ -68,7 +105,8 @@ pub struct LLMBuilder<State> {
 #[derive(PartialEq)]
 pub struct InitState;
 
-/// Intermedia state, with token repo id, ready to accept model repo id
+/// Intermedia state, with token repo id (or a local tokenizer path), ready to accept model repo id (or a local model
+/// path)
 #[derive(PartialEq)]
 pub struct WithTokenizerRepoId;
 
@@ -76,7 +114,7 @@ pub struct WithTokenizerRepoId;
 #[derive(PartialEq)]
 pub struct WithModelRepoId;
 
-/// With both token repo id and model repo id
+/// With a model, and optionally a tokenizer, otherwise the tokenizer embedded in the GGUF file is used
 #[derive(PartialEq)]
 pub struct ReadyState;
 
@@ -90,6 +128,14 @@ impl LLMBuilder<InitState> {
             model_repo_revision: None,
             model_file_name: None,
             cache_dir: None,
+            tokenizer_path: None,
+            model_path: None,
+            offline: false,
+            precision: Precision::F32,
+            eos_tokens: vec![],
+            draft_model_repo_id: None,
+            draft_model_file_name: None,
+            num_draft_tokens: None,
             state: InitState,
         }
     }
@@ -134,9 +180,86 @@ impl LLMBuilder<InitState> {
             model_repo_revision: self.model_repo_revision,
             model_file_name: self.model_file_name,
             cache_dir: self.cache_dir,
+            tokenizer_path: self.tokenizer_path,
+            model_path: self.model_path,
+            offline: self.offline,
+            precision: self.precision,
+            eos_tokens: self.eos_tokens,
+            draft_model_repo_id: self.draft_model_repo_id,
+            draft_model_file_name: self.draft_model_file_name,
+            num_draft_tokens: self.num_draft_tokens,
             state: WithTokenizerRepoId,
         }
     }
+
+    /// A local `tokenizer.json` instead of `tokenizer_repo_id`, the hub is not used for the tokenizer.
+    pub fn tokenizer_path(
+        self,
+        tokenizer_path: impl Into<PathBuf>,
+    ) -> LLMBuilder<WithTokenizerRepoId> {
+        LLMBuilder {
+            tokenizer_repo_id: self.tokenizer_repo_id,
+            tokenizer_repo_revision: self.tokenizer_repo_revision,
+            tokenizer_file_name: self.tokenizer_file_name,
+            model_repo_id: self.model_repo_id,
+            model_repo_revision: self.model_repo_revision,
+            model_file_name: self.model_file_name,
+            cache_dir: self.cache_dir,
+            tokenizer_path: Some(tokenizer_path.into()),
+            model_path: self.model_path,
+            offline: self.offline,
+            precision: self.precision,
+            eos_tokens: self.eos_tokens,
+            draft_model_repo_id: self.draft_model_repo_id,
+            draft_model_file_name: self.draft_model_file_name,
+            num_draft_tokens: self.num_draft_tokens,
+            state: WithTokenizerRepoId,
+        }
+    }
+
+    /// Without a tokenizer repo or file, the tokenizer is read from the metadata of the GGUF file.
+    pub fn model_repo_id(self, model_repo_id: impl Into<String>) -> LLMBuilder<WithModelRepoId> {
+        LLMBuilder {
+            tokenizer_repo_id: self.tokenizer_repo_id,
+            tokenizer_repo_revision: self.tokenizer_repo_revision,
+            tokenizer_file_name: self.tokenizer_file_name,
+            model_repo_id: Some(model_repo_id.into()),
+            model_repo_revision: self.model_repo_revision,
+            model_file_name: self.model_file_name,
+            cache_dir: self.cache_dir,
+            tokenizer_path: self.tokenizer_path,
+            model_path: self.model_path,
+            offline: self.offline,
+            precision: self.precision,
+            eos_tokens: self.eos_tokens,
+            draft_model_repo_id: self.draft_model_repo_id,
+            draft_model_file_name: self.draft_model_file_name,
+            num_draft_tokens: self.num_draft_tokens,
+            state: WithModelRepoId,
+        }
+    }
+
+    /// A local GGUF file, with the tokenizer read from its metadata.
+    pub fn model_path(self, model_path: impl Into<PathBuf>) -> LLMBuilder<ReadyState> {
+        LLMBuilder {
+            tokenizer_repo_id: self.tokenizer_repo_id,
+            tokenizer_repo_revision: self.tokenizer_repo_revision,
+            tokenizer_file_name: self.tokenizer_file_name,
+            model_repo_id: self.model_repo_id,
+            model_repo_revision: self.model_repo_revision,
+            model_file_name: self.model_file_name,
+            cache_dir: self.cache_dir,
+            tokenizer_path: self.tokenizer_path,
+            model_path: Some(model_path.into()),
+            offline: self.offline,
+            precision: self.precision,
+            eos_tokens: self.eos_tokens,
+            draft_model_repo_id: self.draft_model_repo_id,
+            draft_model_file_name: self.draft_model_file_name,
+            num_draft_tokens: self.num_draft_tokens,
+            state: ReadyState,
+        }
+    }
 }
 
 impl<State> LLMBuilder<State> {
@@ -153,9 +276,39 @@ impl LLMBuilder<WithTokenizerRepoId> {
             model_repo_revision: self.model_repo_revision,
             model_file_name: self.model_file_name,
             cache_dir: self.cache_dir,
+            tokenizer_path: self.tokenizer_path,
+            model_path: self.model_path,
+            offline: self.offline,
+            precision: self.precision,
+            eos_tokens: self.eos_tokens,
+            draft_model_repo_id: self.draft_model_repo_id,
+            draft_model_file_name: self.draft_model_file_name,
+            num_draft_tokens: self.num_draft_tokens,
             state: WithModelRepoId,
         }
     }
+
+    /// A local GGUF file instead of `model_repo_id` and `model_file_name`, the hub is not used for the model.
+    pub fn model_path(self, model_path: impl Into<PathBuf>) -> LLMBuilder<ReadyState> {
+        LLMBuilder {
+            tokenizer_repo_id: self.tokenizer_repo_id,
+            tokenizer_repo_revision: self.tokenizer_repo_revision,
+            tokenizer_file_name: self.tokenizer_file_name,
+            model_repo_id: self.model_repo_id,
+            model_repo_revision: self.model_repo_revision,
+            model_file_name: self.model_file_name,
+            cache_dir: self.cache_dir,
+            tokenizer_path: self.tokenizer_path,
+            model_path: Some(model_path.into()),
+            offline: self.offline,
+            precision: self.precision,
+            eos_tokens: self.eos_tokens,
+            draft_model_repo_id: self.draft_model_repo_id,
+            draft_model_file_name: self.draft_model_file_name,
+            num_draft_tokens: self.num_draft_tokens,
+            state: ReadyState,
+        }
+    }
 }
 
 impl LLMBuilder<WithModelRepoId> {
@@ -168,6 +321,14 @@ impl LLMBuilder<WithModelRepoId> {
             model_repo_revision: self.model_repo_revision,
             model_file_name: Some(model_file_name.into()),
             cache_dir: self.cache_dir,
+            tokenizer_path: self.tokenizer_path,
+            model_path: self.model_path,
+            offline: self.offline,
+            precision: self.precision,
+            eos_tokens: self.eos_tokens,
+            draft_model_repo_id: self.draft_model_repo_id,
+            draft_model_file_name: self.draft_model_file_name,
+            num_draft_tokens: self.num_draft_tokens,
             state: ReadyState,
         }
     }
@@ -197,89 +358,279 @@ impl LLMBuilder<ReadyState> {
         self
     }
 
+    /// Resolve the files of the repos from `cache_dir` only, without contacting the hub, fails fast when a file has not
+    /// been downloaded yet.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Load the weights of a safetensors checkpoint in f32 (the default) or bf16, ignored for GGUF files.
+    pub fn precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// More tokens ending the generations, on top of the EOS and end of turn tokens of the metadata, e.g.
+    /// `<|im_end|>` for a ChatML fine-tune whose file only sets `</s>`, they must be in the vocabulary.
+    pub fn eos_tokens(mut self, eos_tokens: Vec<String>) -> Self {
+        self.eos_tokens = eos_tokens;
+        self
+    }
+
+    /// The repo of a small draft model for speculative decoding, it must share the tokenizer of the main model,
+    /// e.g. TinyLlama for Llama 2 models.
+    pub fn draft_model_repo_id(mut self, draft_model_repo_id: impl Into<String>) -> Self {
+        self.draft_model_repo_id = Some(draft_model_repo_id.into());
+        self
+    }
+
+    pub fn draft_model_file_name(mut self, draft_model_file_name: impl Into<String>) -> Self {
+        self.draft_model_file_name = Some(draft_model_file_name.into());
+        self
+    }
+
+    /// How many tokens the draft model proposes per step, defaults to 4.
+    pub fn num_draft_tokens(mut self, num_draft_tokens: usize) -> Self {
+        self.num_draft_tokens = Some(num_draft_tokens);
+        self
+    }
+
     pub async fn build(self, is_silent: bool) -> Result<LLM> {
         let mut spinner = SilentableSpinner::new(
             is_silent,
-            Some(format!("building LLM, this may take a while...",)),
+            Some("building LLM, this may take a while...".to_string()),
         );
 
-        let tokenizer_repo_id = self
-            .tokenizer_repo_id
-            .context("tokenizer_repo_id is None, forgot to .tokenizer_repo_id()?")?;
         let tokenizer_repo_revision = self.tokenizer_repo_revision.unwrap_or("main".to_string());
-        let tokenizer_file_name = self
-            .tokenizer_file_name
-            .unwrap_or("tokenizer.json".to_string());
-
-        let cache_dir = self.cache_dir.unwrap_or({
-            let mut home = dirs::home_dir().context("Failed to get user home dir")?;
-            home.push(".oxpilot");
-            home
-        });
-        tokio::fs::create_dir_all(&cache_dir)
-            .await
-            .context("Failed to crate cache dir for hf_hub_api")?;
-        let hf_hub_api = match hf_hub::api::tokio::ApiBuilder::new()
-            .with_progress(true)
-            .with_cache_dir(cache_dir)
-            .build()
-        {
-            Ok(hf_hub) => hf_hub,
-            Err(error) => {
-                return Result::Err(anyhow!(
-                    "hf_hub_api initialization failed because of {:?}",
-                    error
-                ));
-            }
+        let cache_dir = match self.cache_dir {
+            Some(cache_dir) => cache_dir,
+            None => cache::default_dir()?,
+        };
+        let hub = Hub {
+            cache_dir,
+            offline: self.offline,
         };
 
-        let tokenizer_repo = hf_hub_api.repo(hf_hub::Repo::with_revision(
-            tokenizer_repo_id.to_owned(),
-            hf_hub::RepoType::Model,
-            tokenizer_repo_revision.to_owned(),
-        ));
-        spinner.update("fetching tokenizer file...");
-        let tokenizer_file_path = tokenizer_repo
-            .get(&tokenizer_file_name)
-            .await
-            .context("Failed to fetch tokenizer file")?;
-        spinner.update("initializing tokenizer...");
-        let tokenizer = match tokenizers::Tokenizer::from_file(tokenizer_file_path) {
-            Ok(tokenizer) => tokenizer,
-            Err(error) => {
-                return Result::Err(anyhow!("Failed to init Tokenizer from file {:?}", error))
+        let model_repo_revision = self.model_repo_revision.unwrap_or("main".to_string());
+        let is_local_model = self.model_path.is_some();
+        let is_safetensors = match (&self.model_path, &self.model_file_name) {
+            (Some(model_path), _) => safetensors::is_safetensors(&model_path.to_string_lossy()),
+            (None, Some(model_file_name)) => safetensors::is_safetensors(model_file_name),
+            (None, None) => false,
+        };
+        // safetensors checkpoints don't embed a tokenizer, without a tokenizer repo or file the `tokenizer.json` next to
+        // the weights is used
+        let (tokenizer_path, tokenizer_repo_id, tokenizer_repo_revision) =
+            match (self.tokenizer_path, self.tokenizer_repo_id) {
+                (None, None) if is_safetensors => match &self.model_path {
+                    Some(model_path) => (
+                        Some(model_path.with_file_name("tokenizer.json")),
+                        None,
+                        tokenizer_repo_revision,
+                    ),
+                    None => (
+                        None,
+                        self.model_repo_id.clone(),
+                        model_repo_revision.clone(),
+                    ),
+                },
+                (tokenizer_path, tokenizer_repo_id) => {
+                    (tokenizer_path, tokenizer_repo_id, tokenizer_repo_revision)
+                }
+            };
+
+        // a local tokenizer file bypasses the hub, its directory stands for the repo in `LLM`, without a tokenizer repo
+        // or file the tokenizer is read from the model file below, `tokenizer_config.json` is optional
+        let tokenizer_file = match (tokenizer_path, tokenizer_repo_id) {
+            (Some(tokenizer_path), _) => {
+                let (directory, file_name) = split_path(&tokenizer_path);
+                let tokenizer_config_path = tokenizer_path.with_file_name(TOKENIZER_CONFIG);
+                let tokenizer_config_path = tokenizer_config_path
+                    .is_file()
+                    .then_some(tokenizer_config_path);
+                Some((directory, file_name, tokenizer_path, tokenizer_config_path))
+            }
+            (None, Some(tokenizer_repo_id)) => {
+                let tokenizer_file_name = self
+                    .tokenizer_file_name
+                    .unwrap_or("tokenizer.json".to_string());
+                spinner.update("fetching tokenizer file...");
+                let tokenizer_file_path = hub
+                    .get(
+                        &tokenizer_repo_id,
+                        &tokenizer_repo_revision,
+                        &tokenizer_file_name,
+                    )
+                    .await
+                    .context("Failed to fetch tokenizer file")?;
+                let tokenizer_config_path = hub
+                    .get(
+                        &tokenizer_repo_id,
+                        &tokenizer_repo_revision,
+                        TOKENIZER_CONFIG,
+                    )
+                    .await
+                    .ok();
+                Some((
+                    tokenizer_repo_id,
+                    tokenizer_file_name,
+                    tokenizer_file_path,
+                    tokenizer_config_path,
+                ))
             }
+            (None, None) => None,
         };
 
-        let model_repo_id = self
-            .model_repo_id
-            .context("model_repo_id is None, forgot to .model_repo_id()?")?;
-        let model_repo_revision = self.model_repo_revision.unwrap_or("main".to_string());
-        let model_file_name = self
-            .model_file_name
-            .context("model_file_name is None, forgot to .model_file_name()?")?;
-
-        let model_repo = hf_hub_api.repo(hf_hub::Repo::with_revision(
-            model_repo_id.to_owned(),
-            hf_hub::RepoType::Model,
-            model_repo_revision.to_owned(),
-        ));
-        spinner.update("fetching model weights... (this may take a while)");
-        let model_file_path = model_repo
-            .get(&model_file_name)
-            .await
-            .context("Failed to fetch model file")?;
+        // a local model file bypasses the hub, its directory stands for the repo in `LLM`
+        let (model_repo_id, model_file_name, model_file_path) = match self.model_path {
+            Some(model_path) => {
+                let (directory, file_name) = split_path(&model_path);
+                (directory, file_name, model_path)
+            }
+            None => {
+                let model_repo_id = self
+                    .model_repo_id
+                    .context("model_repo_id is None, forgot to .model_repo_id()?")?;
+                let model_file_name = self
+                    .model_file_name
+                    .context("model_file_name is None, forgot to .model_file_name()?")?;
+                spinner.update("fetching model weights... (this may take a while)");
+                let model_file_path = hub
+                    .get(&model_repo_id, &model_repo_revision, &model_file_name)
+                    .await
+                    .context("Failed to fetch model file")?;
+                (model_repo_id, model_file_name, model_file_path)
+            }
+        };
 
         spinner.update("initializing model weights...");
-        let mut model_file =
-            std::fs::File::open(model_file_path).context("Failed to open model file")?;
-        let model_content = candle_core::quantized::gguf_file::Content::read(&mut model_file)
-            .context("gguf file read failed")?;
-        let model_weights = candle_transformers::models::quantized_llama::ModelWeights::from_gguf(
-            model_content,
-            &mut model_file,
-        )
-        .context("Failed creating model weights from gguf")?;
+        let (metadata, embedded_tokenizer, architecture, model_weights) = if is_safetensors {
+            // `config.json` and the shards listed by the index sit next to the model file, in the same directory or
+            // repo
+            let config_path = if is_local_model {
+                model_file_path.with_file_name(safetensors::CONFIG)
+            } else {
+                hub.get(&model_repo_id, &model_repo_revision, safetensors::CONFIG)
+                    .await
+                    .context("Failed to fetch config.json")?
+            };
+            let weight_paths = if model_file_name.ends_with(".index.json") {
+                let mut weight_paths = vec![];
+                for shard_file_name in safetensors::shard_file_names(&model_file_path)? {
+                    let shard_path = if is_local_model {
+                        model_file_path.with_file_name(&shard_file_name)
+                    } else {
+                        spinner.update(format!("fetching {}...", shard_file_name));
+                        hub.get(&model_repo_id, &model_repo_revision, &shard_file_name)
+                            .await
+                            .with_context(|| format!("Failed to fetch {}", shard_file_name))?
+                    };
+                    weight_paths.push(shard_path);
+                }
+                weight_paths
+            } else {
+                vec![model_file_path.clone()]
+            };
+            let (model_type, model_weights) =
+                safetensors::load(&config_path, &weight_paths, self.precision)
+                    .context("Failed creating model weights from safetensors")?;
+            (HashMap::new(), None, model_type, model_weights)
+        } else {
+            check_gguf(&model_file_path)?;
+            let mut model_file =
+                std::fs::File::open(&model_file_path).context("Failed to open model file")?;
+            let model_content = candle_core::quantized::gguf_file::Content::read(&mut model_file)
+                .context("gguf file read failed")?;
+            let embedded_tokenizer = match tokenizer_file {
+                Some(_) => None,
+                None => {
+                    spinner.update("reading tokenizer from the model file...");
+                    let tokenizer = gguf_tokenizer::from_gguf(&model_content).with_context(|| {
+                        format!(
+                            "Failed to read the tokenizer embedded in {}, set a tokenizer repo or file instead",
+                            model_file_path.display()
+                        )
+                    })?;
+                    Some(tokenizer)
+                }
+            };
+            let metadata = model_content.metadata.clone();
+            let architecture = models::architecture(&model_content)?;
+            let model_weights = models::from_gguf(model_content, &mut model_file)
+                .context("Failed creating model weights from gguf")?;
+            (metadata, embedded_tokenizer, architecture, model_weights)
+        };
+        debug!("architecture: {}", architecture);
+
+        let (
+            tokenizer_repo_id,
+            tokenizer_repo_revision,
+            tokenizer_file_name,
+            tokenizer,
+            tokenizer_config_path,
+        ) = match tokenizer_file {
+            Some((
+                tokenizer_repo_id,
+                tokenizer_file_name,
+                tokenizer_file_path,
+                tokenizer_config_path,
+            )) => {
+                spinner.update("initializing tokenizer...");
+                let tokenizer = load_tokenizer(&tokenizer_file_path)?;
+                (
+                    tokenizer_repo_id,
+                    tokenizer_repo_revision,
+                    tokenizer_file_name,
+                    tokenizer,
+                    tokenizer_config_path,
+                )
+            }
+            None => (
+                model_repo_id.clone(),
+                model_repo_revision.clone(),
+                model_file_name.clone(),
+                embedded_tokenizer.context("the model file embeds no tokenizer")?,
+                None,
+            ),
+        };
+        let mut special_tokens =
+            SpecialTokens::read(&metadata, tokenizer_config_path.as_deref(), &tokenizer)?;
+        special_tokens.add_stop_tokens(&self.eos_tokens, &tokenizer)?;
+        debug!("special tokens: {:?}", special_tokens);
+
+        let draft = match (self.draft_model_repo_id, self.draft_model_file_name) {
+            (Some(draft_model_repo_id), Some(draft_model_file_name)) => {
+                spinner.update("fetching draft model weights...");
+                let draft_model_file_path = hub
+                    .get(&draft_model_repo_id, "main", &draft_model_file_name)
+                    .await
+                    .context("Failed to fetch draft model file")?;
+                spinner.update("initializing draft model weights...");
+                check_gguf(&draft_model_file_path)?;
+                let mut draft_model_file = std::fs::File::open(draft_model_file_path)
+                    .context("Failed to open draft model file")?;
+                let draft_model_content =
+                    candle_core::quantized::gguf_file::Content::read(&mut draft_model_file)
+                        .context("draft gguf file read failed")?;
+                let draft_model_weights =
+                    models::from_gguf(draft_model_content, &mut draft_model_file)
+                        .context("Failed creating draft model weights from gguf")?;
+                Some(DraftModel {
+                    model_repo_id: draft_model_repo_id,
+                    model_file_name: draft_model_file_name,
+                    model_weights: draft_model_weights,
+                    cached_tokens: vec![],
+                    num_draft_tokens: self.num_draft_tokens.unwrap_or(4),
+                })
+            }
+            (None, None) => None,
+            _ => {
+                return Result::Err(anyhow!(
+                    "both draft_model_repo_id and draft_model_file_name are needed for speculative decoding"
+                ))
+            }
+        };
         spinner.success("LLM built successfully.");
 
         Ok(LLM {
@@ -287,16 +638,75 @@ impl LLMBuilder<ReadyState> {
             tokenizer_repo_revision,
             tokenizer_file_name,
             tokenizer,
+            special_tokens,
             model_repo_id,
             model_repo_revision,
             model_file_name,
+            model_file_path,
+            architecture,
             model_weights,
+            draft,
+            speculative_stats: SpeculativeStats::default(),
         })
     }
 }
+
+/// Splits a local file path into its directory and file name, e.g. to report where a model was loaded from.
+fn split_path(path: &Path) -> (String, String) {
+    let directory = path
+        .parent()
+        .map(|parent| parent.display().to_string())
+        .unwrap_or_default();
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    (directory, file_name)
+}
+
+/// Sits next to `tokenizer.json`, with the special tokens of the tokenizer.
+const TOKENIZER_CONFIG: &str = "tokenizer_config.json";
+
+/// Every GGUF file starts with these 4 bytes, see https://github.com/ggerganov/ggml/blob/master/docs/gguf.md
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+
+/// Fails with a readable error when the file is missing or isn't a GGUF file, e.g. a safetensors file or a GGML file
+/// of the older format, instead of the cryptic error of the GGUF parser.
+fn check_gguf(path: &Path) -> Result<()> {
+    if !path.is_file() {
+        return Err(anyhow!("model file {} not found", path.display()));
+    }
+    let mut magic = [0u8; 4];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .with_context(|| format!("Failed to read model file {}", path.display()))?;
+    if &magic != GGUF_MAGIC {
+        return Err(anyhow!(
+            "{} is not a GGUF file (it starts with {:?} instead of \"GGUF\")",
+            path.display(),
+            String::from_utf8_lossy(&magic)
+        ));
+    }
+    Ok(())
+}
+
+fn load_tokenizer(path: &Path) -> Result<tokenizers::Tokenizer> {
+    if !path.is_file() {
+        return Err(anyhow!("tokenizer file {} not found", path.display()));
+    }
+    tokenizers::Tokenizer::from_file(path).map_err(|error| {
+        anyhow!(
+            "{} is not a valid tokenizer file (a `tokenizer.json` is expected): {}",
+            path.display(),
+            error
+        )
+    })
+}
+
 #[cfg(test)]
 mod llm_builder_tests {
     use super::*;
+    use tempfile::TempDir;
 
     #[tokio::test]
     async fn can_accept_string() {
@@ -338,5 +748,95 @@ mod llm_builder_tests {
         assert!(ready_state.tokenizer_file_name.is_none());
         assert!(ready_state.tokenizer_repo_revision.is_none());
         assert!(ready_state.model_repo_revision.is_none());
+        assert!(ready_state.draft_model_repo_id.is_none());
+        assert!(ready_state.draft_model_file_name.is_none());
+    }
+
+    #[tokio::test]
+    async fn can_set_draft_model() {
+        let ready_state = LLMBuilder::new()
+            .tokenizer_repo_id("token_repo_id")
+            .model_repo_id("model_repo_id")
+            .model_file_name("model.file")
+            .draft_model_repo_id("draft_repo_id")
+            .draft_model_file_name("draft.file")
+            .num_draft_tokens(8);
+        assert!(ready_state.draft_model_repo_id.unwrap() == "draft_repo_id");
+        assert!(ready_state.draft_model_file_name.unwrap() == "draft.file");
+        assert!(ready_state.num_draft_tokens == Some(8));
+    }
+
+    #[tokio::test]
+    async fn can_use_local_files() {
+        let ready_state = LLMBuilder::new()
+            .tokenizer_path("/models/tokenizer.json")
+            .model_path("/models/model.gguf");
+        assert!(ready_state.tokenizer_path.unwrap() == Path::new("/models/tokenizer.json"));
+        assert!(ready_state.model_path.unwrap() == Path::new("/models/model.gguf"));
+        assert!(ready_state.tokenizer_repo_id.is_none());
+        assert!(ready_state.model_repo_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn can_skip_tokenizer() {
+        let ready_state = LLMBuilder::new()
+            .model_repo_id("model_repo_id")
+            .model_file_name("model.file");
+        assert!(ready_state.tokenizer_repo_id.is_none());
+        assert!(ready_state.tokenizer_path.is_none());
+        let ready_state = LLMBuilder::new().model_path("/models/model.gguf");
+        assert!(ready_state.model_path.unwrap() == Path::new("/models/model.gguf"));
+    }
+
+    #[tokio::test]
+    async fn can_set_precision() {
+        let ready_state = LLMBuilder::new()
+            .model_repo_id("model_repo_id")
+            .model_file_name("model.safetensors");
+        assert!(ready_state.precision == Precision::F32);
+        let ready_state = ready_state.precision(Precision::Bf16);
+        assert!(ready_state.precision == Precision::Bf16);
+    }
+
+    #[tokio::test]
+    async fn can_set_eos_tokens() {
+        let ready_state = LLMBuilder::new().model_path("/models/model.gguf");
+        assert!(ready_state.eos_tokens.is_empty());
+        let ready_state = ready_state.eos_tokens(vec!["<|im_end|>".to_string()]);
+        assert!(ready_state.eos_tokens == vec!["<|im_end|>"]);
+    }
+
+    /// Writes `content` to the file `name` of `dir`, a `tempfile::tempdir()` deleted when the test drops it.
+    fn temp_file(dir: &TempDir, name: &str, content: &[u8]) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn check_gguf_rejects_missing_and_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.gguf");
+        let error = check_gguf(&missing).unwrap_err().to_string();
+        assert!(error.contains("not found"));
+
+        let safetensors = temp_file(&dir, "model.safetensors", b"\x08\x00\x00\x00{}");
+        let error = check_gguf(&safetensors).unwrap_err().to_string();
+        assert!(error.contains("is not a GGUF file"));
+
+        let gguf = temp_file(&dir, "model.gguf", b"GGUF\x03\x00\x00\x00");
+        assert!(check_gguf(&gguf).is_ok());
+    }
+
+    #[test]
+    fn load_tokenizer_rejects_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing-tokenizer.json");
+        let error = load_tokenizer(&missing).unwrap_err().to_string();
+        assert!(error.contains("not found"));
+
+        let invalid = temp_file(&dir, "tokenizer.json", b"{}");
+        let error = load_tokenizer(&invalid).unwrap_err().to_string();
+        assert!(error.contains("is not a valid tokenizer file"));
     }
 }
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use oxpilot::llm::LLMBuilder;
+use oxpilot::models::safetensors::Precision;
+use oxpilot::registry::ModelEntry;
+use oxpilot::scheduler::{Scheduler, SchedulerOptions};
+use oxpilot::utils::fim::FimTemplate;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::state::{ModelInfo, ServedModel};
+
+/// Builds the LLM of a model entry and spawns its scheduler task, at startup for every model, and later for the
+/// models loaded through the admin endpoints.
+#[derive(Clone)]
+pub struct ModelLoader {
+    /// Used when the entry doesn't set `tokenizer_repo_id`, from `--tokenizer-repo-id`, without any tokenizer the
+    /// tokenizer embedded in the GGUF file is used.
+    pub tokenizer_repo_id: Option<String>,
+    /// Used when the entry doesn't set a tokenizer, instead of `tokenizer_repo_id`, from `--tokenizer-path`.
+    pub tokenizer_path: Option<PathBuf>,
+    /// Used when the entry doesn't set `tokenizer_file`, from `--tokenizer-file`.
+    pub tokenizer_file: String,
+    /// Used when the entry doesn't set `fim_template`, from `--fim-template`.
+    pub fim_template: FimTemplate,
+    /// From `--offline`.
+    pub offline: bool,
+    /// From `--dtype`.
+    pub precision: Precision,
+    /// From `--draft-model-repo-id`, `--draft-model-file-name` and `--draft-tokens`.
+    pub draft_model: Option<(String, String, usize)>,
+    pub options: SchedulerOptions,
+}
+
+impl ModelLoader {
+    /// The draft model shares the tokenizer of `--tokenizer-repo-id`, so it's only used `with_draft`, i.e. for the
+    /// model of the CLI flags.
+    pub async fn spawn(
+        &self,
+        entry: ModelEntry,
+        with_draft: bool,
+        is_silent: bool,
+    ) -> Result<ServedModel> {
+        debug!("model {:?}", &entry.name);
+        // the tokenizer of the entry, from a repo or a local file, otherwise the one of the CLI flags, otherwise the one
+        // embedded in the GGUF file
+        let (tokenizer_path, tokenizer_repo_id) =
+            match (entry.tokenizer_path, entry.tokenizer_repo_id) {
+                (None, None) => (self.tokenizer_path.clone(), self.tokenizer_repo_id.clone()),
+                entry_tokenizer => entry_tokenizer,
+            };
+        debug!(
+            "model_path: {:?}, model_repo_id: {:?}, model_file_name: {:?}",
+            &entry.model_path, &entry.model_repo_id, &entry.model_file_name
+        );
+        let mut llm_builder = match (tokenizer_path, tokenizer_repo_id, entry.model_path) {
+            (Some(tokenizer_path), _, Some(model_path)) => LLMBuilder::new()
+                .tokenizer_path(tokenizer_path)
+                .model_path(model_path),
+            (Some(tokenizer_path), _, None) => LLMBuilder::new()
+                .tokenizer_path(tokenizer_path)
+                .model_repo_id(entry.model_repo_id)
+                .model_file_name(entry.model_file_name),
+            (None, Some(tokenizer_repo_id), Some(model_path)) => LLMBuilder::new()
+                .tokenizer_repo_id(tokenizer_repo_id)
+                .model_path(model_path),
+            (None, Some(tokenizer_repo_id), None) => LLMBuilder::new()
+                .tokenizer_repo_id(tokenizer_repo_id)
+                .model_repo_id(entry.model_repo_id)
+                .model_file_name(entry.model_file_name),
+            (None, None, Some(model_path)) => LLMBuilder::new().model_path(model_path),
+            (None, None, None) => LLMBuilder::new()
+                .model_repo_id(entry.model_repo_id)
+                .model_file_name(entry.model_file_name),
+        }
+        .tokenizer_file_name(entry.tokenizer_file.unwrap_or(self.tokenizer_file.clone()))
+        .offline(self.offline)
+        .precision(self.precision)
+        .eos_tokens(entry.eos_tokens);
+        if let Some(model_repo_revision) = entry.model_repo_revision {
+            llm_builder = llm_builder.model_repo_revision(model_repo_revision);
+        }
+        if let (true, Some((draft_model_repo_id, draft_model_file_name, num_draft_tokens))) =
+            (with_draft, &self.draft_model)
+        {
+            debug!(
+                "draft model: {:?} {:?}",
+                draft_model_repo_id, draft_model_file_name
+            );
+            llm_builder = llm_builder
+                .draft_model_repo_id(draft_model_repo_id)
+                .draft_model_file_name(draft_model_file_name)
+                .num_draft_tokens(*num_draft_tokens);
+        }
+        let llm = llm_builder.build(is_silent).await?;
+        let info = ModelInfo {
+            name: entry.name,
+            architecture: llm.architecture.clone(),
+            ..ModelInfo::new(
+                &llm.model_repo_id,
+                &llm.model_file_name,
+                std::fs::metadata(&llm.model_file_path)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0),
+            )
+        };
+
+        let (tx, rx) = mpsc::channel(32);
+        // the scheduler owns the LLM, and decodes the prompts sent with `tx.send().await` in batches, it exits and
+        // frees the LLM once every `tx` is dropped and the prompts already sent are done
+        let scheduler = Scheduler::new(llm, self.options.clone());
+        // the forward passes block for seconds, the scheduler gets its own thread instead of a tokio worker, the
+        // runtime context lets it spawn the tasks sending the texts left for slow clients
+        let runtime = tokio::runtime::Handle::current();
+        std::thread::spawn(move || {
+            let _guard = runtime.enter();
+            scheduler.run(rx)
+        });
+
+        Ok(ServedModel {
+            tx,
+            info,
+            aliases: entry.aliases,
+            template: entry.template,
+            fim_template: entry.fim_template.unwrap_or(self.fim_template),
+        })
+    }
+}
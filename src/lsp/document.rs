@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+
+/// Position in a text document expressed as zero-based line and zero-based character offset.
+/// The character offset is counted in UTF-16 code units, which is the default position encoding of LSP.
+/// https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#position
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// An event describing a change to a text document. If `range` is omitted the new text is considered to be the full
+/// content of the document (full sync), otherwise the text replaces the range (incremental sync).
+#[derive(Debug, Deserialize)]
+pub struct TextDocumentContentChangeEvent {
+    pub range: Option<Range>,
+    pub text: String,
+}
+
+/// A text document opened in the editor, kept in sync with `textDocument/didOpen` and `textDocument/didChange`.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub language_id: String,
+    pub version: i64,
+    pub text: String,
+}
+
+impl Document {
+    pub fn new(language_id: impl Into<String>, version: i64, text: impl Into<String>) -> Self {
+        Document {
+            language_id: language_id.into(),
+            version,
+            text: text.into(),
+        }
+    }
+
+    /// Converts a LSP `Position` into a byte offset into `text`.
+    /// Positions past the end of a line are clamped to the end of the line, and positions past the end of the
+    /// document are clamped to the end of the document, as the specification requires.
+    pub fn offset_at(&self, position: Position) -> usize {
+        let mut line_start = 0;
+        for _ in 0..position.line {
+            match self.text[line_start..].find('\n') {
+                Some(index) => line_start += index + 1,
+                None => return self.text.len(),
+            }
+        }
+        let line_end = self.text[line_start..]
+            .find('\n')
+            .map(|index| line_start + index)
+            .unwrap_or(self.text.len());
+
+        let mut utf16_units = 0;
+        for (index, character) in self.text[line_start..line_end].char_indices() {
+            if utf16_units >= position.character as usize {
+                return line_start + index;
+            }
+            utf16_units += character.len_utf16();
+        }
+        line_end
+    }
+
+    /// Applies a `textDocument/didChange` content change.
+    pub fn apply_change(&mut self, change: &TextDocumentContentChangeEvent) {
+        match change.range {
+            Some(range) => {
+                let start = self.offset_at(range.start);
+                let end = self.offset_at(range.end).max(start);
+                self.text.replace_range(start..end, &change.text);
+            }
+            None => self.text = change.text.clone(),
+        }
+    }
+
+    /// Splits the document at `position` into the text before and after the cursor.
+    pub fn split_at(&self, position: Position) -> (&str, &str) {
+        self.text.split_at(self.offset_at(position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(line: u32, character: u32) -> Position {
+        Position { line, character }
+    }
+
+    #[test]
+    fn offset_at_counts_utf16_code_units() {
+        // '😀' is one char, 4 bytes in UTF-8 and 2 code units in UTF-16
+        let document = Document::new("rust", 1, "fn a() {}\nlet 😀 = 1;\n");
+        assert_eq!(document.offset_at(position(0, 0)), 0);
+        assert_eq!(document.offset_at(position(1, 4)), 14);
+        assert_eq!(document.offset_at(position(1, 6)), 18);
+        // past the end of the line
+        assert_eq!(document.offset_at(position(0, 100)), 9);
+        // past the end of the document
+        assert_eq!(document.offset_at(position(10, 0)), document.text.len());
+    }
+
+    #[test]
+    fn apply_incremental_and_full_changes() {
+        let mut document = Document::new("rust", 1, "fn main() {\n}\n");
+        document.apply_change(&TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: position(0, 11),
+                end: position(0, 11),
+            }),
+            text: "\n    println!();".to_string(),
+        });
+        assert_eq!(document.text, "fn main() {\n    println!();\n}\n");
+
+        document.apply_change(&TextDocumentContentChangeEvent {
+            range: Some(Range {
+                start: position(1, 4),
+                end: position(1, 14),
+            }),
+            text: "todo!()".to_string(),
+        });
+        assert_eq!(document.text, "fn main() {\n    todo!();\n}\n");
+
+        document.apply_change(&TextDocumentContentChangeEvent {
+            range: None,
+            text: "".to_string(),
+        });
+        assert_eq!(document.text, "");
+    }
+
+    #[test]
+    fn split_at_cursor() {
+        let document = Document::new("rust", 1, "let a = ;\n");
+        assert_eq!(document.split_at(position(0, 8)), ("let a = ", ";\n"));
+    }
+}
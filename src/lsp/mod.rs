@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tokio::io::BufReader;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+
+use crate::cmd::{Command, Priority};
+use crate::speculative::Decoding;
+use crate::utils::fim::{head, tail, FimTemplate};
+use document::{Document, Position, TextDocumentContentChangeEvent};
+use transport::{read_message, write_message};
+
+pub mod document;
+pub mod transport;
+
+/// How many characters before the cursor are sent to the model.
+const MAX_PREFIX_CHARS: usize = 6000;
+/// How many characters after the cursor are sent to the model.
+const MAX_SUFFIX_CHARS: usize = 2000;
+
+/// JSON-RPC error codes
+/// https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#errorCodes
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+pub struct LspOptions {
+    pub fim_template: FimTemplate,
+    pub max_tokens: usize,
+    pub decoding: Decoding,
+}
+
+/// Documents opened in the editor, keyed by uri.
+///
+/// `std::sync::Mutex` is fine (and cheaper than `tokio::sync::Mutex`) because the lock is never held across an `.await`.
+/// https://tokio.rs/tokio/tutorial/shared-state#on-using-stdsyncmutex
+type Documents = Arc<Mutex<HashMap<String, Document>>>;
+
+/// Runs a language server over stdio until the client sends `exit` or closes stdin.
+///
+/// Completions are generated by sending `Command::Prompt` to the manager task via `tx`, the same way the HTTP
+/// endpoints do. Each completion request is handled in its own task so the server keeps reading document changes
+/// while the model is generating.
+pub async fn run(tx: mpsc::Sender<Command>, options: LspOptions) -> Result<()> {
+    let options = Arc::new(options);
+    let documents: Documents = Arc::new(Mutex::new(HashMap::new()));
+
+    // All outgoing messages go through one writer task, so responses of concurrent requests never interleave.
+    let (outgoing, mut outgoing_receiver) = mpsc::channel::<Value>(32);
+    let writer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(message) = outgoing_receiver.recv().await {
+            if let Err(error) = write_message(&mut stdout, &message).await {
+                error!("failed to write LSP message: {}", error);
+                break;
+            }
+        }
+    });
+
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    let mut is_shutdown = false;
+    while let Some(message) = read_message(&mut stdin).await? {
+        let method = message["method"].as_str().unwrap_or_default().to_string();
+        let id = message.get("id").cloned();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        debug!("LSP message: {} {:?}", method, id);
+
+        match (method.as_str(), id) {
+            // a message without `method` is a response to a request sent by us, we don't send any
+            ("", _) => {}
+            ("exit", _) => break,
+            (_, Some(id)) if is_shutdown => {
+                respond_error(&outgoing, id, INVALID_REQUEST, "server is shutting down").await;
+            }
+            ("initialize", Some(id)) => {
+                respond(
+                    &outgoing,
+                    id,
+                    json!({
+                        "capabilities": {
+                            "textDocumentSync": {
+                                "openClose": true,
+                                // 2 = Incremental, documents are synced by sending only the changed ranges
+                                "change": 2,
+                            },
+                            "completionProvider": {
+                                "resolveProvider": false,
+                            },
+                            "inlineCompletionProvider": {},
+                        },
+                        "serverInfo": {
+                            "name": "ox",
+                            "version": env!("CARGO_PKG_VERSION"),
+                        },
+                    }),
+                )
+                .await;
+            }
+            ("shutdown", Some(id)) => {
+                is_shutdown = true;
+                respond(&outgoing, id, Value::Null).await;
+            }
+            ("textDocument/didOpen", None) => {
+                let document = &params["textDocument"];
+                if let Some(uri) = document["uri"].as_str() {
+                    documents.lock().unwrap().insert(
+                        uri.to_string(),
+                        Document::new(
+                            document["languageId"].as_str().unwrap_or_default(),
+                            document["version"].as_i64().unwrap_or_default(),
+                            document["text"].as_str().unwrap_or_default(),
+                        ),
+                    );
+                }
+            }
+            ("textDocument/didChange", None) => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let changes: Vec<TextDocumentContentChangeEvent> =
+                    match serde_json::from_value(params["contentChanges"].clone()) {
+                        Ok(changes) => changes,
+                        Err(error) => {
+                            warn!("invalid textDocument/didChange params: {}", error);
+                            continue;
+                        }
+                    };
+                match documents.lock().unwrap().get_mut(uri) {
+                    Some(document) => {
+                        // changes must be applied in the order they are received
+                        for change in &changes {
+                            document.apply_change(change);
+                        }
+                        if let Some(version) = params["textDocument"]["version"].as_i64() {
+                            document.version = version;
+                        }
+                    }
+                    None => warn!("textDocument/didChange for unknown document {}", uri),
+                }
+            }
+            ("textDocument/didClose", None) => {
+                if let Some(uri) = params["textDocument"]["uri"].as_str() {
+                    documents.lock().unwrap().remove(uri);
+                }
+            }
+            ("textDocument/inlineCompletion", Some(id)) | ("textDocument/completion", Some(id)) => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let position: Position = match serde_json::from_value(params["position"].clone()) {
+                    Ok(position) => position,
+                    Err(error) => {
+                        respond_error(&outgoing, id, INVALID_PARAMS, &error.to_string()).await;
+                        continue;
+                    }
+                };
+                // take a snapshot of the text around the cursor, the document might change while generating
+                let prompt = match documents.lock().unwrap().get(uri) {
+                    Some(document) => {
+                        let (prefix, suffix) = document.split_at(position);
+                        options.fim_template.format(
+                            tail(prefix, MAX_PREFIX_CHARS),
+                            head(suffix, MAX_SUFFIX_CHARS),
+                        )
+                    }
+                    None => {
+                        warn!("{} for unknown document {}", method, uri);
+                        String::new()
+                    }
+                };
+                let method = method.clone();
+                let uri = uri.to_string();
+                let tx = tx.clone();
+                let outgoing = outgoing.clone();
+                let options = options.clone();
+                tokio::spawn(async move {
+                    let text = if prompt.is_empty() {
+                        String::new()
+                    } else {
+                        match complete(&tx, prompt, uri, &options).await {
+                            Ok(text) => text,
+                            Err(error) => {
+                                respond_error(&outgoing, id, INTERNAL_ERROR, &error.to_string())
+                                    .await;
+                                return;
+                            }
+                        }
+                    };
+                    info!("{}: {:?}", method, text);
+                    let result = if method == "textDocument/inlineCompletion" {
+                        inline_completion_list(&text, position)
+                    } else {
+                        completion_list(&text)
+                    };
+                    respond(&outgoing, id, result).await;
+                });
+            }
+            (_, Some(id)) => {
+                respond_error(&outgoing, id, METHOD_NOT_FOUND, &method).await;
+            }
+            // other notifications, e.g. `initialized` or `$/cancelRequest`
+            (_, None) => {}
+        }
+    }
+
+    drop(outgoing);
+    let _ = writer.await;
+    Ok(())
+}
+
+/// Sends the prompt to the manager task and waits for the whole completion.
+async fn complete(
+    tx: &mpsc::Sender<Command>,
+    prompt: String,
+    uri: String,
+    options: &LspOptions,
+) -> Result<String> {
+    let (responder, mut receiver) = mpsc::channel(8);
+    let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
+    tx.send(Command::Prompt {
+        prompt,
+        responder,
+        temperature: None,
+        seed: None,
+        top_p: None,
+        max_sampled: options.max_tokens,
+        decoding: options.decoding,
+        priority: Priority::Interactive,
+        // a completion requested after an edit of the same document makes the previous one useless
+        session: Some(uri),
+        prompt_tokens: Some(prompt_tokens),
+    })
+    .await
+    .map_err(|error| anyhow::anyhow!("failed to send prompt to LLM manager: {}", error))?;
+    // e.g. the text around the cursor is longer than the context of the model
+    if let Ok(Err(error)) = prompt_tokens_receiver.await {
+        return Err(error.into());
+    }
+    let mut text = String::new();
+    while let Some(token) = receiver.recv().await {
+        text.push_str(&token);
+    }
+    Ok(text)
+}
+
+/// `InlineCompletionList`, the text is inserted at the cursor.
+/// https://microsoft.github.io/language-server-protocol/specifications/lsp/3.18/specification/#textDocument_inlineCompletion
+fn inline_completion_list(text: &str, position: Position) -> Value {
+    if text.is_empty() {
+        return json!({ "items": [] });
+    }
+    json!({
+        "items": [{
+            "insertText": text,
+            "range": { "start": position, "end": position },
+        }]
+    })
+}
+
+/// `CompletionList` with one item labelled by the first line of the completion.
+/// https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#textDocument_completion
+fn completion_list(text: &str) -> Value {
+    let label = text.trim().lines().next().unwrap_or_default();
+    if label.is_empty() {
+        return json!({ "isIncomplete": false, "items": [] });
+    }
+    json!({
+        "isIncomplete": false,
+        "items": [{
+            "label": label,
+            // 1 = Text
+            "kind": 1,
+            "detail": "ox",
+            "insertText": text,
+        }]
+    })
+}
+
+async fn respond(outgoing: &mpsc::Sender<Value>, id: Value, result: Value) {
+    let _ = outgoing
+        .send(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+        .await;
+}
+
+async fn respond_error(outgoing: &mpsc::Sender<Value>, id: Value, code: i64, message: &str) {
+    let _ = outgoing
+        .send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }))
+        .await;
+}
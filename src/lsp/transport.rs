@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Reads one JSON-RPC message framed by LSP's base protocol, e.g.
+/// ```text
+/// Content-Length: 52\r\n
+/// \r\n
+/// {"jsonrpc":"2.0","id":1,"method":"initialize",...}
+/// ```
+/// Returns `Ok(None)` when the input is closed.
+/// https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#baseProtocol
+pub async fn read_message(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<Value>> {
+    let mut content_length: Option<usize> = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        // an empty line separates the header part from the content part
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .context("invalid Content-Length header")?,
+                );
+            }
+        }
+    }
+    let content_length = content_length.ok_or(anyhow!("missing Content-Length header"))?;
+    let mut content = vec![0; content_length];
+    reader.read_exact(&mut content).await?;
+    Ok(Some(
+        serde_json::from_slice(&content).context("invalid JSON-RPC message")?,
+    ))
+}
+
+/// Writes one JSON-RPC message with the `Content-Length` header.
+pub async fn write_message(writer: &mut (impl AsyncWrite + Unpin), message: &Value) -> Result<()> {
+    let content = serde_json::to_string(message)?;
+    writer
+        .write_all(format!("Content-Length: {}\r\n\r\n{}", content.len(), content).as_bytes())
+        .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let message = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize"});
+        let mut buffer: Vec<u8> = vec![];
+        write_message(&mut buffer, &message).await.unwrap();
+        write_message(&mut buffer, &message).await.unwrap();
+
+        let mut reader = tokio::io::BufReader::new(buffer.as_slice());
+        assert_eq!(
+            read_message(&mut reader).await.unwrap(),
+            Some(message.clone())
+        );
+        assert_eq!(read_message(&mut reader).await.unwrap(), Some(message));
+        assert_eq!(read_message(&mut reader).await.unwrap(), None);
+    }
+}
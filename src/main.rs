@@ -1,32 +1,45 @@
+use std::future::IntoFuture;
 use std::io::Write;
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use axum::{routing::post, Router};
+use axum::{
+    routing::{delete, get, post},
+    Router,
+};
 use candle_core::utils::{get_num_threads, has_accelerate, has_mkl};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use clap_verbosity_flag::Verbosity;
 use inquire::{Select, Text};
+use loader::ModelLoader;
+use oxpilot::cache::{self, Cache, Hub};
 use oxpilot::cli::{CLICommands, CLI};
-use oxpilot::cmd::Command::Prompt;
-use oxpilot::llm::LLMBuilder;
-use oxpilot::process::process;
+use oxpilot::cmd::{Command::Prompt, Priority};
+use oxpilot::config::Config;
+use oxpilot::inspect::inspect;
+use oxpilot::lsp::{self, LspOptions};
+use oxpilot::quantize::{quantize, QuantizeOptions};
+use oxpilot::registry::{ModelEntry, Registry};
+use oxpilot::scheduler::SchedulerOptions;
+use oxpilot::speculative::Decoding;
 use oxpilot::utils::commit::commit_then_exit;
 use oxpilot::utils::diff::get_diff;
-use oxpilot::utils::mistral;
+use oxpilot::utils::fim::FimTemplate;
+use oxpilot::utils::models;
 use oxpilot::utils::spinner::SilentableSpinner;
+use oxpilot::utils::template::PromptTemplate;
 use regex::Regex;
 use routes::completion::completion;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info, warn};
+use routes::{admin, copilot, llamacpp, ollama, tgi, ws};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
 use tracing_log::{log, AsTrace};
 use tracing_subscriber::fmt::format::FmtSpan;
 
-pub mod llm;
-pub mod process;
+pub mod loader;
 pub mod routes;
+pub mod shutdown;
 pub mod state;
-pub mod token;
-pub mod utils;
 
 // The `#[tokio::main]` function is a macro. It transforms the async fn main()
 // into a synchronous fn main() that initializes a runtime instance and executes the async main function.
@@ -47,11 +60,15 @@ pub mod utils;
 // ```
 #[tokio::main]
 async fn main() {
-    // Parse command line arguments
-    let cli = CLI::parse();
+    // Parse command line arguments, the matches tell the flags given on the command line from the defaults, see
+    // `Preset::apply`
+    let matches = CLI::command().get_matches();
+    let mut cli = CLI::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
 
     let verbosity: &Verbosity = &cli.verbose;
-    let is_silent: bool = verbosity.is_silent();
+    // stdout is the transport of the language server, spinners would corrupt the messages
+    let is_silent: bool =
+        verbosity.is_silent() || matches!(cli.command, Some(CLICommands::Lsp { .. }));
     let log_level = verbosity.log_level();
     let is_debug = match log_level {
         Some(log_level) => log_level == log::Level::Debug,
@@ -65,7 +82,9 @@ async fn main() {
     // Start configuring a `fmt` subscriber
     let subscriber = tracing_subscriber::fmt()
         // Set log level by `-v` or `-vv` or `-vvv` or `-vvvv` or `-vvvvv
-        .with_max_level(cli.verbose.log_level_filter().as_trace());
+        .with_max_level(cli.verbose.log_level_filter().as_trace())
+        // Write logs to stderr, stdout is reserved for the output, e.g. the response of `ox hi`, or LSP messages.
+        .with_writer(std::io::stderr);
     let format = tracing_subscriber::fmt::format()
         // Use a more compact, abbreviated log format
         .compact()
@@ -99,76 +118,250 @@ async fn main() {
     }
     info!("number of thread: {:?} used by candle", get_num_threads());
 
-    debug!("tokenizer_repo_id: {:?}", &cli.tokenizer_repo_id);
-    debug!("model_repo_id: {:?}", &cli.model_repo_id);
-    debug!("model_file_name: {:?}", &cli.model_file_name);
-    let llm_builder = LLMBuilder::new()
-        .tokenizer_repo_id(cli.tokenizer_repo_id)
-        .model_repo_id(cli.model_repo_id)
-        .model_file_name(cli.model_file_name);
-    let mut llm = llm_builder
-        .build(is_silent)
-        .await
-        .expect("Failed to build LLM");
-
-    let (tx, mut rx) = mpsc::channel(32);
-    let _ = tokio::spawn(async move {
-        let seed = cli.seed;
-        let top_p = cli.top_p;
-        let to_sample = cli.to_sample;
-        let repeat_last_n = cli.repeat_last_n;
-        let repeat_penalty = cli.repeat_penalty;
-        let eos_token = "</s>";
-        while let Some(cmd) = rx.recv().await {
-            match cmd {
-                // handle Command::Prompt from `tx.send().await`;
-                Prompt {
-                    prompt,
-                    responder,
-                    temperature,
-                    max_sampled,
-                } => {
-                    debug!("prompt:{}", prompt);
-                    process(
-                        prompt,
-                        &mut llm,
-                        responder,
-                        to_sample,
-                        seed,
-                        temperature,
-                        top_p,
-                        repeat_last_n,
-                        repeat_penalty,
-                        eos_token.to_string(),
-                        max_sampled,
-                    )
-                    .await;
-                }
+    // `--model <alias>`, or the default preset of the config, replaces the defaults of the flags
+    let config = match &cli.config {
+        Some(path) => Config::load(path, true),
+        None => Config::default_path().and_then(|path| Config::load(path, false)),
+    }
+    .expect("Failed to load config");
+    let preset = config
+        .select(cli.model.as_deref())
+        .expect("Failed to select model preset")
+        .map(|(name, preset)| (name.clone(), preset.clone()));
+    if let Some((name, preset)) = &preset {
+        info!("using model preset {:?}", name);
+        preset.apply(&mut cli, &matches);
+    }
+
+    // `ox quantize` converts files, it doesn't need a model
+    if let Some(CLICommands::Quantize {
+        input,
+        output,
+        quant_type,
+        skip,
+    }) = &cli.command
+    {
+        let mut spinner = SilentableSpinner::new(
+            is_silent,
+            Some(format!("quantizing {}...", input.display())),
+        );
+        let options = QuantizeOptions {
+            input: input.clone(),
+            output: output.clone(),
+            quant_type: *quant_type,
+            skip: skip.clone(),
+        };
+        match quantize(&options) {
+            Ok(stats) => spinner.success(&format!(
+                "wrote {} ({} MB), {} of {} tensors quantized",
+                output.display(),
+                stats.size / 1_000_000,
+                stats.quantized,
+                stats.tensors
+            )),
+            Err(error) => {
+                spinner.fail(&format!("failed to quantize: {:#}", error));
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // `ox models` manages the cache, it doesn't need a model either
+    if let Some(CLICommands::Models { command }) = &cli.command {
+        let cache = Cache::new(cache::default_dir().expect("Failed to get the cache dir"));
+        if let Err(error) = models::run(command, &cache).await {
+            error!("{:#}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `ox inspect` only reads the header of a GGUF file, the weights are never loaded
+    if let Some(CLICommands::Inspect {
+        file_or_repo,
+        file_name,
+        json,
+        tensors,
+    }) = &cli.command
+    {
+        let hub = Hub {
+            cache_dir: cache::default_dir().expect("Failed to get the cache dir"),
+            offline: cli.offline,
+        };
+        // a local file, a file of a repo, or the model of `--model-path` or `--model-repo-id`
+        let path = match (file_or_repo, &cli.model_path) {
+            (Some(path), _) if std::path::Path::new(path).is_file() => Ok(path.into()),
+            (Some(repo_id), _) => match file_name {
+                Some(file_name) => hub.get(repo_id, &cli.model_repo_revision, file_name).await,
+                None => Err(anyhow::anyhow!(
+                    "{} is neither a file nor a repo followed by a file name, e.g. `ox inspect {} model.Q4_K_M.gguf`",
+                    repo_id,
+                    repo_id
+                )),
+            },
+            (None, Some(model_path)) => Ok(model_path.clone()),
+            (None, None) => {
+                hub.get(
+                    &cli.model_repo_id,
+                    &cli.model_repo_revision,
+                    &cli.model_file_name,
+                )
+                .await
+            }
+        };
+        match path.and_then(|path| inspect(&path)) {
+            Ok(inspection) if *json => println!(
+                "{}",
+                serde_json::to_string_pretty(&inspection).expect("Failed to serialize")
+            ),
+            Ok(inspection) => print!("{}", inspection.to_text(*tensors)),
+            Err(error) => {
+                error!("{:#}", error);
+                std::process::exit(1);
             }
         }
-    });
+        return;
+    }
+
+    // `ox serve --models <registry>` loads every model of the registry, the other commands load `--model-repo-id`
+    let registry_path = match &cli.command {
+        Some(CLICommands::Serve { models, .. }) => models.as_ref(),
+        _ => None,
+    };
+    let entries = match registry_path {
+        Some(path) => Registry::load(path)
+            .expect("Failed to load model registry")
+            .into_models(),
+        None => vec![ModelEntry {
+            name: match (&preset, &cli.model_path) {
+                (Some((name, _)), _) => name.clone(),
+                (None, Some(model_path)) => model_path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                (None, None) => cli.model_file_name.trim_end_matches(".gguf").to_string(),
+            },
+            // a single model answers to any model name, e.g. Copilot's `copilot-codex` engine
+            aliases: vec!["*".to_string()],
+            tokenizer_repo_id: None,
+            tokenizer_file: None,
+            tokenizer_path: None,
+            model_repo_id: cli.model_repo_id.clone(),
+            model_repo_revision: Some(cli.model_repo_revision.clone()),
+            model_file_name: cli.model_file_name.clone(),
+            model_path: cli.model_path.clone(),
+            template: preset
+                .as_ref()
+                .and_then(|(_, preset)| preset.template)
+                .unwrap_or(PromptTemplate::Mistral),
+            fim_template: None,
+            eos_tokens: preset
+                .as_ref()
+                .map(|(_, preset)| preset.eos_tokens.clone())
+                .unwrap_or_default(),
+        }],
+    };
+    let loader = ModelLoader {
+        tokenizer_repo_id: cli.tokenizer_repo_id.clone(),
+        tokenizer_path: cli.tokenizer_path.clone(),
+        tokenizer_file: cli.tokenizer_file.clone(),
+        offline: cli.offline,
+        precision: cli.dtype,
+        fim_template: match &cli.command {
+            Some(CLICommands::Serve { fim_template, .. }) => *fim_template,
+            _ => FimTemplate::default(),
+        },
+        draft_model: match (&cli.draft_model_repo_id, &cli.draft_model_file_name) {
+            (Some(draft_model_repo_id), Some(draft_model_file_name)) => Some((
+                draft_model_repo_id.clone(),
+                draft_model_file_name.clone(),
+                cli.draft_tokens,
+            )),
+            _ => None,
+        },
+        options: SchedulerOptions {
+            temperature: cli.temperature,
+            to_sample: cli.to_sample,
+            seed: cli.seed,
+            top_p: cli.top_p,
+            repeat_last_n: cli.repeat_last_n,
+            repeat_penalty: cli.repeat_penalty,
+            max_batch_size: cli.max_batch_size,
+        },
+    };
+    // the draft model shares the tokenizer of `--tokenizer-repo-id`, so it only speeds up the model of the CLI
+    let with_draft = registry_path.is_none();
+    let mut models = vec![];
+    for entry in entries {
+        let model = loader
+            .spawn(entry, with_draft, is_silent)
+            .await
+            .expect("Failed to build LLM");
+        models.push(model);
+    }
+    // the default model is the first one, it's the only model of the commands other than `ox serve`
+    let tx = models[0].tx.clone();
+    let template = models[0].template;
 
     match &cli.command {
-        Some(CLICommands::Serve { port }) => {
+        Some(CLICommands::Serve {
+            port,
+            decoding,
+            admin_token,
+            shutdown_timeout,
+            ..
+        }) => {
             info!("starting copilot server on port: {}", &port);
-            let state = state::AppState { tx };
+            // the models are only referenced by the state, so that a model swapped by the admin endpoints is unloaded
+            drop(tx);
+            let state = state::AppState::new(models, *decoding, loader, admin_token.clone());
             let address = SocketAddr::from(([0, 0, 0, 0], port.to_owned()));
             let listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+            let shutdown = state.shutdown.clone();
             let app = app(state);
 
-            match axum::serve(listener, app).await {
-                Ok(_) => info!("copilot server exited."),
-                Err(error) => {
-                    info!("server exited with error: {}", error);
-                    info!("terminating LLM manager");
+            // the remote address identifies the clients that send no other id, see `routes::Client`
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            // on Ctrl-C or SIGTERM, stop accepting connections and wait for the open ones to close, the generations
+            // still running after `--shutdown-timeout` are cancelled
+            let server =
+                axum::serve(listener, app).with_graceful_shutdown(shutdown.clone().signalled());
+            tokio::select! {
+                result = server.into_future() => match result {
+                    Ok(_) => info!("copilot server exited."),
+                    Err(error) => {
+                        info!("server exited with error: {}", error);
+                        info!("terminating LLM manager");
+                    }
+                },
+                _ = shutdown.clone().cancel_after(Duration::from_secs(*shutdown_timeout)) => {
+                    warn!("connections still open after cancelling the generations, exiting anyway");
                 }
             }
+            shutdown.log_summary();
+        }
+        Some(CLICommands::Lsp {
+            fim_template,
+            max_tokens,
+            decoding,
+        }) => {
+            info!("starting language server on stdio");
+            let options = LspOptions {
+                fim_template: *fim_template,
+                max_tokens: *max_tokens,
+                decoding: *decoding,
+            };
+            if let Err(error) = lsp::run(tx, options).await {
+                error!("language server exited with error: {}", error);
+                std::process::exit(1);
+            }
         }
         Some(CLICommands::Commit {
             dry_run,
             function_context,
             all_yes,
             signoff,
+            decoding,
         }) => {
             let mut spinner = SilentableSpinner::new(
                 is_silent,
@@ -176,7 +369,7 @@ async fn main() {
             );
             spinner.update("getting git diff of staged files...");
             let diff = get_diff(*function_context).await;
-            if diff.len() == 0 {
+            if diff.is_empty() {
                 spinner.fail("no diff found, have you staged any?");
                 std::process::exit(1);
             }
@@ -185,17 +378,29 @@ async fn main() {
                 tip = "large diff takes longer, commit often 😊"
             }
             spinner.update(format!("generating commit message... (tip: {})", tip));
-            let prompt = mistral::instruct(format!("Summarize the git diff in one sentence no more then 15 words. The summary starts with 'fix: ' if the git diff fixes bugs. Starts with 'feat: ' if introducing a new feature. 'chore: ' for reformatting code or adding stuff around the build tools. 'docs: ' for documentations. The summary should be concise but comprehensive covering what has changed and explaining why.\n{}\nDo NOT start with 'This git diff' or 'committed:'.", diff));
+            let prompt = template.instruct(format!("Summarize the git diff in one sentence no more then 15 words. The summary starts with 'fix: ' if the git diff fixes bugs. Starts with 'feat: ' if introducing a new feature. 'chore: ' for reformatting code or adding stuff around the build tools. 'docs: ' for documentations. The summary should be concise but comprehensive covering what has changed and explaining why.\n{}\nDo NOT start with 'This git diff' or 'committed:'.", diff));
 
             let (responder, mut receiver) = mpsc::channel(8);
+            let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
             tx.send(Prompt {
                 prompt: prompt.clone(),
                 responder,
-                temperature: 0.8,
+                temperature: None,
+                seed: None,
+                top_p: None,
                 max_sampled: 256,
+                decoding: *decoding,
+                priority: Priority::Chat,
+                session: None,
+                prompt_tokens: Some(prompt_tokens),
             })
             .await
             .expect("failed to send prompt to LLM manager");
+            // e.g. a diff longer than the context of the model
+            if let Ok(Err(error)) = prompt_tokens_receiver.await {
+                spinner.fail(&format!("{}, try staging fewer files", error));
+                std::process::exit(1);
+            }
 
             let mut commit_message = String::new();
             while let Some(text) = receiver.recv().await {
@@ -216,8 +421,15 @@ async fn main() {
                 tx.send(Prompt {
                     prompt: prompt.clone(),
                     responder,
-                    temperature: 1.2,
+                    // the same seed would sample the same message again
+                    temperature: None,
+                    seed: Some(cli.seed.wrapping_add(1)),
+                    top_p: None,
                     max_sampled: 256,
+                    decoding: *decoding,
+                    priority: Priority::Chat,
+                    session: None,
+                    prompt_tokens: None,
                 })
                 .await
                 .expect("failed to send prompt to LLM manager");
@@ -281,16 +493,27 @@ async fn main() {
                             }
                         }
                     }
-                    let prompt = mistral::instruct(input);
+                    let prompt = template.instruct(input);
                     let (responder, mut receiver) = mpsc::channel(8);
+                    let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
                     tx.send(Prompt {
                         prompt,
                         responder,
-                        temperature: 1.0,
+                        temperature: None,
+                        seed: None,
+                        top_p: None,
                         max_sampled: 4096,
+                        decoding: Decoding::Standard,
+                        priority: Priority::Chat,
+                        session: None,
+                        prompt_tokens: Some(prompt_tokens),
                     })
                     .await
                     .expect("failed to send prompt to LLM manager");
+                    if let Ok(Err(error)) = prompt_tokens_receiver.await {
+                        error!("{}", error);
+                        std::process::exit(1);
+                    }
                     let mut last = String::new();
                     while let Some(text) = receiver.recv().await {
                         print!("{text}");
@@ -300,7 +523,7 @@ async fn main() {
                     // print a newline if the last text does not end with a newline
                     // prevent https://unix.stackexchange.com/questions/167582/why-zsh-ends-a-line-with-a-highlighted-percent-symbol
                     if last != "\n" {
-                        print!("\n");
+                        println!();
                         std::io::stdout().flush().expect("failed to flush stdout");
                     }
                 }
@@ -312,6 +535,10 @@ async fn main() {
                 }
             }
         }
+        // handled before loading the models
+        Some(CLICommands::Quantize { .. })
+        | Some(CLICommands::Models { .. })
+        | Some(CLICommands::Inspect { .. }) => unreachable!(),
         None => {
             error!("no operation specified, try `ox serve` or `ox --help` for more options");
         }
@@ -322,6 +549,27 @@ fn app(state: state::AppState) -> Router {
     Router::new()
         .route("/v1/engines/:engine/completions", post(completion))
         .route("/v1/completions", post(completion))
+        // Copilot auxiliary endpoints
+        .route("/copilot_internal/v2/token", get(copilot::token))
+        .route("/telemetry", post(copilot::telemetry))
+        .route("/models", get(copilot::models))
+        .route("/v1/models", get(copilot::models))
+        // Ollama-compatible endpoints
+        .route("/api/generate", post(ollama::generate))
+        .route("/api/chat", post(ollama::chat))
+        .route("/api/tags", get(ollama::tags))
+        .route("/api/show", post(ollama::show))
+        // Text Generation Inference (TGI) compatible endpoints
+        .route("/generate", post(tgi::generate))
+        .route("/generate_stream", post(tgi::generate_stream))
+        // llama.cpp server compatible endpoints
+        .route("/completion", post(llamacpp::completion))
+        .route("/infill", post(llamacpp::infill))
+        // bidirectional streaming, generations can be cancelled without closing the connection
+        .route("/ws", get(ws::ws))
+        // admin endpoints, enabled by `--admin-token`
+        .route("/admin/models", get(admin::models).post(admin::load))
+        .route("/admin/models/:name", delete(admin::unload))
         .with_state(state)
 }
 
@@ -333,17 +581,145 @@ mod tests {
     // imports are only for the tests
     use eventsource_stream::Eventsource; // needed for `.eventsource()`
     use futures::prelude::*; // needed for `.next().await`
-    use oxpilot::types::Completion;
+    use oxpilot::cmd::{Command, PromptError};
+    use oxpilot::models::safetensors::Precision;
+    use oxpilot::types::{Completion, OllamaTags, WsClientMessage, WsServerMessage};
     use serde_json::Value::Null;
     use std::time::{SystemTime, UNIX_EPOCH};
     use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
 
     /// `super::*` means "everything in the parent module"
     /// It will bring all of the test module’s parent’s items into scope.
     use super::*;
+    /// The longest prompt the test manager accepts, in words.
+    const MAX_PROMPT_WORDS: usize = 64;
+
     /// A helper function that spawns our application in the background
     /// and returns its address (e.g. http://127.0.0.1:[random_port])
     async fn spawn_app(host: impl Into<String>) -> String {
+        // a single model answers to any model name, like `ox serve` without `--models`
+        spawn_app_with_models(host, vec![served_model("model.Q4_K_M", &["*"])], None).await
+    }
+
+    /// A model whose manager task streams back the words of the prompt, one word per token.
+    fn served_model(name: &str, aliases: &[&str]) -> state::ServedModel {
+        state::ServedModel {
+            tx: echo_manager(),
+            info: state::ModelInfo::new("repo", format!("{}.gguf", name), 0),
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            template: PromptTemplate::Mistral,
+            fim_template: FimTemplate::Prefix,
+        }
+    }
+
+    /// A manager task that streams back the words of the prompt, one word per token.
+    fn echo_manager() -> mpsc::Sender<Command> {
+        let (tx, mut rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(Prompt {
+                prompt,
+                responder,
+                max_sampled,
+                prompt_tokens,
+                ..
+            }) = rx.recv().await
+            {
+                let words = prompt.split_whitespace().collect::<Vec<_>>();
+                // a context of `MAX_PROMPT_WORDS` words, like the scheduler rejects the prompts too long for the model
+                let result = if words.len() > MAX_PROMPT_WORDS {
+                    Err(PromptError::TooLong {
+                        len: words.len(),
+                        max: MAX_PROMPT_WORDS,
+                    })
+                } else {
+                    Ok(words.len())
+                };
+                let is_rejected = result.is_err();
+                if let Some(prompt_tokens) = prompt_tokens {
+                    let _ = prompt_tokens.send(result);
+                }
+                if is_rejected {
+                    continue;
+                }
+                for word in words.into_iter().take(max_sampled) {
+                    let _ = responder.send(word.to_string()).await;
+                }
+            }
+        });
+        tx
+    }
+
+    /// A manager task that answers every prompt with its session, `none` without one.
+    fn session_manager() -> mpsc::Sender<Command> {
+        let (tx, mut rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(Prompt {
+                responder,
+                session,
+                prompt_tokens,
+                ..
+            }) = rx.recv().await
+            {
+                if let Some(prompt_tokens) = prompt_tokens {
+                    let _ = prompt_tokens.send(Ok(0));
+                }
+                let _ = responder.send(session.unwrap_or("none".to_string())).await;
+            }
+        });
+        tx
+    }
+
+    /// A model whose manager task streams back the words of the prompt, then waits until the generation is dropped.
+    fn echo_model(name: &str) -> state::ServedModel {
+        let (tx, mut rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(Prompt {
+                prompt,
+                responder,
+                prompt_tokens,
+                ..
+            }) = rx.recv().await
+            {
+                if let Some(prompt_tokens) = prompt_tokens {
+                    let _ = prompt_tokens.send(Ok(prompt.split_whitespace().count()));
+                }
+                for word in prompt.split_whitespace() {
+                    let _ = responder.send(word.to_string()).await;
+                }
+                responder.closed().await;
+            }
+        });
+        state::ServedModel {
+            tx,
+            ..served_model(name, &["*"])
+        }
+    }
+
+    async fn spawn_app_with_models(
+        host: impl Into<String>,
+        models: Vec<state::ServedModel>,
+        admin_token: Option<&str>,
+    ) -> String {
+        let loader = ModelLoader {
+            tokenizer_repo_id: Some("hf-internal-testing/llama-tokenizer".to_string()),
+            tokenizer_path: None,
+            tokenizer_file: "tokenizer.json".to_string(),
+            offline: true,
+            precision: Precision::F32,
+            fim_template: FimTemplate::Prefix,
+            draft_model: None,
+            options: SchedulerOptions {
+                temperature: 1.0,
+                to_sample: 4096,
+                seed: 299792458,
+                top_p: None,
+                repeat_last_n: 64,
+                repeat_penalty: 1.1,
+                max_batch_size: 4,
+            },
+        };
+        let admin_token = admin_token.map(|token| token.to_string());
         let _host = host.into();
         // Bind to localhost at the port 0, which will let the OS assign an available port to us
         let listener = TcpListener::bind(format!("{}:0", _host)).await.unwrap();
@@ -351,10 +727,9 @@ mod tests {
         let port = listener.local_addr().unwrap().port();
 
         // The `move` keyword is used to **move** the ownership of `listener` into the task.
-        let _ = tokio::spawn(async move {
-            let (tx, _) = mpsc::channel(32);
-            let state = state::AppState { tx };
-            let app = app(state);
+        tokio::spawn(async move {
+            let state = state::AppState::new(models, Decoding::Standard, loader, admin_token);
+            let app = app(state).into_make_service_with_connect_info::<SocketAddr>();
             axum::serve(listener, app).await.unwrap();
         });
 
@@ -396,7 +771,7 @@ mod tests {
             .unwrap()
             .as_secs();
         let mut stream = reqwest::Client::new()
-            .post(&format!(
+            .post(format!(
                 "{}/v1/engines/{engine}/completions",
                 listening_url,
                 engine = model_name
@@ -429,34 +804,445 @@ mod tests {
             }
         }
         // The endpoint should return at least one completion object
-        assert!(completions.len() > 0);
+        assert!(!completions.is_empty());
 
         // Check that each completion object has the correct fields
         // note that we didn't check all the values of the fields because
         // `serde_json::from_str::<Completion>` should panic if the field is missing or in unexpected format
         for completion in completions {
             // id should be a non-empty string
-            assert!(completion.id.len() > 0);
+            assert!(!completion.id.is_empty());
             assert!(completion.object == "text_completion");
             assert!(completion.created >= time_before_request);
             assert!(completion.model == model_name);
 
             // each completion object should have at least one choice
-            assert!(completion.choices.len() > 0);
+            assert!(!completion.choices.is_empty());
 
             // check that each choice has a non-empty text
             for choice in completion.choices {
-                assert!(choice.text.len() > 0);
+                assert!(!choice.text.is_empty());
                 // finish_reason should can be None or Some(String)
-                match choice.finish_reason {
-                    Some(finish_reason) => {
-                        assert!(finish_reason.len() > 0);
-                    }
-                    None => {}
+                if let Some(finish_reason) = choice.finish_reason {
+                    assert!(!finish_reason.is_empty());
                 }
             }
 
-            assert!(completion.system_fingerprint == "");
+            assert!(completion.system_fingerprint.is_empty());
         }
     }
+
+    #[tokio::test]
+    async fn test_ollama_tags() {
+        let listening_url = spawn_app("127.0.0.1").await;
+        let tags = reqwest::Client::new()
+            .get(format!("{}/api/tags", listening_url))
+            .send()
+            .await
+            .unwrap()
+            .json::<OllamaTags>()
+            .await
+            .unwrap();
+
+        assert!(tags.models.len() == 1);
+        assert!(tags.models[0].name == "model.Q4_K_M");
+        assert!(tags.models[0].details.format == "gguf");
+        assert!(tags.models[0].details.quantization_level == "Q4_K_M");
+    }
+
+    #[tokio::test]
+    async fn test_ollama_tags_safetensors_format() {
+        let listening_url = spawn_app_with_models(
+            "127.0.0.1",
+            vec![state::ServedModel {
+                info: state::ModelInfo::new("repo", "model.safetensors", 0),
+                ..served_model("checkpoint", &["*"])
+            }],
+            None,
+        )
+        .await;
+        let tags = reqwest::Client::new()
+            .get(format!("{}/api/tags", listening_url))
+            .send()
+            .await
+            .unwrap()
+            .json::<OllamaTags>()
+            .await
+            .unwrap();
+
+        assert!(tags.models[0].details.format == "safetensors");
+    }
+
+    #[tokio::test]
+    async fn test_ollama_generate_options() {
+        let listening_url = spawn_app("127.0.0.1").await;
+        let generate = |body: serde_json::Value| {
+            reqwest::Client::new()
+                .post(format!("{}/api/generate", listening_url))
+                .json(&body)
+                .send()
+        };
+
+        // the test manager streams the words of the prompt, the generation ends at the stop sequence
+        let response = generate(serde_json::json!({
+            "prompt": "one two three four",
+            "raw": true,
+            "stream": false,
+            "options": { "stop": ["three"], "seed": 42, "top_p": 0.9 },
+        }))
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+        assert!(response["response"] == "onetwo");
+        assert!(response["done_reason"] == "stop");
+        assert!(response["prompt_eval_count"] == 4);
+
+        let response = generate(serde_json::json!({
+            "prompt": "one two three",
+            "raw": true,
+            "stream": false,
+            "options": { "num_predict": 2 },
+        }))
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+        assert!(response["response"] == "onetwo");
+        assert!(response["done_reason"] == "length");
+        assert!(response["eval_count"] == 2);
+
+        let response = generate(serde_json::json!({
+            "prompt": "Hi end",
+            "template": "{{ .System }} {{ .Prompt }} {{ .Response }}",
+            "system": "Be brief.",
+            "stream": false,
+            "options": { "stop": ["end"] },
+        }))
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+        assert!(response["response"] == "Bebrief.Hi");
+
+        // the `prefix` FIM template of the test app continues the prompt
+        let response = generate(serde_json::json!({
+            "prompt": "fn main",
+            "suffix": "}",
+            "stream": false,
+        }))
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+        assert!(response["response"] == "fnmain");
+
+        let response = generate(serde_json::json!({
+            "prompt": "Hi",
+            "template": "{{ range .Messages }}{{ end }}",
+        }))
+        .await
+        .unwrap();
+        assert!(response.status() == reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_too_long_for_the_model() {
+        let listening_url = spawn_app("127.0.0.1").await;
+        let prompt = "word ".repeat(MAX_PROMPT_WORDS + 1);
+        let requests = [
+            ("/v1/completions", serde_json::json!({ "prompt": prompt })),
+            (
+                "/api/generate",
+                serde_json::json!({ "prompt": prompt, "raw": true }),
+            ),
+            ("/generate", serde_json::json!({ "inputs": prompt })),
+            ("/completion", serde_json::json!({ "prompt": prompt })),
+        ];
+        for (path, body) in requests {
+            let response = reqwest::Client::new()
+                .post(format!("{}{}", listening_url, path))
+                .json(&body)
+                .send()
+                .await
+                .unwrap();
+            assert!(
+                response.status() == reqwest::StatusCode::PAYLOAD_TOO_LARGE,
+                "{} answered {}",
+                path,
+                response.status()
+            );
+        }
+    }
+
+    /// Requests a completion and returns its whole text.
+    async fn complete(request: reqwest::RequestBuilder) -> String {
+        let mut stream = request.send().await.unwrap().bytes_stream().eventsource();
+        let mut text = String::new();
+        while let Some(Ok(event)) = stream.next().await {
+            if event.data == "[DONE]" {
+                break;
+            }
+            let completion = serde_json::from_str::<Completion>(&event.data).unwrap();
+            text.push_str(&completion.choices[0].text);
+        }
+        text
+    }
+
+    #[tokio::test]
+    async fn test_completion_sessions() {
+        let listening_url = spawn_app_with_models(
+            "127.0.0.1",
+            vec![state::ServedModel {
+                tx: session_manager(),
+                ..served_model("session", &["*"])
+            }],
+            None,
+        )
+        .await;
+        let session = |user: Option<&str>, headers: Vec<(&'static str, &'static str)>| {
+            let mut request = reqwest::Client::new()
+                .post(format!("{}/v1/completions", listening_url))
+                .json(&serde_json::json!({ "prompt": "fn main() {", "user": user }));
+            for (name, value) in headers {
+                request = request.header(name, value);
+            }
+            complete(request)
+        };
+
+        assert_eq!(session(Some("alice"), vec![]).await, "user:alice");
+        assert_eq!(
+            session(None, vec![("X-Client-Id", "editor-1")]).await,
+            "client:editor-1"
+        );
+        // the `user` field names the client more precisely than the headers
+        assert_eq!(
+            session(Some("alice"), vec![("X-Client-Id", "editor-1")]).await,
+            "user:alice"
+        );
+        assert!(session(None, vec![("Authorization", "Bearer key")])
+            .await
+            .starts_with("key:"));
+        // without any id, the connection identifies the client
+        assert!(session(None, vec![])
+            .await
+            .starts_with("connection:127.0.0.1:"));
+    }
+
+    #[tokio::test]
+    async fn test_copilot_completion_session() {
+        let listening_url = spawn_app_with_models(
+            "127.0.0.1",
+            vec![state::ServedModel {
+                tx: session_manager(),
+                ..served_model("session", &["*"])
+            }],
+            None,
+        )
+        .await;
+        // the headers of a completion request of the Copilot extension, it sends neither a `user` nor an
+        // `X-Client-Id`
+        let request = reqwest::Client::new()
+            .post(format!(
+                "{}/v1/engines/copilot-codex/completions",
+                listening_url
+            ))
+            .header("Authorization", "Bearer ghu_token")
+            .header("Editor-Version", "vscode/1.93.1")
+            .header("Editor-Plugin-Version", "copilot/1.229.0")
+            .header("Copilot-Integration-Id", "vscode-chat")
+            .header("OpenAI-Organization", "github-copilot")
+            .header("OpenAI-Intent", "copilot-ghost")
+            .header("VScode-SessionId", "a5f2c3e1-session")
+            .header("VScode-MachineId", "b7d9e0f4-machine")
+            .header("X-Request-Id", "c1e2d3f4-request")
+            .json(&serde_json::json!({
+                "prompt": "fn main() {",
+                "suffix": "}",
+                "max_tokens": 16,
+                "temperature": 0.0,
+                "top_p": 1,
+                "n": 1,
+                "stop": ["\n"],
+                "stream": true,
+                "extra": { "language": "rust" },
+            }));
+        assert_eq!(complete(request).await, "vscode:a5f2c3e1-session");
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_routed_by_model_name() {
+        let listening_url = spawn_app_with_models(
+            "127.0.0.1",
+            vec![
+                served_model("chat", &[]),
+                served_model("code", &["copilot-codex"]),
+            ],
+            None,
+        )
+        .await;
+        let show = |model: &'static str| {
+            reqwest::Client::new()
+                .post(format!("{}/api/show", listening_url))
+                .json(&serde_json::json!({ "model": model }))
+                .send()
+        };
+
+        assert!(show("copilot-codex").await.unwrap().status() == reqwest::StatusCode::OK);
+        let response = show("gpt-4").await.unwrap();
+        assert!(response.status() == reqwest::StatusCode::NOT_FOUND);
+        let error = response.json::<serde_json::Value>().await.unwrap();
+        assert!(error["error"] == "model 'gpt-4' not found");
+    }
+
+    #[tokio::test]
+    async fn test_admin_endpoints_require_token() {
+        let disabled_url = spawn_app("127.0.0.1").await;
+        let response = reqwest::Client::new()
+            .get(format!("{}/admin/models", disabled_url))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status() == reqwest::StatusCode::FORBIDDEN);
+
+        let listening_url = spawn_app_with_models(
+            "127.0.0.1",
+            vec![served_model("chat", &[]), served_model("code", &[])],
+            Some("secret"),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let response = client
+            .get(format!("{}/admin/models", listening_url))
+            .bearer_auth("guess")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status() == reqwest::StatusCode::UNAUTHORIZED);
+
+        let models = client
+            .get(format!("{}/admin/models", listening_url))
+            .bearer_auth("secret")
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        assert!(models["models"].as_array().unwrap().len() == 2);
+        assert!(models["models"][0]["name"] == "chat");
+        assert!(models["models"][0]["default"] == true);
+        assert!(models["models"][1]["status"] == "ready");
+    }
+
+    #[tokio::test]
+    async fn test_admin_unload_keeps_last_model() {
+        let listening_url = spawn_app_with_models(
+            "127.0.0.1",
+            vec![served_model("chat", &[]), served_model("code", &[])],
+            Some("secret"),
+        )
+        .await;
+        let unload = |name: &'static str| {
+            reqwest::Client::new()
+                .delete(format!("{}/admin/models/{}", listening_url, name))
+                .bearer_auth("secret")
+                .send()
+        };
+
+        assert!(unload("gpt-4").await.unwrap().status() == reqwest::StatusCode::NOT_FOUND);
+        assert!(unload("chat").await.unwrap().status() == reqwest::StatusCode::OK);
+        assert!(unload("code").await.unwrap().status() == reqwest::StatusCode::CONFLICT);
+        let tags = reqwest::Client::new()
+            .get(format!("{}/api/tags", listening_url))
+            .send()
+            .await
+            .unwrap()
+            .json::<OllamaTags>()
+            .await
+            .unwrap();
+        assert!(tags.models.len() == 1);
+        assert!(tags.models[0].name == "code");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_cancel_then_prompt_again() {
+        let listening_url =
+            spawn_app_with_models("127.0.0.1", vec![echo_model("echo")], None).await;
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("{}/ws", listening_url.replace("http", "ws")))
+                .await
+                .unwrap();
+        let prompt = |prompt: &str| {
+            WsMessage::Text(
+                serde_json::to_string(&WsClientMessage::Prompt {
+                    prompt: prompt.to_string(),
+                    model: None,
+                    temperature: None,
+                    max_tokens: None,
+                    priority: None,
+                })
+                .unwrap(),
+            )
+        };
+        let token = |text: &str| WsServerMessage::Token {
+            text: text.to_string(),
+        };
+        let cancelled = WsServerMessage::Done {
+            reason: "cancelled".to_string(),
+        };
+
+        socket.send(prompt("hello world")).await.unwrap();
+        assert_eq!(receive(&mut socket).await, token("hello"));
+        assert_eq!(receive(&mut socket).await, token("world"));
+        socket
+            .send(WsMessage::Text(r#"{"type": "cancel"}"#.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(receive(&mut socket).await, cancelled);
+
+        // the socket stays open for the next prompt
+        socket.send(prompt("again")).await.unwrap();
+        assert_eq!(receive(&mut socket).await, token("again"));
+        socket
+            .send(WsMessage::Text(r#"{"type": "stop"}"#.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(receive(&mut socket).await, cancelled);
+    }
+
+    /// The next message of the server on a WebSocket.
+    async fn receive(
+        socket: &mut tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+    ) -> WsServerMessage {
+        match socket.next().await.unwrap().unwrap() {
+            WsMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copilot_token_points_to_server() {
+        let listening_url = spawn_app("127.0.0.1").await;
+        let token = reqwest::Client::new()
+            .get(format!("{}/copilot_internal/v2/token", listening_url))
+            .header("Authorization", "token gho_anything")
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+
+        assert!(!token["token"].as_str().unwrap().is_empty());
+        assert!(token["expires_at"].as_u64().unwrap() > token["refresh_in"].as_u64().unwrap());
+        assert!(token["endpoints"]["api"] == listening_url.as_str());
+        assert!(token["endpoints"]["proxy"] == listening_url.as_str());
+    }
 }
@@ -0,0 +1,711 @@
+//! Builds a `tokenizers::Tokenizer` from the metadata of a GGUF file, so that a single GGUF file is enough to run a
+//! model, without a separate repo for `tokenizer.json`.
+//!
+//! GGUF files embed the vocabulary under the `tokenizer.ggml.*` keys, see
+//! https://github.com/ggerganov/ggml/blob/master/docs/gguf.md#tokenizer
+//!
+//! - `tokenizer.ggml.model = "llama"`: a SentencePiece BPE vocabulary (Llama, Mistral, CodeLlama...) with a score per
+//!   token. The merges are not stored, they are recovered from the scores the way `transformers` converts
+//!   SentencePiece models (`SentencePieceExtractor`): a token made of two other tokens is a merge, ranked by its score.
+//! - `tokenizer.ggml.model = "gpt2"`: a byte-level BPE vocabulary (StarCoder, DeepSeek Coder, Qwen...) with its merges.
+//!   `tokenizer.ggml.pre` names how the text is split before the BPE, the regexes of each name are copied from
+//!   `llama.cpp`, the names we don't know are rejected rather than splitting the text the wrong way.
+//!
+//! `to_metadata` goes the other way, from a `tokenizer.json` to the `tokenizer.ggml.*` keys, to embed the tokenizer of
+//! a safetensors checkpoint in the GGUF file written by `ox quantize`.
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use candle_core::quantized::gguf_file::{Content, Value};
+use serde::Deserialize;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::models::bpe::BPE;
+use tokenizers::normalizers::{NormalizerWrapper, Prepend, Replace, Sequence};
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::pre_tokenizers::sequence::Sequence as PreTokenizerSequence;
+use tokenizers::pre_tokenizers::split::{Split, SplitPattern};
+use tokenizers::pre_tokenizers::PreTokenizerWrapper;
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::processors::PostProcessorWrapper;
+use tokenizers::{AddedToken, SplitDelimiterBehavior, Tokenizer};
+
+/// The values of `tokenizer.ggml.token_type`, from `llama.cpp`.
+const TOKEN_TYPE_NORMAL: i32 = 1;
+const TOKEN_TYPE_UNKNOWN: i32 = 2;
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+const TOKEN_TYPE_UNUSED: i32 = 5;
+const TOKEN_TYPE_BYTE: i32 = 6;
+
+/// SentencePiece replaces spaces with this "lower one eighth block" character.
+const SPIECE_UNDERLINE: &str = "▁";
+
+/// The word splitting regex of Llama 3, numbers are split in groups of up to 3 digits.
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+/// The word splitting regex of Qwen2, the same as Llama 3 but every digit is a word.
+const QWEN2_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+/// The successive splits of DeepSeek Coder: line breaks, words, punctuation, CJK characters, then every digit.
+const DEEPSEEK_CODER_PATTERNS: [&str; 5] = [
+    r"[\r\n]",
+    r"\s?\p{L}+",
+    r"\s?\p{P}+",
+    r"[\x{4E00}-\x{9FA5}\x{0800}-\x{4E00}\x{AC00}-\x{D7FF}]+",
+    r"\p{N}",
+];
+
+/// Builds the tokenizer embedded in a GGUF file.
+pub fn from_gguf(content: &Content) -> Result<Tokenizer> {
+    from_metadata(&content.metadata)
+}
+
+/// Builds the tokenizer from the `tokenizer.ggml.*` keys of GGUF metadata.
+pub fn from_metadata(metadata: &HashMap<String, Value>) -> Result<Tokenizer> {
+    let model = get(metadata, "tokenizer.ggml.model")?.to_string()?.clone();
+    let tokens = strings(metadata, "tokenizer.ggml.tokens")?;
+    // the token types are optional, without them every token is a normal token
+    let token_types = match metadata.get("tokenizer.ggml.token_type") {
+        Some(token_types) => token_types
+            .to_vec()?
+            .iter()
+            .map(|token_type| token_type.to_i32())
+            .collect::<candle_core::Result<Vec<_>>>()?,
+        None => vec![TOKEN_TYPE_NORMAL; tokens.len()],
+    };
+
+    let (mut tokenizer, add_bos_by_default) = match model.as_str() {
+        "llama" => {
+            let scores = get(metadata, "tokenizer.ggml.scores")?
+                .to_vec()?
+                .iter()
+                .map(|score| score.to_f32())
+                .collect::<candle_core::Result<Vec<_>>>()?;
+            let unknown_token_id = token_id(metadata, "tokenizer.ggml.unknown_token_id")?.unwrap_or(0);
+            (
+                sentencepiece_bpe(&tokens, &scores, &token_types, unknown_token_id)?,
+                true,
+            )
+        }
+        "gpt2" => {
+            let merges = strings(metadata, "tokenizer.ggml.merges")?;
+            // the files converted before the key existed use the GPT-2 regex
+            let pre = match metadata.get("tokenizer.ggml.pre") {
+                Some(pre) => pre.to_string()?.clone(),
+                None => "default".to_string(),
+            };
+            (byte_level_bpe(&tokens, &merges, &pre)?, false)
+        }
+        _ => {
+            return Err(anyhow!(
+                "unsupported tokenizer model {:?} in tokenizer.ggml.model, only \"llama\" and \"gpt2\" are supported",
+                model
+            ))
+        }
+    };
+
+    // the control tokens, e.g. `<s>` or `<|im_end|>`, are matched before the model splits the text, and skipped when
+    // decoding, the user defined tokens are only matched before the model splits the text
+    let special_tokens: Vec<AddedToken> = tokens
+        .iter()
+        .zip(&token_types)
+        .filter(|(_, token_type)| **token_type == TOKEN_TYPE_CONTROL)
+        .map(|(token, _)| AddedToken::from(token.clone(), true))
+        .collect();
+    tokenizer.add_special_tokens(&special_tokens);
+    let user_defined_tokens: Vec<AddedToken> = tokens
+        .iter()
+        .zip(&token_types)
+        .filter(|(_, token_type)| **token_type == TOKEN_TYPE_USER_DEFINED)
+        .map(|(token, _)| AddedToken::from(token.clone(), false))
+        .collect();
+    tokenizer.add_tokens(&user_defined_tokens);
+
+    // `encode(prompt, true)` prepends the BOS token, like `tokenizer.json` of Llama does
+    let add_bos = match metadata.get("tokenizer.ggml.add_bos_token") {
+        Some(add_bos) => add_bos.to_bool()?,
+        None => add_bos_by_default,
+    };
+    if let (true, Some(bos_token_id)) =
+        (add_bos, token_id(metadata, "tokenizer.ggml.bos_token_id")?)
+    {
+        let bos = tokens
+            .get(bos_token_id as usize)
+            .with_context(|| format!("bos_token_id {} is not in the vocabulary", bos_token_id))?;
+        let template = TemplateProcessing::builder()
+            .try_single(format!("{}:0 $A:0", bos))
+            .map_err(anyhow::Error::msg)?
+            .try_pair(format!("{}:0 $A:0 {}:1 $B:1", bos, bos))
+            .map_err(anyhow::Error::msg)?
+            .special_tokens(vec![(bos.clone(), bos_token_id)])
+            .build()
+            .map_err(|error| anyhow!("failed to build the BOS template: {}", error))?;
+        tokenizer.with_post_processor(PostProcessorWrapper::Template(template));
+    }
+    Ok(tokenizer)
+}
+
+/// The SentencePiece BPE tokenizer of Llama, the same pipeline as the `tokenizer.json` of Llama or Mistral: spaces are
+/// replaced by "▁", unknown characters fall back to the `<0xNN>` byte tokens.
+fn sentencepiece_bpe(
+    tokens: &[String],
+    scores: &[f32],
+    token_types: &[i32],
+    unknown_token_id: u32,
+) -> Result<Tokenizer> {
+    if scores.len() != tokens.len() {
+        return Err(anyhow!(
+            "tokenizer.ggml.scores has {} scores for {} tokens",
+            scores.len(),
+            tokens.len()
+        ));
+    }
+    let vocab: HashMap<String, u32> = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id as u32))
+        .collect();
+    let unknown_token = tokens.get(unknown_token_id as usize).with_context(|| {
+        format!(
+            "unknown_token_id {} is not in the vocabulary",
+            unknown_token_id
+        )
+    })?;
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges_from_scores(tokens, scores, token_types))
+        .unk_token(unknown_token.clone())
+        .fuse_unk(true)
+        .byte_fallback(true)
+        .build()
+        .map_err(|error| anyhow!("failed to build the BPE model: {}", error))?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_normalizer(NormalizerWrapper::Sequence(Sequence::new(vec![
+        Prepend::new(SPIECE_UNDERLINE.to_string()).into(),
+        Replace::new(" ", SPIECE_UNDERLINE)
+            .map_err(anyhow::Error::msg)?
+            .into(),
+    ])));
+    tokenizer.with_decoder(DecoderWrapper::Sequence(DecoderSequence::new(vec![
+        Replace::new(SPIECE_UNDERLINE, " ")
+            .map_err(anyhow::Error::msg)?
+            .into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+        // the space of the "▁" prepended by the normalizer
+        Strip::new(' ', 1, 0).into(),
+    ])));
+    Ok(tokenizer)
+}
+
+/// Every normal token that is the concatenation of two other normal tokens is a merge, the merges of the tokens with
+/// the highest score are applied first.
+fn merges_from_scores(
+    tokens: &[String],
+    scores: &[f32],
+    token_types: &[i32],
+) -> Vec<(String, String)> {
+    let is_normal = |id: usize| token_types.get(id) == Some(&TOKEN_TYPE_NORMAL);
+    let normal_ids: HashMap<&str, usize> = tokens
+        .iter()
+        .enumerate()
+        .filter(|(id, _)| is_normal(*id))
+        .map(|(id, token)| (token.as_str(), id))
+        .collect();
+    let mut merges = vec![];
+    for (&token, &id) in &normal_ids {
+        // split between every pair of characters, not bytes, "▁" is 3 bytes long
+        for (split, _) in token.char_indices().skip(1) {
+            let (left, right) = token.split_at(split);
+            if let (Some(&left_id), Some(&right_id)) = (normal_ids.get(left), normal_ids.get(right))
+            {
+                merges.push((id, left_id, right_id));
+            }
+        }
+    }
+    // ties are broken by the ids of the tokens, like `SentencePieceExtractor`
+    merges.sort_by(|a, b| scores[b.0].total_cmp(&scores[a.0]).then(a.cmp(b)));
+    merges
+        .into_iter()
+        .map(|(_, left_id, right_id)| (tokens[left_id].clone(), tokens[right_id].clone()))
+        .collect()
+}
+
+/// The byte-level BPE tokenizer of GPT-2, where each byte is mapped to a printable character, e.g. a space to "Ġ".
+/// The text is split into words by the pre-tokenizer named `pre`, see `byte_level_pre_tokenizer`.
+fn byte_level_bpe(tokens: &[String], merges: &[String], pre: &str) -> Result<Tokenizer> {
+    let vocab: HashMap<String, u32> = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), id as u32))
+        .collect();
+    let merges = merges
+        .iter()
+        .map(|merge| {
+            merge
+                .split_once(' ')
+                .map(|(left, right)| (left.to_string(), right.to_string()))
+                .with_context(|| format!("invalid merge {:?} in tokenizer.ggml.merges", merge))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .build()
+        .map_err(|error| anyhow!("failed to build the BPE model: {}", error))?;
+
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer.with_pre_tokenizer(byte_level_pre_tokenizer(pre)?);
+    tokenizer.with_decoder(DecoderWrapper::ByteLevel(ByteLevel::default()));
+    Ok(tokenizer)
+}
+
+/// The pre-tokenizer of the `tokenizer.json` a byte-level vocabulary was converted from, by its `tokenizer.ggml.pre`
+/// name: the text is split by the regexes of the model, then mapped to bytes, with no prefix space and
+/// `trim_offsets`, like the `tokenizer.json` of these models.
+fn byte_level_pre_tokenizer(pre: &str) -> Result<PreTokenizerWrapper> {
+    // the patterns splitting the text, and whether the GPT-2 regex splits it afterwards
+    let (patterns, use_gpt2_regex): (&[&str], bool) = match pre {
+        "default" | "gpt-2" | "phi-2" | "mpt" | "olmo" => (&[], true),
+        "starcoder" | "refact" | "smollm" | "codeshell" => (&[r"\p{N}"], true),
+        "llama3" | "llama-v3" | "llama-bpe" | "smaug-bpe" | "dbrx" => (&[LLAMA3_PATTERN], false),
+        "qwen2" => (&[QWEN2_PATTERN], false),
+        "deepseek-coder" => (&DEEPSEEK_CODER_PATTERNS, false),
+        _ => {
+            return Err(anyhow!(
+                "unsupported pre-tokenizer {:?} in tokenizer.ggml.pre, pass --tokenizer-repo-id to use the tokenizer.json of the model instead",
+                pre
+            ))
+        }
+    };
+    let byte_level = PreTokenizerWrapper::ByteLevel(ByteLevel::new(false, true, use_gpt2_regex));
+    if patterns.is_empty() {
+        return Ok(byte_level);
+    }
+    let mut pre_tokenizers = patterns
+        .iter()
+        .map(|pattern| {
+            Split::new(
+                SplitPattern::Regex(pattern.to_string()),
+                SplitDelimiterBehavior::Isolated,
+                false,
+            )
+            .map(PreTokenizerWrapper::Split)
+            .map_err(|error| anyhow!("invalid pre-tokenizer regex {:?}: {}", pattern, error))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    pre_tokenizers.push(byte_level);
+    Ok(PreTokenizerWrapper::Sequence(PreTokenizerSequence::new(
+        pre_tokenizers,
+    )))
+}
+
+/// The parts of `tokenizer.json` stored in GGUF metadata.
+#[derive(Deserialize)]
+struct TokenizerJson {
+    model: TokenizerJsonModel,
+    #[serde(default)]
+    added_tokens: Vec<TokenizerJsonAddedToken>,
+}
+
+#[derive(Deserialize)]
+struct TokenizerJsonModel {
+    #[serde(rename = "type")]
+    kind: String,
+    vocab: HashMap<String, u32>,
+    #[serde(default)]
+    merges: Vec<TokenizerJsonMerge>,
+    #[serde(default)]
+    byte_fallback: bool,
+    unk_token: Option<String>,
+}
+
+/// `"h i"` in older `tokenizer.json` files, `["h", "i"]` since `tokenizers` 0.20.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TokenizerJsonMerge {
+    Joined(String),
+    Pair(String, String),
+}
+
+#[derive(Deserialize)]
+struct TokenizerJsonAddedToken {
+    id: u32,
+    content: String,
+    special: bool,
+}
+
+/// The `tokenizer.ggml.*` keys of a BPE `tokenizer.json`, and of the `tokenizer_config.json` next to it when there is
+/// one (BOS, EOS, `add_bos_token` and the chat template).
+///
+/// A vocabulary with byte fallback is written as a SentencePiece vocabulary ("llama"), the scores rank the tokens in
+/// the order of the merges producing them, so that `merges_from_scores` recovers the merges. Any other BPE vocabulary
+/// is written as a byte-level vocabulary ("gpt2") with its merges.
+pub fn to_metadata(
+    tokenizer_json: &Path,
+    tokenizer_config: Option<&Path>,
+) -> Result<Vec<(String, Value)>> {
+    let content = std::fs::read_to_string(tokenizer_json)
+        .with_context(|| format!("Failed to read {}", tokenizer_json.display()))?;
+    let TokenizerJson {
+        model,
+        added_tokens,
+    } = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", tokenizer_json.display()))?;
+    if model.kind != "BPE" {
+        return Err(anyhow!(
+            "unsupported tokenizer model {:?} in {}, only BPE tokenizers can be embedded",
+            model.kind,
+            tokenizer_json.display()
+        ));
+    }
+
+    // the ids of the vocabulary and of the added tokens may leave gaps, filled with unused tokens like llama.cpp does
+    let size = model
+        .vocab
+        .values()
+        .chain(added_tokens.iter().map(|token| &token.id))
+        .max()
+        .map(|id| *id as usize + 1)
+        .unwrap_or(0);
+    let mut tokens: Vec<String> = (0..size).map(|id| format!("[PAD{}]", id)).collect();
+    let mut token_types = vec![TOKEN_TYPE_UNUSED; size];
+    for (token, &id) in &model.vocab {
+        let is_byte = model.byte_fallback && token.len() == 6 && token.starts_with("<0x");
+        token_types[id as usize] = if Some(token) == model.unk_token.as_ref() {
+            TOKEN_TYPE_UNKNOWN
+        } else if is_byte {
+            TOKEN_TYPE_BYTE
+        } else {
+            TOKEN_TYPE_NORMAL
+        };
+        tokens[id as usize] = token.clone();
+    }
+    for token in &added_tokens {
+        tokens[token.id as usize] = token.content.clone();
+        token_types[token.id as usize] = if Some(&token.content) == model.unk_token.as_ref() {
+            TOKEN_TYPE_UNKNOWN
+        } else if token.special {
+            TOKEN_TYPE_CONTROL
+        } else {
+            TOKEN_TYPE_USER_DEFINED
+        };
+    }
+    let merges: Vec<(String, String)> = model
+        .merges
+        .into_iter()
+        .map(|merge| match merge {
+            TokenizerJsonMerge::Pair(left, right) => Ok((left, right)),
+            TokenizerJsonMerge::Joined(merge) => merge
+                .split_once(' ')
+                .map(|(left, right)| (left.to_string(), right.to_string()))
+                .with_context(|| format!("invalid merge {:?}", merge)),
+        })
+        .collect::<Result<_>>()?;
+
+    let strings =
+        |values: &[String]| Value::Array(values.iter().cloned().map(Value::String).collect());
+    let mut metadata = vec![
+        ("tokenizer.ggml.tokens".to_string(), strings(&tokens)),
+        (
+            "tokenizer.ggml.token_type".to_string(),
+            Value::Array(
+                token_types
+                    .iter()
+                    .map(|token_type| Value::I32(*token_type))
+                    .collect(),
+            ),
+        ),
+    ];
+    if model.byte_fallback {
+        // the first merges get the highest scores, the other tokens rank after every merge
+        let mut scores: Vec<f32> = (0..size).map(|id| -((merges.len() + id) as f32)).collect();
+        for (rank, (left, right)) in merges.iter().enumerate().rev() {
+            if let Some(&id) = model.vocab.get(&format!("{}{}", left, right)) {
+                scores[id as usize] = -(rank as f32);
+            }
+        }
+        metadata.push((
+            "tokenizer.ggml.model".to_string(),
+            Value::String("llama".to_string()),
+        ));
+        metadata.push((
+            "tokenizer.ggml.scores".to_string(),
+            Value::Array(scores.into_iter().map(Value::F32).collect()),
+        ));
+        if let Some(&unknown_token_id) = model
+            .unk_token
+            .as_ref()
+            .and_then(|unk| model.vocab.get(unk))
+        {
+            metadata.push((
+                "tokenizer.ggml.unknown_token_id".to_string(),
+                Value::U32(unknown_token_id),
+            ));
+        }
+    } else {
+        let merges: Vec<String> = merges
+            .iter()
+            .map(|(left, right)| format!("{} {}", left, right))
+            .collect();
+        metadata.push((
+            "tokenizer.ggml.model".to_string(),
+            Value::String("gpt2".to_string()),
+        ));
+        metadata.push(("tokenizer.ggml.merges".to_string(), strings(&merges)));
+    }
+
+    if let Some(path) = tokenizer_config {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let config: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        // the special tokens are a string or an added token object
+        let token_id = |key: &str| {
+            let token = config.get(key)?;
+            let content = token.as_str().or_else(|| token.get("content")?.as_str())?;
+            tokens.iter().position(|token| token == content)
+        };
+        for (key, gguf_key) in [
+            ("bos_token", "tokenizer.ggml.bos_token_id"),
+            ("eos_token", "tokenizer.ggml.eos_token_id"),
+        ] {
+            if let Some(id) = token_id(key) {
+                metadata.push((gguf_key.to_string(), Value::U32(id as u32)));
+            }
+        }
+        if let Some(add_bos_token) = config.get("add_bos_token").and_then(|add| add.as_bool()) {
+            metadata.push((
+                "tokenizer.ggml.add_bos_token".to_string(),
+                Value::Bool(add_bos_token),
+            ));
+        }
+        if let Some(chat_template) = config
+            .get("chat_template")
+            .and_then(|template| template.as_str())
+        {
+            metadata.push((
+                "tokenizer.chat_template".to_string(),
+                Value::String(chat_template.to_string()),
+            ));
+        }
+    }
+    Ok(metadata)
+}
+
+fn get<'a>(metadata: &'a HashMap<String, Value>, key: &str) -> Result<&'a Value> {
+    metadata
+        .get(key)
+        .with_context(|| format!("{} is missing from the GGUF metadata", key))
+}
+
+fn strings(metadata: &HashMap<String, Value>, key: &str) -> Result<Vec<String>> {
+    let values = get(metadata, key)?
+        .to_vec()?
+        .iter()
+        .map(|value| value.to_string().cloned())
+        .collect::<candle_core::Result<Vec<_>>>()?;
+    Ok(values)
+}
+
+/// The id of a special token, `None` when the model doesn't have one.
+fn token_id(metadata: &HashMap<String, Value>, key: &str) -> Result<Option<u32>> {
+    metadata
+        .get(key)
+        .map(|id| id.to_u32())
+        .transpose()
+        .map_err(anyhow::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array<T>(values: &[T], to_value: impl Fn(&T) -> Value) -> Value {
+        Value::Array(values.iter().map(to_value).collect())
+    }
+
+    fn llama_metadata() -> HashMap<String, Value> {
+        let tokens = ["<unk>", "<s>", "</s>", "▁", "h", "i", "hi", "▁hi", "<0x21>"];
+        let scores = [0.0, 0.0, 0.0, -1.0, -2.0, -3.0, -4.0, -5.0, 0.0];
+        let token_types = [2, 3, 3, 1, 1, 1, 1, 1, 6];
+        HashMap::from([
+            (
+                "tokenizer.ggml.model".to_string(),
+                Value::String("llama".to_string()),
+            ),
+            (
+                "tokenizer.ggml.tokens".to_string(),
+                array(&tokens, |token| Value::String(token.to_string())),
+            ),
+            (
+                "tokenizer.ggml.scores".to_string(),
+                array(&scores, |score| Value::F32(*score)),
+            ),
+            (
+                "tokenizer.ggml.token_type".to_string(),
+                array(&token_types, |token_type| Value::I32(*token_type)),
+            ),
+            ("tokenizer.ggml.bos_token_id".to_string(), Value::U32(1)),
+            ("tokenizer.ggml.eos_token_id".to_string(), Value::U32(2)),
+            ("tokenizer.ggml.unknown_token_id".to_string(), Value::U32(0)),
+        ])
+    }
+
+    #[test]
+    fn builds_sentencepiece_tokenizer() {
+        let tokenizer = from_metadata(&llama_metadata()).unwrap();
+        let encoding = tokenizer.encode("hi hi!", true).unwrap();
+        // BOS, "▁hi" twice, and "!" falls back to its byte token
+        assert_eq!(encoding.get_ids(), &[1, 7, 7, 8]);
+        assert_eq!(tokenizer.decode(&[1, 7, 7, 8], true).unwrap(), "hi hi!");
+        // the control tokens are not split
+        assert_eq!(
+            tokenizer.encode("hi</s>", false).unwrap().get_ids(),
+            &[7, 2]
+        );
+    }
+
+    #[test]
+    fn builds_byte_level_tokenizer() {
+        let tokens = ["h", "i", "hi", "Ġ", "Ġhi", "<|endoftext|>"];
+        let merges = ["h i", "Ġ hi"];
+        let metadata = HashMap::from([
+            (
+                "tokenizer.ggml.model".to_string(),
+                Value::String("gpt2".to_string()),
+            ),
+            (
+                "tokenizer.ggml.tokens".to_string(),
+                array(&tokens, |token| Value::String(token.to_string())),
+            ),
+            (
+                "tokenizer.ggml.merges".to_string(),
+                array(&merges, |merge| Value::String(merge.to_string())),
+            ),
+            (
+                "tokenizer.ggml.token_type".to_string(),
+                array(&[1, 1, 1, 1, 1, 3], |token_type| Value::I32(*token_type)),
+            ),
+            ("tokenizer.ggml.bos_token_id".to_string(), Value::U32(5)),
+        ]);
+        let tokenizer = from_metadata(&metadata).unwrap();
+        // no BOS by default for GPT-2 tokenizers
+        let encoding = tokenizer.encode("hi hi<|endoftext|>", true).unwrap();
+        assert_eq!(encoding.get_ids(), &[2, 4, 5]);
+        assert_eq!(tokenizer.decode(&[2, 4], true).unwrap(), "hi hi");
+    }
+
+    #[test]
+    fn splits_words_by_pre_tokenizer() {
+        let tokens = ["1", "2", "3", "4", "12", "123", "1234"];
+        let merges = ["1 2", "12 3", "123 4"];
+        let metadata = |pre: Option<&str>| {
+            let mut metadata = HashMap::from([
+                (
+                    "tokenizer.ggml.model".to_string(),
+                    Value::String("gpt2".to_string()),
+                ),
+                (
+                    "tokenizer.ggml.tokens".to_string(),
+                    array(&tokens, |token| Value::String(token.to_string())),
+                ),
+                (
+                    "tokenizer.ggml.merges".to_string(),
+                    array(&merges, |merge| Value::String(merge.to_string())),
+                ),
+            ]);
+            if let Some(pre) = pre {
+                metadata.insert(
+                    "tokenizer.ggml.pre".to_string(),
+                    Value::String(pre.to_string()),
+                );
+            }
+            metadata
+        };
+        let ids = |pre: Option<&str>| {
+            let tokenizer = from_metadata(&metadata(pre)).unwrap();
+            tokenizer.encode("1234", false).unwrap().get_ids().to_vec()
+        };
+        // the GPT-2 regex keeps numbers whole, Llama 3 splits them in groups of 3 digits, Qwen2 and StarCoder in digits
+        assert_eq!(ids(None), &[6]);
+        assert_eq!(ids(Some("default")), &[6]);
+        assert_eq!(ids(Some("llama-bpe")), &[5, 3]);
+        assert_eq!(ids(Some("qwen2")), &[0, 1, 2, 3]);
+        assert_eq!(ids(Some("starcoder")), &[0, 1, 2, 3]);
+
+        let error = from_metadata(&metadata(Some("jais")))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("--tokenizer-repo-id"));
+    }
+
+    #[test]
+    fn roundtrips_tokenizer_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let directory = dir.path();
+        let tokenizer_json = directory.join("tokenizer.json");
+        std::fs::write(
+            &tokenizer_json,
+            r#"{
+                "added_tokens": [
+                    {"id": 0, "content": "<unk>", "special": true},
+                    {"id": 1, "content": "<s>", "special": true},
+                    {"id": 2, "content": "</s>", "special": true}
+                ],
+                "model": {
+                    "type": "BPE",
+                    "unk_token": "<unk>",
+                    "byte_fallback": true,
+                    "vocab": {"<unk>": 0, "<s>": 1, "</s>": 2, "▁": 3, "h": 4, "i": 5, "hi": 6, "▁hi": 7, "<0x21>": 8},
+                    "merges": ["h i", ["▁", "hi"]]
+                }
+            }"#,
+        )
+        .unwrap();
+        let tokenizer_config = directory.join("tokenizer_config.json");
+        std::fs::write(
+            &tokenizer_config,
+            r#"{"bos_token": "<s>", "eos_token": {"content": "</s>"}, "chat_template": "{{ messages }}"}"#,
+        )
+        .unwrap();
+
+        let metadata: HashMap<String, Value> =
+            to_metadata(&tokenizer_json, Some(&tokenizer_config))
+                .unwrap()
+                .into_iter()
+                .collect();
+        assert_eq!(
+            metadata["tokenizer.ggml.model"].to_string().unwrap(),
+            "llama"
+        );
+        assert_eq!(metadata["tokenizer.ggml.eos_token_id"].to_u32().unwrap(), 2);
+        assert_eq!(
+            metadata["tokenizer.chat_template"].to_string().unwrap(),
+            "{{ messages }}"
+        );
+        // the same tokenizer as `llama_metadata`, once read back
+        let tokenizer = from_metadata(&metadata).unwrap();
+        assert_eq!(
+            tokenizer.encode("hi hi!", true).unwrap().get_ids(),
+            &[1, 7, 7, 8]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_models() {
+        let mut metadata = llama_metadata();
+        metadata.insert(
+            "tokenizer.ggml.model".to_string(),
+            Value::String("rwkv".to_string()),
+        );
+        let error = from_metadata(&metadata).unwrap_err().to_string();
+        assert!(error.contains("unsupported tokenizer model"));
+
+        metadata.remove("tokenizer.ggml.tokens");
+        let error = from_metadata(&metadata).unwrap_err().to_string();
+        assert!(error.contains("tokenizer.ggml.tokens is missing"));
+    }
+}
@@ -0,0 +1,118 @@
+//! The model architectures, behind the `Model` trait so that `LLM` and the scheduler don't depend on one of them.
+//!
+//! `quantized_llama` is our fork with KV cache slots and batching, the other architectures are the quantized models of
+//! `candle_transformers`, wrapped by `upstream::UpstreamModel`. The architecture is picked from the
+//! `general.architecture` key of the GGUF file, or from the `config.json` of unquantized checkpoints, see
+//! `safetensors`.
+use candle_core::quantized::gguf_file;
+use candle_core::{Result, Tensor};
+
+pub mod gguf_tokenizer;
+pub mod quantized_llama;
+pub mod safetensors;
+pub mod special_tokens;
+pub mod upstream;
+
+use quantized_llama::BatchSequence;
+
+/// A causal language model with a KV cache per slot, see `quantized_llama::ModelWeights` for the reference
+/// implementation.
+///
+/// `Send` lets the scheduler task own the model.
+pub trait Model: Send {
+    /// Evaluates the tokens `x` (shaped `(1, seq_len)`) at positions `index_pos..index_pos + seq_len` in the current
+    /// slot, and returns the logits of the last position. The KV cache is rewound to `index_pos` first.
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor>;
+
+    /// Same as `forward` but returns the logits of every position, shaped `(1, seq_len, vocab_size)`.
+    fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor>;
+
+    /// Evaluates several sequences, each in its own slot, returns the logits of the last token of each sequence,
+    /// shaped `(batch.len(), vocab_size)`.
+    fn forward_batch(&mut self, batch: &[BatchSequence]) -> Result<Tensor>;
+
+    /// Makes `forward` and `forward_all` use the KV cache of `slot`.
+    fn select_slot(&mut self, slot: usize);
+
+    /// The number of positions in the KV cache of `slot`.
+    fn slot_len(&self, slot: usize) -> usize;
+
+    /// How many sequences can be decoded together efficiently, the scheduler never batches more.
+    fn max_batch_size(&self) -> usize {
+        usize::MAX
+    }
+
+    /// The maximum number of positions in the KV cache of a slot, prompt and generated tokens included.
+    fn max_seq_len(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Whether `forward_all` evaluates the tokens in a single forward pass. Speculative decoding verifies the draft
+    /// tokens with `forward_all`, it only pays off when it does.
+    fn is_forward_all_batched(&self) -> bool {
+        true
+    }
+}
+
+impl Model for quantized_llama::ModelWeights {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        quantized_llama::ModelWeights::forward(self, x, index_pos)
+    }
+
+    fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        quantized_llama::ModelWeights::forward_all(self, x, index_pos)
+    }
+
+    fn forward_batch(&mut self, batch: &[BatchSequence]) -> Result<Tensor> {
+        quantized_llama::ModelWeights::forward_batch(self, batch)
+    }
+
+    fn select_slot(&mut self, slot: usize) {
+        quantized_llama::ModelWeights::select_slot(self, slot)
+    }
+
+    fn slot_len(&self, slot: usize) -> usize {
+        quantized_llama::ModelWeights::slot_len(self, slot)
+    }
+
+    fn max_seq_len(&self) -> usize {
+        quantized_llama::ModelWeights::max_seq_len(self)
+    }
+}
+
+/// The values of `general.architecture` we can load.
+pub const ARCHITECTURES: [&str; 5] = ["llama", "phi2", "phi3", "qwen2", "gemma3"];
+
+/// The `general.architecture` of a GGUF file, files converted before the key existed are Llama models.
+pub fn architecture(content: &gguf_file::Content) -> Result<String> {
+    match content.metadata.get("general.architecture") {
+        Some(architecture) => Ok(architecture.to_string()?.clone()),
+        None => Ok("llama".to_string()),
+    }
+}
+
+/// Builds the model of a GGUF file, e.g. Mistral, CodeLlama or DeepSeek Coder (all "llama"), Phi-2, Phi-3,
+/// Qwen2 (Qwen2.5-Coder) or Gemma 3.
+pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+    content: gguf_file::Content,
+    reader: &mut R,
+) -> Result<Box<dyn Model>> {
+    let architecture = architecture(&content)?;
+    let model: Box<dyn Model> = match architecture.as_str() {
+        "llama" => Box::new(quantized_llama::ModelWeights::from_gguf(content, reader)?),
+        "phi2" | "phi3" | "qwen2" | "gemma3" => {
+            Box::new(upstream::UpstreamModel::from_gguf(content, reader)?)
+        }
+        // candle_transformers only has unquantized versions of these models
+        "gemma" | "gemma2" | "starcoder2" => candle_core::bail!(
+            "{} GGUF files are not supported, load the safetensors checkpoint of the model instead",
+            architecture
+        ),
+        _ => candle_core::bail!(
+            "unsupported model architecture {:?}, supported architectures are {}",
+            architecture,
+            ARCHITECTURES.join(", ")
+        ),
+    };
+    Ok(model)
+}
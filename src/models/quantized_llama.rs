@@ -0,0 +1,568 @@
+//! A fork of `candle_transformers::models::quantized_llama`.
+//!
+//! The upstream model keeps the KV cache private and drops it whenever `index_pos == 0`, here the cache can be
+//! rewound to any position instead, which lets us keep the cache of the previous request and only evaluate the
+//! part of the prompt that changed. The attention mask also takes the cached positions into account, so several
+//! tokens can be evaluated on top of a non-empty cache.
+//!
+//! The weights also hold several KV caches ("slots"), one per sequence, so that `forward_batch` can evaluate
+//! several sequences in one forward pass: the projections and the MLP run on the tokens of all sequences at once,
+//! only the attention is computed per sequence, against the cache of its own slot.
+//! See https://github.com/huggingface/candle/blob/main/candle-transformers/src/models/quantized_llama.rs
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm};
+
+/// The context length of models without `llama.context_length` in their metadata.
+pub const DEFAULT_SEQ_LEN: usize = 4096;
+/// The KV cache never grows past this many positions, whatever the context length of the model, so that a model
+/// trained on a long context doesn't allocate rope tables and caches we would never fill on a laptop.
+pub const MAX_SEQ_LEN: usize = 32768;
+
+struct RmsNorm {
+    inner: LayerNorm,
+}
+
+impl RmsNorm {
+    fn new(scale: QTensor, eps: f32) -> Result<Self> {
+        let scale = scale.dequantize(&Device::Cpu)?;
+        let inner = LayerNorm::rms_norm(scale, eps as f64);
+        Ok(Self { inner })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        self.inner.forward(x)
+    }
+}
+
+struct Mlp {
+    feed_forward_w1: QMatMul,
+    feed_forward_w2: QMatMul,
+    feed_forward_w3: QMatMul,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let w1 = self.feed_forward_w1.forward(xs)?;
+        let w3 = self.feed_forward_w3.forward(xs)?;
+        self.feed_forward_w2
+            .forward(&(candle_nn::ops::silu(&w1)? * w3)?)
+    }
+}
+
+struct LayerWeights {
+    attention_wq: QMatMul,
+    attention_wk: QMatMul,
+    attention_wv: QMatMul,
+    attention_wo: QMatMul,
+    attention_norm: RmsNorm,
+    mlp: Mlp,
+    ffn_norm: RmsNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    cos: Tensor,
+    sin: Tensor,
+    /// `(k, v)` of the evaluated positions per slot, shaped `(batch, n_kv_head, seq_len, head_dim)`
+    kv_caches: Vec<Option<(Tensor, Tensor)>>,
+}
+
+/// The positions `start..start + len` of the input belong to the sequence in `slot`, at `index_pos..`.
+struct Span {
+    slot: usize,
+    start: usize,
+    len: usize,
+    index_pos: usize,
+}
+
+/// One sequence of a batch, see [`ModelWeights::forward_batch`].
+pub struct BatchSequence<'a> {
+    /// The KV cache slot of the sequence.
+    pub slot: usize,
+    /// The tokens to evaluate, e.g. the whole prompt, or only the last sampled token.
+    pub tokens: &'a [u32],
+    /// The position of the first token, the cache of `slot` is rewound to it.
+    pub index_pos: usize,
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
+    let m = mask.where_cond(&on_true, on_false)?;
+    Ok(m)
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, n_head, seq_len, n_embd) = x.dims4()?;
+        let cos = self
+            .cos
+            .narrow(0, index_pos, seq_len)?
+            .reshape((seq_len, n_embd / 2, 1))?;
+        let sin = self
+            .sin
+            .narrow(0, index_pos, seq_len)?
+            .reshape((seq_len, n_embd / 2, 1))?;
+        let cos = cos.broadcast_as((b_sz, 1, seq_len, n_embd / 2, 1))?;
+        let sin = sin.broadcast_as((b_sz, 1, seq_len, n_embd / 2, 1))?;
+        // This mimics the llama.cpp behavior.
+        // https://github.com/ggerganov/llama.cpp/blob/1f0bccb27929e261744c979bc75114955da49e98/ggml.c#L12104-L12105
+        // The x0 and x1 value are interleaved on the n_embd (= head_dim) dimension.
+        // The resulting y0 and y1 are also interleaved with:
+        //   y0 = x0*cos - x1*sin
+        //   y1 = x0*sin + x1*cos
+        let x = x.reshape((b_sz, n_head, seq_len, n_embd / 2, 2))?;
+        let x0 = x.narrow(D::Minus1, 0, 1)?;
+        let x1 = x.narrow(D::Minus1, 1, 1)?;
+        let y0 = (x0.broadcast_mul(&cos)? - x1.broadcast_mul(&sin)?)?;
+        let y1 = (x0.broadcast_mul(&sin)? + x1.broadcast_mul(&cos)?)?;
+        let rope = Tensor::cat(&[y0, y1], D::Minus1)?;
+        let rope = rope.flatten_from(D::Minus2)?;
+        Ok(rope)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        spans: &[Span],
+        masks: &[Option<Tensor>],
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self.attention_wq.forward(x)?;
+        let k = self.attention_wk.forward(x)?;
+        let v = self.attention_wv.forward(x)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = v
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+
+        // each sequence attends to its own positions only
+        let mut ys = Vec::with_capacity(spans.len());
+        for (span, mask) in spans.iter().zip(masks) {
+            let q = q.narrow(2, span.start, span.len)?;
+            let k = k.narrow(2, span.start, span.len)?;
+            let v = v.narrow(2, span.start, span.len)?;
+            ys.push(self.attend(&q, &k, &v, span.slot, span.index_pos, mask.as_ref())?);
+        }
+        let y = Tensor::cat(&ys, 2)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        let y = self.attention_wo.forward(&y)?;
+        Ok(y)
+    }
+
+    /// The attention of one sequence, `q`, `k` and `v` are shaped `(batch, n_head, seq_len, head_dim)`.
+    fn attend(
+        &mut self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        slot: usize,
+        index_pos: usize,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let q = self.apply_rotary_emb(q, index_pos)?;
+        let k = self.apply_rotary_emb(k, index_pos)?;
+
+        if self.kv_caches.len() <= slot {
+            self.kv_caches.resize(slot + 1, None);
+        }
+        // `index_pos` is the number of positions to keep from the cache, everything after it is dropped.
+        let (k, v) = match &self.kv_caches[slot] {
+            Some((k_cache, v_cache)) if index_pos > 0 => {
+                let k_cache = k_cache.narrow(2, 0, index_pos)?;
+                let v_cache = v_cache.narrow(2, 0, index_pos)?;
+                let k = Tensor::cat(&[&k_cache, &k], 2)?.contiguous()?;
+                let v = Tensor::cat(&[&v_cache, v], 2)?.contiguous()?;
+                (k, v)
+            }
+            _ => (k.contiguous()?, v.contiguous()?),
+        };
+        self.kv_caches[slot] = Some((k.clone(), v.clone()));
+
+        // Support for MQA, useful for 70B models.
+        let k = self.repeat_kv(k)?;
+        let v = self.repeat_kv(v)?;
+
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, f32::NEG_INFINITY)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        att.matmul(&v.contiguous()?)
+    }
+
+    fn repeat_kv(&self, x: Tensor) -> Result<Tensor> {
+        let n_rep = self.n_head / self.n_kv_head;
+        if n_rep == 1 {
+            Ok(x)
+        } else {
+            let (b_sz, n_kv_head, seq_len, head_dim) = x.dims4()?;
+            x.unsqueeze(2)?
+                .broadcast_as((b_sz, n_kv_head, n_rep, seq_len, head_dim))?
+                .reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
+        }
+    }
+
+    fn kv_cache_len(&self, slot: usize) -> usize {
+        match self.kv_caches.get(slot) {
+            Some(Some((k_cache, _))) => k_cache.dim(2).unwrap_or(0),
+            _ => 0,
+        }
+    }
+}
+
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    norm: RmsNorm,
+    output: QMatMul,
+    max_seq_len: usize,
+    /// The slot used by `forward`, `forward_all` and the `kv_cache_*` methods, see `select_slot`.
+    slot: usize,
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    max_seq_len: usize,
+) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), &Device::Cpu)?;
+    let idx_theta = Tensor::arange(0, max_seq_len as u32, &Device::Cpu)?
+        .to_dtype(DType::F32)?
+        .reshape((max_seq_len, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?;
+    let sin = idx_theta.sin()?;
+    Ok((cos, sin))
+}
+
+/// The causal mask for `seq_len` new positions on top of `index_pos` cached positions, shaped
+/// `(seq_len, index_pos + seq_len)`. `1` means the position is masked.
+fn mask(seq_len: usize, index_pos: usize) -> Result<Tensor> {
+    let kv_len = index_pos + seq_len;
+    let mask: Vec<u8> = (0..seq_len)
+        .flat_map(|i| (0..kv_len).map(move |j| u8::from(j > index_pos + i)))
+        .collect();
+    Tensor::from_slice(&mask, (seq_len, kv_len), &Device::Cpu)
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle_core::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        // Parameter extraction from metadata.
+        let head_count = md_get("llama.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("llama.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("llama.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("llama.embedding_length")?.to_u32()? as usize;
+        let rope_dim = md_get("llama.rope.dimension_count")?.to_u32()? as usize;
+        // Strangely this value is generally 1e-6 in GGUF file but used to be 1e-5 by default.
+        let rms_norm_eps = md_get("llama.attention.layer_norm_rms_epsilon")?.to_f32()?;
+
+        let rope_freq_base = md_get("llama.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+        let max_seq_len = md_get("llama.context_length")
+            .and_then(|m| m.to_u32())
+            .map(|context_length| context_length as usize)
+            .unwrap_or(DEFAULT_SEQ_LEN)
+            .min(MAX_SEQ_LEN);
+        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, max_seq_len)?;
+
+        let device = Device::Cpu;
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight", &device)?;
+        let tok_embeddings = tok_embeddings.dequantize(&Device::Cpu)?;
+        let norm = RmsNorm::new(
+            ct.tensor(reader, "output_norm.weight", &device)?,
+            rms_norm_eps,
+        )?;
+        let output = ct.tensor(reader, "output.weight", &device)?;
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), &device)?;
+            let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), &device)?;
+            let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), &device)?;
+            let attention_wo =
+                ct.tensor(reader, &format!("{prefix}.attn_output.weight"), &device)?;
+            let mlp = Mlp {
+                feed_forward_w1: QMatMul::from_qtensor(ct.tensor(
+                    reader,
+                    &format!("{prefix}.ffn_gate.weight"),
+                    &device,
+                )?)?,
+                feed_forward_w2: QMatMul::from_qtensor(ct.tensor(
+                    reader,
+                    &format!("{prefix}.ffn_down.weight"),
+                    &device,
+                )?)?,
+                feed_forward_w3: QMatMul::from_qtensor(ct.tensor(
+                    reader,
+                    &format!("{prefix}.ffn_up.weight"),
+                    &device,
+                )?)?,
+            };
+            let attention_norm =
+                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), &device)?;
+            let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), &device)?;
+            layers.push(LayerWeights {
+                attention_wq: QMatMul::from_qtensor(attention_wq)?,
+                attention_wk: QMatMul::from_qtensor(attention_wk)?,
+                attention_wv: QMatMul::from_qtensor(attention_wv)?,
+                attention_wo: QMatMul::from_qtensor(attention_wo)?,
+                attention_norm: RmsNorm::new(attention_norm, rms_norm_eps)?,
+                mlp,
+                ffn_norm: RmsNorm::new(ffn_norm, rms_norm_eps)?,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim: embedding_length / head_count,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                kv_caches: vec![],
+            })
+        }
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            norm,
+            output: QMatMul::from_qtensor(output)?,
+            max_seq_len,
+            slot: 0,
+        })
+    }
+
+    /// The number of positions the KV cache can hold, the context length of the model capped to `MAX_SEQ_LEN`.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Evaluates the tokens `x` (shaped `(batch, seq_len)`) at positions `index_pos..index_pos + seq_len`, and
+    /// returns the logits of the last position.
+    ///
+    /// The KV cache is rewound to `index_pos` first, e.g. `index_pos == 0` starts from an empty cache (the upstream
+    /// behavior), and `index_pos == self.kv_cache_len()` appends to the cache.
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let x = self.forward_hidden(x, index_pos)?;
+        let seq_len = x.dim(1)?;
+        // only project the last position, the vocabulary projection is expensive
+        self.output.forward(&x.i((.., seq_len - 1, ..))?)
+    }
+
+    /// Same as `forward` but returns the logits of every position, shaped `(batch, seq_len, vocab_size)`, e.g. to
+    /// verify several draft tokens in a single forward pass.
+    pub fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let x = self.forward_hidden(x, index_pos)?;
+        self.output.forward(&x)
+    }
+
+    /// Evaluates several sequences, each in its own KV cache slot, in one forward pass. Returns the logits of the
+    /// last token of each sequence, shaped `(batch.len(), vocab_size)`.
+    pub fn forward_batch(&mut self, batch: &[BatchSequence]) -> Result<Tensor> {
+        let mut tokens = vec![];
+        let mut spans = Vec::with_capacity(batch.len());
+        for sequence in batch {
+            if sequence.tokens.is_empty() {
+                candle_core::bail!("no tokens to evaluate in slot {}", sequence.slot)
+            }
+            spans.push(Span {
+                slot: sequence.slot,
+                start: tokens.len(),
+                len: sequence.tokens.len(),
+                index_pos: sequence.index_pos,
+            });
+            tokens.extend_from_slice(sequence.tokens);
+        }
+        let x = Tensor::new(tokens.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
+        let x = self.forward_spans(&x, &spans)?;
+        let last_positions = spans
+            .iter()
+            .map(|span| x.i((.., span.start + span.len - 1, ..)))
+            .collect::<Result<Vec<_>>>()?;
+        self.output.forward(&Tensor::cat(&last_positions, 0)?)
+    }
+
+    /// Runs the transformer blocks on the current slot, returns the normalized hidden states of every position.
+    fn forward_hidden(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let span = Span {
+            slot: self.slot,
+            start: 0,
+            len: seq_len,
+            index_pos,
+        };
+        self.forward_spans(x, &[span])
+    }
+
+    fn forward_spans(&mut self, x: &Tensor, spans: &[Span]) -> Result<Tensor> {
+        let mut masks = Vec::with_capacity(spans.len());
+        for span in spans {
+            if span.index_pos > self.slot_len(span.slot) {
+                candle_core::bail!(
+                    "index_pos {} is past the end of the KV cache ({}) of slot {}",
+                    span.index_pos,
+                    self.slot_len(span.slot),
+                    span.slot
+                )
+            }
+            if span.index_pos + span.len > self.max_seq_len {
+                candle_core::bail!(
+                    "the sequence in slot {} is longer than {} tokens",
+                    span.slot,
+                    self.max_seq_len
+                )
+            }
+            masks.push(if span.len == 1 {
+                None
+            } else {
+                Some(mask(span.len, span.index_pos)?)
+            });
+        }
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        for layer in self.layers.iter_mut() {
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_attn(&x, spans, &masks)?;
+            let x = (attn + residual)?;
+
+            // MLP
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = x
+        }
+        self.norm.forward(&layer_in)
+    }
+
+    /// Makes `forward`, `forward_all` and the `kv_cache_*` methods use the KV cache of `slot`, slot 0 is used by
+    /// default.
+    pub fn select_slot(&mut self, slot: usize) {
+        self.slot = slot;
+    }
+
+    /// The number of positions in the KV cache of `slot`.
+    pub fn slot_len(&self, slot: usize) -> usize {
+        self.layers
+            .first()
+            .map(|layer| layer.kv_cache_len(slot))
+            .unwrap_or(0)
+    }
+
+    /// Drops the positions of `slot` after `len`.
+    pub fn truncate_slot(&mut self, slot: usize, len: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            if let Some(Some((k_cache, v_cache))) = layer.kv_caches.get(slot) {
+                layer.kv_caches[slot] = if len == 0 {
+                    None
+                } else {
+                    let len = len.min(k_cache.dim(2)?);
+                    Some((k_cache.narrow(2, 0, len)?, v_cache.narrow(2, 0, len)?))
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of positions in the KV cache of the current slot.
+    pub fn kv_cache_len(&self) -> usize {
+        self.slot_len(self.slot)
+    }
+
+    /// Drops the cached positions of the current slot after `len`.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.truncate_slot(self.slot, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{tiny_model, TinyConfig};
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b)
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn forward_batch_matches_forward() -> Result<()> {
+        let mut model = tiny_model(&TinyConfig::default())?;
+        let first = [1u32, 2, 3, 4, 5];
+        let second = [6u32, 7, 8];
+
+        let expected_first = model.forward(&Tensor::new(&first, &Device::Cpu)?.unsqueeze(0)?, 0)?;
+        let expected_second =
+            model.forward(&Tensor::new(&second, &Device::Cpu)?.unsqueeze(0)?, 0)?;
+
+        // a prefill of two sequences, then a decode step on top of their caches
+        let logits = model.forward_batch(&[
+            BatchSequence {
+                slot: 1,
+                tokens: &first[..4],
+                index_pos: 0,
+            },
+            BatchSequence {
+                slot: 2,
+                tokens: &second,
+                index_pos: 0,
+            },
+        ])?;
+        assert!(max_diff(&logits.get(1)?, &expected_second.squeeze(0)?) < 1e-4);
+        assert_eq!(model.slot_len(1), 4);
+        assert_eq!(model.slot_len(2), 3);
+
+        let logits = model.forward_batch(&[BatchSequence {
+            slot: 1,
+            tokens: &first[4..],
+            index_pos: 4,
+        }])?;
+        assert!(max_diff(&logits.get(0)?, &expected_first.squeeze(0)?) < 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn select_slot_keeps_other_caches() -> Result<()> {
+        let mut model = tiny_model(&TinyConfig::default())?;
+        let tokens = Tensor::new(&[1u32, 2, 3], &Device::Cpu)?.unsqueeze(0)?;
+        model.forward(&tokens, 0)?;
+        model.select_slot(1);
+        assert_eq!(model.kv_cache_len(), 0);
+        model.forward(&tokens.narrow(1, 0, 2)?, 0)?;
+        assert_eq!(model.kv_cache_len(), 2);
+        assert_eq!(model.slot_len(0), 3);
+        model.truncate_kv_cache(1)?;
+        assert_eq!(model.slot_len(1), 1);
+        assert_eq!(model.slot_len(0), 3);
+        Ok(())
+    }
+}
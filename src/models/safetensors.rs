@@ -0,0 +1,324 @@
+//! Unquantized checkpoints as published by `transformers`: the weights in `model.safetensors`, or sharded in
+//! `model-00001-of-00002.safetensors`... listed by `model.safetensors.index.json`, and the hyperparameters in
+//! `config.json`. The architecture is picked from the `model_type` of `config.json`, and the weights are memory
+//! mapped by `VarBuilder` in f32 or bf16, on CPU.
+//!
+//! The models of `candle_transformers` keep a single KV cache, they are wrapped by `UpstreamModel` like the quantized
+//! ones, see `upstream`.
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{
+    gemma, gemma2, gemma3, llama, mistral, phi, phi3, qwen2, starcoder2,
+};
+use serde::Deserialize;
+
+use super::upstream::{SingleCache, UpstreamModel};
+use super::Model;
+
+/// The hyperparameters of the model, next to the weights.
+pub const CONFIG: &str = "config.json";
+
+/// The values of `model_type` in `config.json` we can load.
+pub const MODEL_TYPES: [&str; 9] = [
+    "llama",
+    "mistral",
+    "phi",
+    "phi3",
+    "qwen2",
+    "gemma",
+    "gemma2",
+    "gemma3_text",
+    "starcoder2",
+];
+
+/// The type of the weights once loaded, the checkpoints are usually stored in bf16, f32 takes twice the memory but
+/// is faster on most CPUs.
+///
+/// `clap::ValueEnum` lets clap parse the enum from CLI arguments, e.g. `--dtype bf16`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Precision {
+    #[default]
+    F32,
+    Bf16,
+}
+
+impl Precision {
+    pub fn dtype(&self) -> DType {
+        match self {
+            Precision::F32 => DType::F32,
+            Precision::Bf16 => DType::BF16,
+        }
+    }
+}
+
+/// Whether the model file is a safetensors checkpoint (or its index) rather than a GGUF file.
+pub fn is_safetensors(file_name: &str) -> bool {
+    file_name.ends_with(".safetensors") || file_name.ends_with(".safetensors.index.json")
+}
+
+#[derive(Deserialize)]
+struct Index {
+    /// The file of each tensor.
+    weight_map: HashMap<String, String>,
+}
+
+/// The files of a sharded checkpoint listed by its `model.safetensors.index.json`, sorted.
+pub fn shard_file_names(index_path: &Path) -> Result<Vec<String>> {
+    let index = std::fs::read_to_string(index_path)
+        .with_context(|| format!("Failed to read {}", index_path.display()))?;
+    let index: Index = serde_json::from_str(&index)
+        .with_context(|| format!("Failed to parse {}", index_path.display()))?;
+    let shards: BTreeSet<String> = index.weight_map.into_values().collect();
+    if shards.is_empty() {
+        return Err(anyhow!("{} lists no weights", index_path.display()));
+    }
+    Ok(shards.into_iter().collect())
+}
+
+/// The keys of `config.json` common to every architecture.
+#[derive(Deserialize)]
+struct ModelType {
+    model_type: String,
+    /// The context length of the model.
+    max_position_embeddings: Option<usize>,
+}
+
+/// Builds the model of `config.json` from the safetensors `weight_paths`, returns its `model_type` and the model.
+pub fn load(
+    config_path: &Path,
+    weight_paths: &[PathBuf],
+    precision: Precision,
+) -> Result<(String, Box<dyn Model>)> {
+    let config = std::fs::read_to_string(config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    let ModelType {
+        model_type,
+        max_position_embeddings,
+    } = serde_json::from_str(&config)
+        .with_context(|| format!("{} has no model_type", config_path.display()))?;
+    let parse_error = || {
+        format!(
+            "Failed to parse {} as a {} config",
+            config_path.display(),
+            model_type
+        )
+    };
+    let device = Device::Cpu;
+    let dtype = precision.dtype();
+    // the files are memory mapped, they must not be modified while the model is loaded
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(weight_paths, dtype, &device)? };
+    let model: Box<dyn SingleCache> = match model_type.as_str() {
+        "llama" => {
+            let config: llama::LlamaConfig =
+                serde_json::from_str(&config).with_context(parse_error)?;
+            // flash attention is for CUDA only
+            Box::new(Llama::new(vb, config.into_config(false), dtype)?)
+        }
+        "mistral" => {
+            let config: mistral::Config =
+                serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(mistral::Model::new(&config, vb)?)
+        }
+        "phi" => {
+            let config: phi::Config = serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(phi::Model::new(&config, vb)?)
+        }
+        "phi3" => {
+            let config: phi3::Config = serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(phi3::Model::new(&config, vb)?)
+        }
+        "qwen2" => {
+            let config: qwen2::Config = serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(qwen2::ModelForCausalLM::new(&config, vb)?)
+        }
+        "gemma" => {
+            let config: gemma::Config = serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(gemma::Model::new(false, &config, vb)?)
+        }
+        "gemma2" => {
+            let config: gemma2::Config = serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(gemma2::Model::new(false, &config, vb)?)
+        }
+        "starcoder2" => {
+            let config: starcoder2::Config =
+                serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(starcoder2::Model::new(&config, vb)?)
+        }
+        "gemma3_text" => {
+            let config: gemma3::Config = serde_json::from_str(&config).with_context(parse_error)?;
+            Box::new(gemma3::Model::new(false, &config, vb)?)
+        }
+        _ => {
+            return Err(anyhow!(
+                "unsupported model_type {:?} in {}, supported types are {}",
+                model_type,
+                config_path.display(),
+                MODEL_TYPES.join(", ")
+            ))
+        }
+    };
+    let max_seq_len = max_position_embeddings
+        .with_context(|| format!("{} has no max_position_embeddings", config_path.display()))?;
+    Ok((model_type, Box::new(UpstreamModel::new(model, max_seq_len))))
+}
+
+/// `llama::Llama` keeps its KV cache outside of the model, a new cache is created when `index_pos == 0`.
+struct Llama {
+    model: llama::Llama,
+    config: llama::Config,
+    cache: llama::Cache,
+    dtype: DType,
+}
+
+impl Llama {
+    fn new(vb: VarBuilder, config: llama::Config, dtype: DType) -> candle_core::Result<Self> {
+        let model = llama::Llama::load(vb, &config)?;
+        let cache = llama::Cache::new(true, dtype, &config, &Device::Cpu)?;
+        Ok(Llama {
+            model,
+            config,
+            cache,
+            dtype,
+        })
+    }
+}
+
+impl SingleCache for Llama {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.cache = llama::Cache::new(true, self.dtype, &self.config, &Device::Cpu)?;
+        }
+        self.model.forward(x, index_pos, &mut self.cache)
+    }
+}
+
+// The other models keep the cache inside, and append to it until it's cleared.
+
+impl SingleCache for mistral::Model {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        mistral::Model::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for phi::Model {
+    /// Phi tracks the positions itself.
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        phi::Model::forward(self, x)
+    }
+}
+
+impl SingleCache for phi3::Model {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        phi3::Model::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for qwen2::ModelForCausalLM {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        qwen2::ModelForCausalLM::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for gemma::Model {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        gemma::Model::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for gemma2::Model {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        gemma2::Model::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for starcoder2::Model {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        starcoder2::Model::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for gemma3::Model {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> candle_core::Result<Tensor> {
+        if index_pos == 0 {
+            self.clear_kv_cache();
+        }
+        gemma3::Model::forward(self, x, index_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_safetensors_files() {
+        assert!(is_safetensors("model.safetensors"));
+        assert!(is_safetensors("model.safetensors.index.json"));
+        assert!(!is_safetensors("model.Q4_K_M.gguf"));
+        assert!(!is_safetensors("config.json"));
+    }
+
+    #[test]
+    fn lists_the_shards_of_an_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("model.safetensors.index.json");
+        std::fs::write(
+            &index,
+            r#"{
+                "metadata": {"total_size": 1024},
+                "weight_map": {
+                    "lm_head.weight": "model-00002-of-00002.safetensors",
+                    "model.embed_tokens.weight": "model-00001-of-00002.safetensors",
+                    "model.norm.weight": "model-00002-of-00002.safetensors"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            shard_file_names(&index).unwrap(),
+            vec![
+                "model-00001-of-00002.safetensors",
+                "model-00002-of-00002.safetensors"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_model_types() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config.json");
+        std::fs::write(&config, r#"{"model_type": "rwkv"}"#).unwrap();
+        let weights = dir.path().join("empty.safetensors");
+        candle_core::safetensors::save(&HashMap::<String, Tensor>::new(), &weights).unwrap();
+        let error = load(&config, &[weights], Precision::F32)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("unsupported model_type \"rwkv\""));
+    }
+}
@@ -0,0 +1,197 @@
+//! The special tokens of a model, read from the GGUF metadata and the `tokenizer_config.json` next to the tokenizer,
+//! instead of assuming the `</s>` of Llama 2. A ChatML model (OpenHermes, Qwen...) ends its answers with
+//! `<|im_end|>`, Llama 3 with `<|eot_id|>`, StarCoder with `<|endoftext|>`, and the generation must stop on any of
+//! them.
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file::Value;
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+/// The end of turn tokens of instruct models, the generation stops on the ones in the vocabulary, like `llama.cpp`
+/// does, since GGUF files converted before `tokenizer.ggml.eot_token_id` existed only set the EOS token.
+const END_OF_TURN_TOKENS: [&str; 7] = [
+    "<|im_end|>",
+    "<|eot_id|>",
+    "<|end|>",
+    "<end_of_turn>",
+    "<|EOT|>",
+    "<|endoftext|>",
+    "<|end_of_text|>",
+];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpecialTokens {
+    /// Beginning of sequence, prepended to the prompt by the tokenizer.
+    pub bos_token_id: Option<u32>,
+    /// End of sequence, e.g. `</s>`.
+    pub eos_token_id: Option<u32>,
+    /// End of turn of instruct models, e.g. `<|im_end|>` or `<|eot_id|>`.
+    pub eot_token_id: Option<u32>,
+    /// Every token ending a generation, the EOS and EOT tokens included.
+    pub stop_token_ids: Vec<u32>,
+}
+
+/// The special tokens of `tokenizer_config.json`, as a string or as an added token object.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigToken {
+    Content(String),
+    AddedToken { content: String },
+}
+
+impl ConfigToken {
+    fn content(&self) -> &str {
+        match self {
+            ConfigToken::Content(content) | ConfigToken::AddedToken { content } => content,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenizerConfig {
+    bos_token: Option<ConfigToken>,
+    eos_token: Option<ConfigToken>,
+}
+
+impl SpecialTokens {
+    /// `tokenizer_config` overrides the BOS and EOS tokens of `metadata`, the EOS token of both ends the generation.
+    pub fn read(
+        metadata: &HashMap<String, Value>,
+        tokenizer_config: Option<&Path>,
+        tokenizer: &Tokenizer,
+    ) -> Result<Self> {
+        let config = match tokenizer_config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                let config: TokenizerConfig = serde_json::from_str(&content)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?;
+                Some(config)
+            }
+            None => None,
+        };
+        let config_token_id = |token: Option<&ConfigToken>| {
+            token.and_then(|token| tokenizer.token_to_id(token.content()))
+        };
+        let gguf_token_id = |key: &str| metadata.get(key).and_then(|id| id.to_u32().ok());
+
+        let config_eos_token_id =
+            config_token_id(config.as_ref().and_then(|config| config.eos_token.as_ref()));
+        let gguf_eos_token_id = gguf_token_id("tokenizer.ggml.eos_token_id");
+        let bos_token_id =
+            config_token_id(config.as_ref().and_then(|config| config.bos_token.as_ref()))
+                .or(gguf_token_id("tokenizer.ggml.bos_token_id"));
+        // without any metadata, fall back to the EOS token of Llama 2
+        let eos_token_id = config_eos_token_id
+            .or(gguf_eos_token_id)
+            .or(tokenizer.token_to_id("</s>"));
+        let end_of_turn_token_ids: Vec<u32> = END_OF_TURN_TOKENS
+            .iter()
+            .filter_map(|token| tokenizer.token_to_id(token))
+            .collect();
+        let eot_token_id =
+            gguf_token_id("tokenizer.ggml.eot_token_id").or(end_of_turn_token_ids.first().copied());
+
+        let mut stop_token_ids = vec![];
+        let candidates = [
+            eos_token_id,
+            gguf_eos_token_id,
+            eot_token_id,
+            // end of message, the end of a tool call of Llama 3.1
+            gguf_token_id("tokenizer.ggml.eom_token_id"),
+        ];
+        for token_id in candidates
+            .into_iter()
+            .flatten()
+            .chain(end_of_turn_token_ids)
+        {
+            if !stop_token_ids.contains(&token_id) {
+                stop_token_ids.push(token_id);
+            }
+        }
+        Ok(SpecialTokens {
+            bos_token_id,
+            eos_token_id,
+            eot_token_id,
+            stop_token_ids,
+        })
+    }
+
+    /// Adds `eos_tokens` to the stop tokens, e.g. the `<|im_end|>` of a model preset, a token missing from the
+    /// vocabulary is an error rather than a generation that never stops.
+    pub fn add_stop_tokens(&mut self, eos_tokens: &[String], tokenizer: &Tokenizer) -> Result<()> {
+        for token in eos_tokens {
+            let token_id = tokenizer
+                .token_to_id(token)
+                .with_context(|| format!("the stop token {:?} is not in the vocabulary", token))?;
+            if !self.stop_token_ids.contains(&token_id) {
+                self.stop_token_ids.push(token_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the generation ends on this token.
+    pub fn is_stop(&self, token_id: u32) -> bool {
+        self.stop_token_ids.contains(&token_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::gguf_tokenizer;
+    use crate::testing::chat_ml_metadata;
+
+    #[test]
+    fn reads_gguf_metadata_and_end_of_turn_tokens() {
+        let metadata = chat_ml_metadata();
+        let tokenizer = gguf_tokenizer::from_metadata(&metadata).unwrap();
+        let special_tokens = SpecialTokens::read(&metadata, None, &tokenizer).unwrap();
+        assert_eq!(special_tokens.bos_token_id, Some(1));
+        assert_eq!(special_tokens.eos_token_id, Some(2));
+        assert_eq!(special_tokens.eot_token_id, Some(7));
+        assert_eq!(special_tokens.stop_token_ids, vec![2, 7]);
+        assert!(special_tokens.is_stop(7));
+        assert!(!special_tokens.is_stop(6));
+    }
+
+    #[test]
+    fn tokenizer_config_overrides_eos() {
+        let mut metadata = chat_ml_metadata();
+        metadata.insert("tokenizer.ggml.eot_token_id".to_string(), Value::U32(7));
+        let tokenizer = gguf_tokenizer::from_metadata(&metadata).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("tokenizer_config.json");
+        std::fs::write(
+            &config,
+            r#"{"bos_token": null, "eos_token": {"content": "<|im_end|>", "special": true}}"#,
+        )
+        .unwrap();
+        let special_tokens = SpecialTokens::read(&metadata, Some(&config), &tokenizer).unwrap();
+        assert_eq!(special_tokens.bos_token_id, Some(1));
+        assert_eq!(special_tokens.eos_token_id, Some(7));
+        // the EOS token of the GGUF file still stops the generation
+        assert_eq!(special_tokens.stop_token_ids, vec![7, 2]);
+    }
+
+    #[test]
+    fn adds_stop_tokens_of_the_vocabulary() {
+        let metadata = chat_ml_metadata();
+        let tokenizer = gguf_tokenizer::from_metadata(&metadata).unwrap();
+        let mut special_tokens = SpecialTokens::read(&metadata, None, &tokenizer).unwrap();
+        special_tokens
+            .add_stop_tokens(
+                &["<|im_start|>".to_string(), "</s>".to_string()],
+                &tokenizer,
+            )
+            .unwrap();
+        assert_eq!(special_tokens.stop_token_ids, vec![2, 7, 6]);
+        assert!(special_tokens
+            .add_stop_tokens(&["<|eot_id|>".to_string()], &tokenizer)
+            .is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! The models of `candle_transformers`, the quantized Phi-2, Phi-3, Qwen2 and Gemma 3 and the unquantized models of
+//! `safetensors`, behind the `Model` trait.
+//!
+//! Unlike our `quantized_llama` fork, they have a single KV cache, reset when `index_pos == 0` and appended to
+//! otherwise, and their attention mask only covers the new tokens, so several tokens can't be appended to a non-empty
+//! cache. `UpstreamModel` remembers the tokens of each slot, and evaluates them again from an empty cache whenever
+//! the cache can't be used as is: to rewind it, to switch to another slot, or to append a prompt. The common prefix
+//! of two prompts is not reused, but generating token by token is as fast as with the fork. Since switching slots
+//! evaluates the whole sequence again, the scheduler decodes one sequence at a time with these models, and doesn't
+//! use speculative decoding, `forward_all` evaluates the draft tokens one by one.
+//! See https://github.com/huggingface/candle/tree/main/candle-transformers/src/models
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Result, Tensor};
+use candle_transformers::models::{
+    quantized_gemma3, quantized_phi, quantized_phi3, quantized_qwen2,
+};
+
+use super::quantized_llama::BatchSequence;
+use super::Model;
+
+/// A model with a single KV cache, reset when `index_pos == 0`, appended to otherwise.
+pub trait SingleCache: Send {
+    /// Returns the logits of the last position, shaped `(1, vocab_size)`.
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor>;
+}
+
+impl SingleCache for quantized_phi::ModelWeights {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        quantized_phi::ModelWeights::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for quantized_phi3::ModelWeights {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        quantized_phi3::ModelWeights::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for quantized_qwen2::ModelWeights {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        quantized_qwen2::ModelWeights::forward(self, x, index_pos)
+    }
+}
+
+impl SingleCache for quantized_gemma3::ModelWeights {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        quantized_gemma3::ModelWeights::forward(self, x, index_pos)
+    }
+}
+
+pub struct UpstreamModel {
+    model: Box<dyn SingleCache>,
+    /// The tokens of each slot, as if each slot had its own KV cache.
+    slots: Vec<Vec<u32>>,
+    /// The slot whose tokens are in the KV cache of `model`, `None` when the cache is in an unknown state.
+    loaded: Option<usize>,
+    /// The slot used by `forward` and `forward_all`.
+    slot: usize,
+    /// `{architecture}.context_length`, the positions of the rotary embeddings of the model.
+    max_seq_len: usize,
+}
+
+impl UpstreamModel {
+    pub fn new(model: Box<dyn SingleCache>, max_seq_len: usize) -> Self {
+        UpstreamModel {
+            model,
+            slots: vec![],
+            loaded: None,
+            slot: 0,
+            max_seq_len,
+        }
+    }
+
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        content: gguf_file::Content,
+        reader: &mut R,
+    ) -> Result<Self> {
+        let device = Device::Cpu;
+        let architecture = super::architecture(&content)?;
+        let context_length = format!("{}.context_length", architecture);
+        let max_seq_len = match content.metadata.get(&context_length) {
+            Some(value) => value.to_u32()? as usize,
+            None => candle_core::bail!("cannot find {} in metadata", context_length),
+        };
+        let model: Box<dyn SingleCache> = match architecture.as_str() {
+            "phi2" => Box::new(quantized_phi::ModelWeights::from_gguf(
+                content, reader, &device,
+            )?),
+            // flash attention is for CUDA only
+            "phi3" => Box::new(quantized_phi3::ModelWeights::from_gguf(
+                false, content, reader, &device,
+            )?),
+            "qwen2" => Box::new(quantized_qwen2::ModelWeights::from_gguf(
+                content, reader, &device,
+            )?),
+            "gemma3" => Box::new(quantized_gemma3::ModelWeights::from_gguf(
+                content, reader, &device,
+            )?),
+            architecture => candle_core::bail!(
+                "{:?} is not one of the architectures of candle_transformers",
+                architecture
+            ),
+        };
+        Ok(Self::new(model, max_seq_len))
+    }
+
+    /// Evaluates `tokens` at `index_pos..` in `slot`, returns the logits of the last token, shaped `(1, vocab_size)`.
+    fn evaluate(&mut self, slot: usize, tokens: &[u32], index_pos: usize) -> Result<Tensor> {
+        if tokens.is_empty() {
+            candle_core::bail!("no tokens to evaluate in slot {}", slot)
+        }
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, vec![]);
+        }
+        if index_pos > self.slots[slot].len() {
+            candle_core::bail!(
+                "index_pos {} is past the end of the KV cache ({}) of slot {}",
+                index_pos,
+                self.slots[slot].len(),
+                slot
+            )
+        }
+        if index_pos + tokens.len() > self.max_seq_len {
+            candle_core::bail!(
+                "the sequence in slot {} is longer than {} tokens",
+                slot,
+                self.max_seq_len
+            )
+        }
+        let can_append =
+            self.loaded == Some(slot) && index_pos == self.slots[slot].len() && tokens.len() == 1;
+        let logits = if index_pos == 0 || can_append {
+            self.model.forward(&input(tokens)?, index_pos)
+        } else {
+            // start over from an empty cache with the tokens of the slot before `index_pos`
+            let mut context = self.slots[slot][..index_pos].to_vec();
+            context.extend_from_slice(tokens);
+            self.model.forward(&input(&context)?, 0)
+        };
+        // the unquantized models return bf16 logits when loaded in bf16, the samplers expect f32
+        match logits.and_then(|logits| logits.flatten_all()?.to_dtype(DType::F32)?.unsqueeze(0)) {
+            Ok(logits) => {
+                self.loaded = Some(slot);
+                self.slots[slot].truncate(index_pos);
+                self.slots[slot].extend_from_slice(tokens);
+                Ok(logits)
+            }
+            Err(error) => {
+                // the cache is in an unknown state, evaluate from scratch next time
+                self.loaded = None;
+                Err(error)
+            }
+        }
+    }
+}
+
+/// The tokens shaped `(1, seq_len)`.
+fn input(tokens: &[u32]) -> Result<Tensor> {
+    Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)
+}
+
+impl Model for UpstreamModel {
+    fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let tokens = x.squeeze(0)?.to_vec1::<u32>()?;
+        self.evaluate(self.slot, &tokens, index_pos)
+    }
+
+    /// The upstream models only return the logits of the last position, the tokens are evaluated one by one.
+    fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let tokens = x.squeeze(0)?.to_vec1::<u32>()?;
+        let mut logits = Vec::with_capacity(tokens.len());
+        for (offset, token) in tokens.iter().enumerate() {
+            logits.push(self.evaluate(self.slot, &[*token], index_pos + offset)?);
+        }
+        Tensor::cat(&logits, 0)?.unsqueeze(0)
+    }
+
+    /// The sequences are evaluated one after the other.
+    fn forward_batch(&mut self, batch: &[BatchSequence]) -> Result<Tensor> {
+        let mut logits = Vec::with_capacity(batch.len());
+        for sequence in batch {
+            logits.push(self.evaluate(sequence.slot, sequence.tokens, sequence.index_pos)?);
+        }
+        Tensor::cat(&logits, 0)
+    }
+
+    fn select_slot(&mut self, slot: usize) {
+        self.slot = slot;
+    }
+
+    fn slot_len(&self, slot: usize) -> usize {
+        self.slots.get(slot).map(Vec::len).unwrap_or(0)
+    }
+
+    /// Switching between slots evaluates the whole sequence again, the sequences are decoded one at a time.
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    fn is_forward_all_batched(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Behaves like the upstream models, the logits are the sum and the number of the tokens in the cache.
+    #[derive(Default)]
+    struct FakeModel {
+        cache: Vec<u32>,
+    }
+
+    impl SingleCache for FakeModel {
+        fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+            let tokens = x.squeeze(0)?.to_vec1::<u32>()?;
+            if index_pos == 0 {
+                self.cache.clear();
+            } else {
+                // the mask of the upstream models only covers the new tokens
+                assert_eq!(tokens.len(), 1);
+                assert_eq!(index_pos, self.cache.len());
+            }
+            self.cache.extend(tokens);
+            let sum = self.cache.iter().sum::<u32>() as f32;
+            Tensor::new(&[[sum, self.cache.len() as f32]], &Device::Cpu)
+        }
+    }
+
+    fn logits(logits: Tensor) -> Vec<f32> {
+        logits.flatten_all().unwrap().to_vec1::<f32>().unwrap()
+    }
+
+    #[test]
+    fn rewinds_and_switches_slots() -> Result<()> {
+        let mut model = UpstreamModel::new(Box::<FakeModel>::default(), 16);
+        assert_eq!(logits(model.forward(&input(&[1, 2, 3])?, 0)?), [6.0, 3.0]);
+        // appended to the cache
+        assert_eq!(logits(model.forward(&input(&[4])?, 3)?), [10.0, 4.0]);
+        // rewound to [1, 2]
+        assert_eq!(logits(model.forward(&input(&[5])?, 2)?), [8.0, 3.0]);
+        // several tokens are evaluated again with the tokens before them
+        assert_eq!(logits(model.forward(&input(&[6, 7])?, 3)?), [21.0, 5.0]);
+        assert_eq!(model.slot_len(0), 5);
+
+        model.select_slot(1);
+        assert_eq!(logits(model.forward(&input(&[9])?, 0)?), [9.0, 1.0]);
+        model.select_slot(0);
+        assert_eq!(logits(model.forward(&input(&[1])?, 5)?), [22.0, 6.0]);
+
+        let all = model.forward_all(&input(&[2, 3])?, 6)?;
+        assert_eq!(all.dims(), &[1, 2, 2]);
+        assert_eq!(logits(all), [24.0, 7.0, 27.0, 8.0]);
+
+        let batch = model.forward_batch(&[
+            BatchSequence {
+                slot: 1,
+                tokens: &[1],
+                index_pos: 1,
+            },
+            BatchSequence {
+                slot: 0,
+                tokens: &[1],
+                index_pos: 8,
+            },
+        ])?;
+        assert_eq!(batch.dims(), &[2, 2]);
+        assert_eq!(logits(batch), [10.0, 2.0, 28.0, 9.0]);
+        assert!(model.forward(&input(&[1])?, 10).is_err());
+
+        // the scheduler neither batches nor speculates with these models
+        assert_eq!(model.max_batch_size(), 1);
+        assert!(!model.is_forward_all_batched());
+        assert_eq!(model.max_seq_len(), 16);
+        // 8 tokens after the 9 of slot 0
+        assert!(model.forward(&input(&[1; 8])?, 9).is_err());
+        Ok(())
+    }
+}
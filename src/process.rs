@@ -1,76 +1,71 @@
-use crate::llm::LLM;
-use crate::token::token_to_text;
+use crate::models::Model;
 use candle_core::{Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
+use tracing::debug;
 
-/// A function that takes a prompt and returns the generated text to a responder.
-pub async fn process(
-    prompt: String,
-    llm: &mut LLM,
-    responder: tokio::sync::mpsc::Sender<String>,
-    to_sample: usize,
-    seed: u64,
-    temperature: f64,
-    top_p: Option<f64>,
-    repeat_last_n: usize,
-    repeat_penalty: f32,
-    eos_token: String,
-    max_sampled: usize,
-) {
-    let tokens = llm
-        .tokenizer
-        .encode(prompt, true)
-        .expect("Failed to encode prompt as tokens.");
-    let prompt_tokens = tokens.get_ids().to_vec();
-    let mut all_tokens: Vec<u32> = vec![];
-    let mut logits_processor = LogitsProcessor::new(seed, Some(temperature), top_p);
-    let mut next_token = {
-        let input = Tensor::new(prompt_tokens.as_slice(), &Device::Cpu)
-            .unwrap()
-            .unsqueeze(0)
-            .unwrap();
-        let logits = llm.model_weights.forward(&input, 0).unwrap();
-        let logits = logits.squeeze(0).unwrap();
-        logits_processor.sample(&logits).unwrap()
-    };
-    all_tokens.push(next_token);
-    responder
-        .send((token_to_text(next_token, &llm.tokenizer)).to_string())
-        .await
-        .unwrap();
+/// Evaluates the prompt and returns the logits of the last prompt token.
+///
+/// Copilot clients send nearly identical prompts on every keystroke, so instead of evaluating the whole prompt from
+/// position 0, the KV cache of the previous request is kept, and only the tokens after the longest common prefix
+/// of `prompt_tokens` and `cached_tokens` (the tokens in the KV cache) are evaluated. The cache is rewound to
+/// where the prompts diverge.
+pub fn prefill(
+    model_weights: &mut dyn Model,
+    cached_tokens: &mut Vec<u32>,
+    prompt_tokens: &[u32],
+) -> candle_core::Result<Tensor> {
+    evaluate(model_weights, cached_tokens, prompt_tokens, 1)?.squeeze(0)
+}
 
-    let eos_token_id = *llm.tokenizer.get_vocab(true).get(&eos_token).unwrap();
+/// Same as `prefill` but returns the logits of the last `n_logits` tokens, shaped `(n_logits, vocab_size)`, e.g.
+/// to verify draft tokens appended to the prompt in a single forward pass.
+pub fn evaluate(
+    model_weights: &mut dyn Model,
+    cached_tokens: &mut Vec<u32>,
+    tokens: &[u32],
+    n_logits: usize,
+) -> candle_core::Result<Tensor> {
+    if n_logits == 0 || n_logits > tokens.len() {
+        candle_core::bail!(
+            "cannot return the logits of {} tokens out of {}",
+            n_logits,
+            tokens.len()
+        )
+    }
+    let common_prefix_len = cached_tokens
+        .iter()
+        .zip(tokens)
+        .take_while(|(cached, token)| cached == token)
+        .count();
+    // the last `n_logits` tokens are always evaluated, we need their logits
+    let reused = common_prefix_len.min(tokens.len() - n_logits);
+    debug!(
+        "reusing {} of {} prompt tokens from the KV cache",
+        reused,
+        tokens.len()
+    );
+    cached_tokens.truncate(reused);
 
-    let mut sampled = 0;
-    for index in 0..to_sample {
-        let input = Tensor::new(&[next_token], &Device::Cpu)
-            .unwrap()
-            .unsqueeze(0)
-            .unwrap();
-        let logits = llm
-            .model_weights
-            .forward(&input, prompt_tokens.len() + index)
-            .unwrap();
-        let logits = logits.squeeze(0).unwrap();
-        let start_at = all_tokens.len().saturating_sub(repeat_last_n);
-        let _ = candle_transformers::utils::apply_repeat_penalty(
-            &logits,
-            repeat_penalty,
-            &all_tokens[start_at..],
-        );
-        next_token = logits_processor.sample(&logits).unwrap();
-        sampled += 1;
-        if next_token == 32000 {
-            break;
-        };
-        if sampled >= max_sampled {
-            break;
+    let input = Tensor::new(&tokens[reused..], &Device::Cpu)?.unsqueeze(0)?;
+    let logits = if n_logits == 1 {
+        model_weights.forward(&input, reused)
+    } else {
+        model_weights
+            .forward_all(&input, reused)
+            .and_then(|logits| logits.squeeze(0))
+            .and_then(|logits| {
+                let len = logits.dim(0)?;
+                logits.narrow(0, len - n_logits, n_logits)
+            })
+    };
+    match logits {
+        Ok(logits) => {
+            cached_tokens.extend_from_slice(&tokens[reused..]);
+            Ok(logits)
         }
-        if next_token == eos_token_id {
-            break;
+        Err(error) => {
+            // the cache is in an unknown state, start from scratch next time
+            cached_tokens.clear();
+            Err(error)
         }
-        all_tokens.push(next_token);
-        let text = token_to_text(next_token, &llm.tokenizer);
-        responder.send((text).to_string()).await.unwrap();
     }
 }
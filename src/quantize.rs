@@ -0,0 +1,618 @@
+//! `ox quantize`: converts a safetensors checkpoint, or an f16/f32 GGUF file, to a quantized GGUF file that
+//! `LLMBuilder` can load, without the Python tools of `llama.cpp`.
+//!
+//! The matrices are quantized to a k-quant type (blocks of 256 values with their own scales, see
+//! https://github.com/ggerganov/llama.cpp/pull/1684), the vectors (norms, biases) and the tensors skipped with `--skip`
+//! are kept in f32, like `llama.cpp` does.
+//!
+//! A GGUF input keeps its metadata and tensor names. A safetensors input (Llama or Mistral, as fine-tuned with
+//! `transformers`) is converted along the way:
+//! - the tensors are renamed to the GGUF names, e.g. `model.layers.0.self_attn.q_proj.weight` to `blk.0.attn_q.weight`,
+//! - the rows of the query and key projections are permuted, `transformers` rotates the two halves of each head while
+//!   GGUF models rotate interleaved pairs, see `quantized_llama::LayerWeights::apply_rotary_emb`,
+//! - `config.json` becomes the `llama.*` metadata, and the `tokenizer.json` next to the weights is embedded, see
+//!   `gguf_tokenizer::to_metadata`.
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device, Tensor};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::models::{gguf_tokenizer, safetensors};
+
+/// The quantization types of `ox quantize --type`, named like the GGUF files quantized with them.
+///
+/// `clap::ValueEnum` lets clap parse the enum from CLI arguments, e.g. `--type q4_k`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum QuantType {
+    #[value(name = "q2_k")]
+    Q2K,
+    #[value(name = "q3_k")]
+    Q3K,
+    /// The usual tradeoff between size and quality, about 4.5 bits per weight.
+    #[default]
+    #[value(name = "q4_k")]
+    Q4K,
+    #[value(name = "q5_k")]
+    Q5K,
+    #[value(name = "q6_k")]
+    Q6K,
+    /// Not a k-quant, blocks of 32 values, nearly lossless.
+    #[value(name = "q8_0")]
+    Q8_0,
+}
+
+impl QuantType {
+    fn ggml_dtype(&self) -> GgmlDType {
+        match self {
+            QuantType::Q2K => GgmlDType::Q2K,
+            QuantType::Q3K => GgmlDType::Q3K,
+            QuantType::Q4K => GgmlDType::Q4K,
+            QuantType::Q5K => GgmlDType::Q5K,
+            QuantType::Q6K => GgmlDType::Q6K,
+            QuantType::Q8_0 => GgmlDType::Q8_0,
+        }
+    }
+
+    /// The number of values quantized together, the last dimension of a quantized tensor must be a multiple of it.
+    fn block_size(&self) -> usize {
+        self.ggml_dtype().block_size()
+    }
+
+    fn quantize(&self, tensor: &Tensor) -> candle_core::Result<QTensor> {
+        QTensor::quantize(tensor, self.ggml_dtype())
+    }
+
+    /// The `general.file_type` of `llama.cpp` (`llama_ftype`) for a file quantized to this type only, the "small"
+    /// variants of the k-quant mixes.
+    fn file_type(&self) -> u32 {
+        match self {
+            QuantType::Q8_0 => 7,
+            QuantType::Q2K => 10,
+            QuantType::Q3K => 11,
+            QuantType::Q4K => 14,
+            QuantType::Q5K => 16,
+            QuantType::Q6K => 18,
+        }
+    }
+}
+
+pub struct QuantizeOptions {
+    /// A GGUF file, a `.safetensors` file, a `model.safetensors.index.json`, or a directory with one of them.
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub quant_type: QuantType,
+    /// Tensors whose GGUF name contains one of these are kept in f32, e.g. `token_embd` for the embeddings.
+    pub skip: Vec<String>,
+}
+
+/// What `quantize` wrote.
+#[derive(Debug, Default, PartialEq)]
+pub struct QuantizeStats {
+    pub tensors: usize,
+    /// The tensors quantized to `quant_type`, the others are kept in f32.
+    pub quantized: usize,
+    /// The size of the output file, in bytes.
+    pub size: u64,
+}
+
+/// Quantizes `options.input` to `options.output`.
+pub fn quantize(options: &QuantizeOptions) -> Result<QuantizeStats> {
+    let input = resolve_input(&options.input)?;
+    let (mut metadata, tensors) = if input
+        .file_name()
+        .map(|name| safetensors::is_safetensors(&name.to_string_lossy()))
+        .unwrap_or(false)
+    {
+        from_safetensors(&input, options)?
+    } else {
+        from_gguf(&input, options)?
+    };
+
+    // the file is no longer an f16 or f32 file
+    metadata.retain(|(key, _)| key != "general.file_type" && key != "general.quantization_version");
+    metadata.push((
+        "general.file_type".to_string(),
+        gguf_file::Value::U32(options.quant_type.file_type()),
+    ));
+    // the layout of the k-quant blocks, 2 since ggml switched to the current one
+    metadata.push((
+        "general.quantization_version".to_string(),
+        gguf_file::Value::U32(2),
+    ));
+    metadata.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let metadata: Vec<(&str, &gguf_file::Value)> = metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value))
+        .collect();
+    let tensor_refs: Vec<(&str, &QTensor)> = tensors
+        .iter()
+        .map(|(name, tensor)| (name.as_str(), tensor))
+        .collect();
+    let file = std::fs::File::create(&options.output)
+        .with_context(|| format!("Failed to create {}", options.output.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    gguf_file::write(&mut writer, &metadata, &tensor_refs)
+        .with_context(|| format!("Failed to write {}", options.output.display()))?;
+    drop(writer);
+
+    Ok(QuantizeStats {
+        tensors: tensors.len(),
+        quantized: tensors
+            .iter()
+            .filter(|(_, tensor)| tensor.dtype() != GgmlDType::F32)
+            .count(),
+        size: std::fs::metadata(&options.output)?.len(),
+    })
+}
+
+/// The weights of a directory, the index of a sharded checkpoint first.
+fn resolve_input(input: &Path) -> Result<PathBuf> {
+    if !input.is_dir() {
+        if !input.is_file() {
+            return Err(anyhow!("{} not found", input.display()));
+        }
+        return Ok(input.to_path_buf());
+    }
+    ["model.safetensors.index.json", "model.safetensors"]
+        .iter()
+        .map(|file_name| input.join(file_name))
+        .find(|path| path.is_file())
+        .with_context(|| {
+            format!(
+                "{} has neither model.safetensors nor model.safetensors.index.json",
+                input.display()
+            )
+        })
+}
+
+/// Quantizes `tensor` unless it's a vector, it's skipped, or its rows can't be split in blocks.
+fn quantize_tensor(name: &str, tensor: &Tensor, options: &QuantizeOptions) -> Result<QTensor> {
+    let tensor = tensor.to_dtype(DType::F32)?;
+    let is_skipped = options.skip.iter().any(|skip| name.contains(skip.as_str()));
+    let fits_blocks = tensor.rank() >= 2
+        && tensor.dims().last().copied().unwrap_or(0) % options.quant_type.block_size() == 0;
+    let qtensor = if is_skipped || !fits_blocks {
+        QTensor::quantize(&tensor, GgmlDType::F32)?
+    } else {
+        options.quant_type.quantize(&tensor)?
+    };
+    Ok(qtensor)
+}
+
+type Converted = (Vec<(String, gguf_file::Value)>, Vec<(String, QTensor)>);
+
+/// Requantizes an f16 or f32 GGUF file, the metadata and the names are kept as is.
+fn from_gguf(input: &Path, options: &QuantizeOptions) -> Result<Converted> {
+    let mut file = std::fs::File::open(input)
+        .with_context(|| format!("Failed to open {}", input.display()))?;
+    let content = gguf_file::Content::read(&mut file)
+        .with_context(|| format!("{} is not a GGUF file", input.display()))?;
+    let mut names: Vec<&String> = content.tensor_infos.keys().collect();
+    names.sort();
+    let mut tensors = Vec::with_capacity(names.len());
+    for name in names {
+        let dtype = content.tensor_infos[name].ggml_dtype;
+        if dtype != GgmlDType::F32 && dtype != GgmlDType::F16 {
+            return Err(anyhow!(
+                "{} of {} is already quantized ({:?}), quantize from an f16 or f32 file instead",
+                name,
+                input.display(),
+                dtype
+            ));
+        }
+        let tensor = content
+            .tensor(&mut file, name, &Device::Cpu)?
+            .dequantize(&Device::Cpu)?;
+        tensors.push((name.clone(), quantize_tensor(name, &tensor, options)?));
+    }
+    let metadata = content.metadata.into_iter().collect();
+    Ok((metadata, tensors))
+}
+
+/// The hyperparameters of `config.json` stored in GGUF metadata.
+#[derive(Deserialize)]
+struct LlamaConfig {
+    model_type: String,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    rms_norm_eps: f64,
+    rope_theta: Option<f32>,
+    max_position_embeddings: Option<usize>,
+    #[serde(default)]
+    tie_word_embeddings: bool,
+}
+
+/// Converts a Llama or Mistral checkpoint of `transformers`, one shard at a time so that only one shard of f32
+/// weights is in memory.
+fn from_safetensors(input: &Path, options: &QuantizeOptions) -> Result<Converted> {
+    let config_path = input.with_file_name(safetensors::CONFIG);
+    let content = std::fs::read_to_string(&config_path)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    let config: LlamaConfig = serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse {}", config_path.display()))?;
+    if config.model_type != "llama" && config.model_type != "mistral" {
+        return Err(anyhow!(
+            "model_type {:?} can't be converted to GGUF, only \"llama\" and \"mistral\" can, quantize a GGUF file \
+             converted by llama.cpp instead",
+            config.model_type
+        ));
+    }
+    let head_count = config.num_attention_heads;
+    let head_count_kv = config.num_key_value_heads.unwrap_or(head_count);
+
+    let shard_paths = if input.to_string_lossy().ends_with(".index.json") {
+        safetensors::shard_file_names(input)?
+            .iter()
+            .map(|shard| input.with_file_name(shard))
+            .collect()
+    } else {
+        vec![input.to_path_buf()]
+    };
+    let mut tensors = vec![];
+    for shard_path in shard_paths {
+        let shard = candle_core::safetensors::load(&shard_path, &Device::Cpu)
+            .with_context(|| format!("Failed to read {}", shard_path.display()))?;
+        let mut names: Vec<&String> = shard.keys().collect();
+        names.sort();
+        for name in names {
+            let Some(gguf_name) = gguf_name(name)? else {
+                continue;
+            };
+            let tensor = shard[name].to_dtype(DType::F32)?;
+            let tensor = if gguf_name.ends_with("attn_q.weight") {
+                permute_for_rope(&tensor, head_count)?
+            } else if gguf_name.ends_with("attn_k.weight") {
+                permute_for_rope(&tensor, head_count_kv)?
+            } else {
+                tensor
+            };
+            // tied embeddings are also the output layer, GGUF models expect both
+            if gguf_name == "token_embd.weight" && config.tie_word_embeddings {
+                let output = quantize_tensor("output.weight", &tensor, options)?;
+                tensors.push(("output.weight".to_string(), output));
+            }
+            let qtensor = quantize_tensor(&gguf_name, &tensor, options)?;
+            tensors.push((gguf_name, qtensor));
+        }
+    }
+
+    let name = input
+        .parent()
+        .and_then(|directory| directory.file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut metadata = vec![
+        (
+            "general.architecture",
+            gguf_file::Value::String("llama".to_string()),
+        ),
+        ("general.name", gguf_file::Value::String(name)),
+        (
+            "llama.context_length",
+            gguf_file::Value::U32(config.max_position_embeddings.unwrap_or(4096) as u32),
+        ),
+        (
+            "llama.embedding_length",
+            gguf_file::Value::U32(config.hidden_size as u32),
+        ),
+        (
+            "llama.block_count",
+            gguf_file::Value::U32(config.num_hidden_layers as u32),
+        ),
+        (
+            "llama.feed_forward_length",
+            gguf_file::Value::U32(config.intermediate_size as u32),
+        ),
+        (
+            "llama.attention.head_count",
+            gguf_file::Value::U32(head_count as u32),
+        ),
+        (
+            "llama.attention.head_count_kv",
+            gguf_file::Value::U32(head_count_kv as u32),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(config.rms_norm_eps as f32),
+        ),
+        (
+            "llama.rope.dimension_count",
+            gguf_file::Value::U32((config.hidden_size / head_count) as u32),
+        ),
+        (
+            "llama.rope.freq_base",
+            gguf_file::Value::F32(config.rope_theta.unwrap_or(10000.0)),
+        ),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect::<Vec<_>>();
+
+    let tokenizer_json = input.with_file_name("tokenizer.json");
+    if tokenizer_json.is_file() {
+        let tokenizer_config = input.with_file_name("tokenizer_config.json");
+        let tokenizer_config = tokenizer_config.is_file().then_some(tokenizer_config);
+        metadata.extend(gguf_tokenizer::to_metadata(
+            &tokenizer_json,
+            tokenizer_config.as_deref(),
+        )?);
+    } else {
+        warn!(
+            "no tokenizer.json next to {}, the GGUF file will need --tokenizer-path or --tokenizer-repo-id",
+            input.display()
+        );
+    }
+    Ok((metadata, tensors))
+}
+
+/// The GGUF name of a tensor of `transformers`, `None` for the buffers that are not weights.
+fn gguf_name(name: &str) -> Result<Option<String>> {
+    let gguf_name = match name {
+        "model.embed_tokens.weight" => "token_embd.weight".to_string(),
+        "model.norm.weight" => "output_norm.weight".to_string(),
+        "lm_head.weight" => "output.weight".to_string(),
+        _ if name.ends_with("rotary_emb.inv_freq") => return Ok(None),
+        _ => {
+            let layer = name
+                .strip_prefix("model.layers.")
+                .and_then(|rest| rest.split_once('.'));
+            let (index, rest) = layer.with_context(|| format!("unexpected tensor {}", name))?;
+            let gguf_rest = match rest {
+                "self_attn.q_proj.weight" => "attn_q.weight",
+                "self_attn.k_proj.weight" => "attn_k.weight",
+                "self_attn.v_proj.weight" => "attn_v.weight",
+                "self_attn.o_proj.weight" => "attn_output.weight",
+                "mlp.gate_proj.weight" => "ffn_gate.weight",
+                "mlp.up_proj.weight" => "ffn_up.weight",
+                "mlp.down_proj.weight" => "ffn_down.weight",
+                "input_layernorm.weight" => "attn_norm.weight",
+                "post_attention_layernorm.weight" => "ffn_norm.weight",
+                _ => return Err(anyhow!("unexpected tensor {}", name)),
+            };
+            format!("blk.{}.{}", index, gguf_rest)
+        }
+    };
+    Ok(Some(gguf_name))
+}
+
+/// Reorders the rows of each head from `[first half, second half]` to interleaved pairs, like `permute` in the
+/// `convert_hf_to_gguf.py` script of `llama.cpp`.
+fn permute_for_rope(weight: &Tensor, head_count: usize) -> Result<Tensor> {
+    let (rows, columns) = weight.dims2()?;
+    let permuted = weight
+        .reshape((head_count, 2, rows / head_count / 2, columns))?
+        .transpose(1, 2)?
+        .contiguous()?
+        .reshape((rows, columns))?;
+    Ok(permuted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::models;
+
+    const HIDDEN_SIZE: usize = 256;
+    const VOCAB_SIZE: usize = 9;
+
+    /// A randomly initialized two-layer Llama checkpoint of `transformers`, with its config and tokenizer.
+    fn tiny_checkpoint(directory: &Path) -> HashMap<String, Tensor> {
+        std::fs::create_dir_all(directory).unwrap();
+        let matrix = |rows: usize, columns: usize| {
+            Tensor::randn(0f32, 0.02, (rows, columns), &Device::Cpu).unwrap()
+        };
+        let norm = || Tensor::ones(HIDDEN_SIZE, DType::F32, &Device::Cpu).unwrap();
+        let mut tensors = HashMap::from([
+            (
+                "model.embed_tokens.weight".to_string(),
+                matrix(VOCAB_SIZE, HIDDEN_SIZE),
+            ),
+            ("model.norm.weight".to_string(), norm()),
+            (
+                "lm_head.weight".to_string(),
+                matrix(VOCAB_SIZE, HIDDEN_SIZE),
+            ),
+        ]);
+        for layer in 0..2 {
+            let prefix = format!("model.layers.{}", layer);
+            for (name, tensor) in [
+                ("self_attn.q_proj.weight", matrix(HIDDEN_SIZE, HIDDEN_SIZE)),
+                (
+                    "self_attn.k_proj.weight",
+                    matrix(HIDDEN_SIZE / 2, HIDDEN_SIZE),
+                ),
+                (
+                    "self_attn.v_proj.weight",
+                    matrix(HIDDEN_SIZE / 2, HIDDEN_SIZE),
+                ),
+                ("self_attn.o_proj.weight", matrix(HIDDEN_SIZE, HIDDEN_SIZE)),
+                ("mlp.gate_proj.weight", matrix(2 * HIDDEN_SIZE, HIDDEN_SIZE)),
+                ("mlp.up_proj.weight", matrix(2 * HIDDEN_SIZE, HIDDEN_SIZE)),
+                ("mlp.down_proj.weight", matrix(HIDDEN_SIZE, 2 * HIDDEN_SIZE)),
+                ("input_layernorm.weight", norm()),
+                ("post_attention_layernorm.weight", norm()),
+            ] {
+                tensors.insert(format!("{}.{}", prefix, name), tensor);
+            }
+        }
+        candle_core::safetensors::save(&tensors, directory.join("model.safetensors")).unwrap();
+        std::fs::write(
+            directory.join("config.json"),
+            format!(
+                r#"{{
+                    "model_type": "llama",
+                    "hidden_size": {},
+                    "intermediate_size": {},
+                    "num_hidden_layers": 2,
+                    "num_attention_heads": 4,
+                    "num_key_value_heads": 2,
+                    "rms_norm_eps": 1e-5,
+                    "vocab_size": {}
+                }}"#,
+                HIDDEN_SIZE,
+                2 * HIDDEN_SIZE,
+                VOCAB_SIZE
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("tokenizer.json"),
+            r#"{
+                "added_tokens": [
+                    {"id": 0, "content": "<unk>", "special": true},
+                    {"id": 1, "content": "<s>", "special": true},
+                    {"id": 2, "content": "</s>", "special": true}
+                ],
+                "model": {
+                    "type": "BPE",
+                    "unk_token": "<unk>",
+                    "byte_fallback": true,
+                    "vocab": {"<unk>": 0, "<s>": 1, "</s>": 2, "▁": 3, "h": 4, "i": 5, "hi": 6, "▁hi": 7, "<0x21>": 8},
+                    "merges": ["h i", "▁ hi"]
+                }
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("tokenizer_config.json"),
+            r#"{"bos_token": "<s>", "eos_token": "</s>", "add_bos_token": true}"#,
+        )
+        .unwrap();
+        tensors
+    }
+
+    fn mean_abs(tensor: &Tensor) -> f32 {
+        tensor
+            .abs()
+            .unwrap()
+            .mean_all()
+            .unwrap()
+            .to_scalar::<f32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn roundtrips_a_safetensors_checkpoint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let directory = dir.path().to_path_buf();
+        let original = tiny_checkpoint(&directory);
+        let output = directory.join("model.Q4_K.gguf");
+        let stats = quantize(&QuantizeOptions {
+            input: directory.clone(),
+            output: output.clone(),
+            quant_type: QuantType::Q4K,
+            skip: vec!["token_embd".to_string()],
+        })?;
+        // 3 + 9 per layer, the 7 matrices of each layer and the output layer are quantized
+        assert_eq!(stats.tensors, 21);
+        assert_eq!(stats.quantized, 15);
+
+        let mut file = std::fs::File::open(&output)?;
+        let content = gguf_file::Content::read(&mut file)?;
+        assert_eq!(models::architecture(&content)?, "llama");
+        assert_eq!(content.metadata["llama.block_count"].to_u32()?, 2);
+        assert_eq!(
+            content.metadata["llama.attention.head_count_kv"].to_u32()?,
+            2
+        );
+        assert_eq!(content.metadata["general.file_type"].to_u32()?, 14);
+        let dtype = |name: &str| content.tensor_infos[name].ggml_dtype;
+        assert_eq!(dtype("blk.0.ffn_up.weight"), GgmlDType::Q4K);
+        assert_eq!(dtype("output.weight"), GgmlDType::Q4K);
+        assert_eq!(dtype("blk.1.attn_norm.weight"), GgmlDType::F32);
+        assert_eq!(dtype("token_embd.weight"), GgmlDType::F32);
+
+        // the skipped embeddings are exact, the quantized weights are close
+        let tensor = |name: &str| -> Result<Tensor> {
+            let mut file = std::fs::File::open(&output)?;
+            Ok(content
+                .tensor(&mut file, name, &Device::Cpu)?
+                .dequantize(&Device::Cpu)?)
+        };
+        let embeddings = &original["model.embed_tokens.weight"];
+        assert_eq!(mean_abs(&(tensor("token_embd.weight")? - embeddings)?), 0.0);
+        let up = &original["model.layers.0.mlp.up_proj.weight"];
+        assert!(mean_abs(&(tensor("blk.0.ffn_up.weight")? - up)?) < 0.2 * mean_abs(up));
+        let k = permute_for_rope(&original["model.layers.1.self_attn.k_proj.weight"], 2)?;
+        assert!(mean_abs(&(tensor("blk.1.attn_k.weight")? - &k)?) < 0.2 * mean_abs(&k));
+
+        // the file runs with the embedded tokenizer
+        let tokenizer = gguf_tokenizer::from_gguf(&content)?;
+        let ids = tokenizer
+            .encode("hi hi!", true)
+            .map_err(anyhow::Error::msg)?;
+        assert_eq!(ids.get_ids(), &[1, 7, 7, 8]);
+        let mut model = models::from_gguf(content, &mut file)?;
+        let logits = model.forward(&Tensor::new(ids.get_ids(), &Device::Cpu)?.unsqueeze(0)?, 0)?;
+        assert_eq!(logits.dims(), &[1, VOCAB_SIZE]);
+        Ok(())
+    }
+
+    #[test]
+    fn requantizes_gguf_and_keeps_its_metadata() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let directory = dir.path().to_path_buf();
+        let input = directory.join("model.f32.gguf");
+        let weight = QTensor::quantize(
+            &Tensor::randn(0f32, 0.02, (4, HIDDEN_SIZE), &Device::Cpu)?,
+            GgmlDType::F32,
+        )?;
+        let name = gguf_file::Value::String("fine-tuned".to_string());
+        let file_type = gguf_file::Value::U32(0);
+        let mut file = std::fs::File::create(&input)?;
+        gguf_file::write(
+            &mut file,
+            &[("general.name", &name), ("general.file_type", &file_type)],
+            &[("blk.0.ffn_up.weight", &weight)],
+        )?;
+
+        let output = directory.join("model.Q8_0.gguf");
+        let options = QuantizeOptions {
+            input: input.clone(),
+            output: output.clone(),
+            quant_type: QuantType::Q8_0,
+            skip: vec![],
+        };
+        assert_eq!(quantize(&options)?.quantized, 1);
+        let content = gguf_file::Content::read(&mut std::fs::File::open(&output)?)?;
+        assert_eq!(content.metadata["general.name"].to_string()?, "fine-tuned");
+        assert_eq!(content.metadata["general.file_type"].to_u32()?, 7);
+        assert_eq!(
+            content.tensor_infos["blk.0.ffn_up.weight"].ggml_dtype,
+            GgmlDType::Q8_0
+        );
+
+        // quantizing twice loses more than needed
+        let error = quantize(&QuantizeOptions {
+            input: output,
+            output: directory.join("model.Q4_K.gguf"),
+            ..options
+        })
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("already quantized"));
+        Ok(())
+    }
+
+    #[test]
+    fn maps_tensor_names() {
+        assert_eq!(
+            gguf_name("model.layers.12.self_attn.o_proj.weight").unwrap(),
+            Some("blk.12.attn_output.weight".to_string())
+        );
+        assert_eq!(
+            gguf_name("model.layers.0.self_attn.rotary_emb.inv_freq").unwrap(),
+            None
+        );
+        assert!(gguf_name("model.layers.0.mlp.experts.0.weight").is_err());
+    }
+}
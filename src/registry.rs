@@ -0,0 +1,190 @@
+//! The model registry lets `ox serve` load several models at once, e.g. a small code model for inline completions
+//! and an instruct model for chat, each one with its own scheduler. Requests are routed by the `model` field or the
+//! `/v1/engines/:engine` path segment.
+//!
+//! ```toml
+//! # the model used when a request doesn't name one, default to the first model
+//! default = "chat"
+//!
+//! [[models]]
+//! name = "chat"
+//! model_repo_id = "TheBloke/Mistral-7B-Instruct-v0.2-GGUF"
+//! model_file_name = "mistral-7b-instruct-v0.2.Q4_K_M.gguf"
+//! template = "mistral"
+//!
+//! [[models]]
+//! name = "code"
+//! # Copilot asks for the `copilot-codex` engine, `"*"` would match any name
+//! aliases = ["copilot-codex"]
+//! tokenizer_repo_id = "deepseek-ai/deepseek-coder-1.3b-base"
+//! model_repo_id = "TheBloke/deepseek-coder-1.3b-base-GGUF"
+//! model_file_name = "deepseek-coder-1.3b-base.Q4_K_M.gguf"
+//! template = "raw"
+//! fim_template = "deep-seek"
+//!
+//! [[models]]
+//! name = "local"
+//! # local files are loaded without accessing the hub
+//! tokenizer_path = "/models/tokenizer.json"
+//! model_path = "/models/model.Q4_K_M.gguf"
+//! ```
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::utils::fim::FimTemplate;
+use crate::utils::template::PromptTemplate;
+
+#[derive(Deserialize, Debug)]
+pub struct Registry {
+    /// The name of the model used when a request doesn't name one.
+    pub default: Option<String>,
+    pub models: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ModelEntry {
+    /// The name clients use in the `model` field, e.g. "code".
+    pub name: String,
+    /// Other names routed to this model, `"*"` matches any name.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// Default to `--tokenizer-repo-id`, then to the tokenizer embedded in the GGUF file.
+    pub tokenizer_repo_id: Option<String>,
+    /// Default to `--tokenizer-file`.
+    pub tokenizer_file: Option<String>,
+    /// A local tokenizer file, used instead of `tokenizer_repo_id`.
+    pub tokenizer_path: Option<PathBuf>,
+    #[serde(default)]
+    pub model_repo_id: String,
+    pub model_repo_revision: Option<String>,
+    #[serde(default)]
+    pub model_file_name: String,
+    /// A local GGUF file, used instead of `model_repo_id` and `model_file_name`.
+    pub model_path: Option<PathBuf>,
+    /// How chat messages and instructions are turned into a prompt.
+    #[serde(default)]
+    pub template: PromptTemplate,
+    /// Default to `--fim-template`.
+    pub fim_template: Option<FimTemplate>,
+    /// More tokens ending the generations, e.g. `["<|im_end|>"]`, see `LLMBuilder::eos_tokens`.
+    #[serde(default)]
+    pub eos_tokens: Vec<String>,
+}
+
+impl Registry {
+    /// Reads and validates a registry from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read model registry {}", path.display()))?;
+        let registry: Registry = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse model registry {}", path.display()))?;
+        registry.validate()?;
+        Ok(registry)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.models.is_empty() {
+            return Err(anyhow!("the model registry has no models"));
+        }
+        let mut names = HashSet::new();
+        for entry in &self.models {
+            if !names.insert(entry.name.as_str()) {
+                return Err(anyhow!("model {:?} is registered twice", entry.name));
+            }
+            if entry.model_path.is_none()
+                && (entry.model_repo_id.is_empty() || entry.model_file_name.is_empty())
+            {
+                return Err(anyhow!(
+                    "model {:?} needs either model_path, or model_repo_id and model_file_name",
+                    entry.name
+                ));
+            }
+        }
+        if let Some(default) = &self.default {
+            if !names.contains(default.as_str()) {
+                return Err(anyhow!("the default model {:?} is not registered", default));
+            }
+        }
+        Ok(())
+    }
+
+    /// The models with the default one first.
+    pub fn into_models(self) -> Vec<ModelEntry> {
+        let mut models = self.models;
+        if let Some(default) = self.default {
+            if let Some(index) = models.iter().position(|entry| entry.name == default) {
+                let entry = models.remove(index);
+                models.insert(0, entry);
+            }
+        }
+        models
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGISTRY: &str = r#"
+        default = "chat"
+
+        [[models]]
+        name = "code"
+        aliases = ["copilot-codex"]
+        model_repo_id = "TheBloke/deepseek-coder-1.3b-base-GGUF"
+        model_file_name = "deepseek-coder-1.3b-base.Q4_K_M.gguf"
+        template = "raw"
+        fim_template = "deep-seek"
+
+        [[models]]
+        name = "chat"
+        model_repo_id = "TheBloke/Mistral-7B-Instruct-v0.2-GGUF"
+        model_file_name = "mistral-7b-instruct-v0.2.Q4_K_M.gguf"
+    "#;
+
+    #[test]
+    fn default_model_comes_first() {
+        let registry: Registry = toml::from_str(REGISTRY).unwrap();
+        registry.validate().unwrap();
+        let models = registry.into_models();
+        assert_eq!(models[0].name, "chat");
+        assert_eq!(models[0].template, PromptTemplate::Mistral);
+        assert_eq!(models[1].aliases, vec!["copilot-codex"]);
+        assert_eq!(models[1].template, PromptTemplate::Raw);
+        assert_eq!(models[1].fim_template, Some(FimTemplate::DeepSeek));
+    }
+
+    #[test]
+    fn model_needs_repo_or_path() {
+        let registry: Registry = toml::from_str(
+            r#"
+            [[models]]
+            name = "local"
+            model_file_name = "model.gguf"
+        "#,
+        )
+        .unwrap();
+        assert!(registry.validate().is_err());
+
+        let registry: Registry = toml::from_str(
+            r#"
+            [[models]]
+            name = "local"
+            model_path = "/models/model.gguf"
+        "#,
+        )
+        .unwrap();
+        assert!(registry.validate().is_ok());
+    }
+
+    #[test]
+    fn unknown_default_is_rejected() {
+        let registry: Registry =
+            toml::from_str(&REGISTRY.replace("default = \"chat\"", "default = \"gpt\"")).unwrap();
+        assert!(registry.validate().is_err());
+    }
+}
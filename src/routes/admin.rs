@@ -0,0 +1,169 @@
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Path, State};
+use axum::http::{header, request::Parts, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use oxpilot::types::AdminLoadRequest;
+use serde_json::{json, Value};
+use std::time::Instant;
+use tracing::{error, info};
+
+use crate::state::{AppState, LoadStatus};
+
+// Admin endpoints to swap the served models at runtime, without restarting `ox serve` and dropping the requests in
+// flight. They are disabled unless `ox serve --admin-token` is set, and require `Authorization: Bearer <token>`.
+
+/// Only extracted when the request carries the admin token, otherwise the request is rejected before the handler
+/// runs.
+pub struct Admin;
+
+#[async_trait]
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(admin_token) = &state.admin_token else {
+            return Err(error_response(
+                StatusCode::FORBIDDEN,
+                "admin endpoints are disabled, start `ox serve` with `--admin-token`",
+            ));
+        };
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "));
+        match bearer {
+            Some(bearer) if bearer == admin_token => Ok(Admin),
+            _ => Err(error_response(
+                StatusCode::UNAUTHORIZED,
+                "missing or invalid admin token",
+            )),
+        }
+    }
+}
+
+/// `GET /admin/models` lists the served models, and the models being loaded or that failed to load.
+pub async fn models(_: Admin, State(state): State<AppState>) -> Json<Value> {
+    let mut models: Vec<Value> = state
+        .models()
+        .iter()
+        .enumerate()
+        .map(|(index, served)| {
+            json!({
+                "name": served.info.name,
+                "repo_id": served.info.repo_id,
+                "file_name": served.info.file_name,
+                "default": index == 0,
+                "status": "ready",
+            })
+        })
+        .collect();
+    for (name, status) in state.loads.lock().unwrap().iter() {
+        let mut model = serde_json::to_value(status).unwrap();
+        model["name"] = json!(name);
+        models.push(model);
+    }
+    Json(json!({ "models": models }))
+}
+
+/// `POST /admin/models` builds a model in the background, the model is served once it's ready, replacing the model
+/// of the same name (or `replace`) atomically, the replaced model finishes its requests in flight then is unloaded.
+pub async fn load(
+    _: Admin,
+    State(state): State<AppState>,
+    Json(body): Json<AdminLoadRequest>,
+) -> Response {
+    let name = body.entry.name.clone();
+    let replace = body.replace.unwrap_or(name.clone());
+    {
+        let mut loads = state.loads.lock().unwrap();
+        if let Some(LoadStatus::Loading) = loads.get(&name) {
+            return error_response(
+                StatusCode::CONFLICT,
+                &format!("model '{}' is already loading", name),
+            );
+        }
+        loads.insert(name.clone(), LoadStatus::Loading);
+    }
+    info!("loading model {} to replace {}", name, replace);
+
+    let entry = body.entry;
+    let task_state = state.clone();
+    let task_name = name.clone();
+    tokio::spawn(async move {
+        let started_at = Instant::now();
+        let loader = task_state.loader.clone();
+        let handle = tokio::runtime::Handle::current();
+        // the weights are read synchronously, a blocking thread keeps the other requests flowing in the meantime
+        let loaded =
+            tokio::task::spawn_blocking(move || handle.block_on(loader.spawn(entry, false, true)))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|loaded| loaded);
+        match loaded {
+            Ok(served) => {
+                // swap before clearing the status, so that the model is always listed
+                let replaced = task_state.swap(served, &replace);
+                task_state.loads.lock().unwrap().remove(&task_name);
+                info!(
+                    "model {} loaded in {:.2}s",
+                    task_name,
+                    started_at.elapsed().as_secs_f64()
+                );
+                if let Some(replaced) = replaced {
+                    info!(
+                        "draining and unloading model {} ({})",
+                        replaced.info.name, replaced.info.file_name
+                    );
+                }
+            }
+            Err(error) => {
+                error!("failed to load model {}: {:#}", task_name, error);
+                task_state.loads.lock().unwrap().insert(
+                    task_name,
+                    LoadStatus::Failed {
+                        error: format!("{:#}", error),
+                    },
+                );
+            }
+        }
+    });
+
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "name": name, "status": "loading" })),
+    )
+        .into_response()
+}
+
+/// `DELETE /admin/models/:name` stops routing requests to a model, it finishes its requests in flight then is
+/// unloaded.
+pub async fn unload(_: Admin, State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    // a failed load is forgotten as well, a load in progress can't be cancelled
+    let failed = {
+        let mut loads = state.loads.lock().unwrap();
+        match loads.get(&name) {
+            Some(LoadStatus::Failed { .. }) => loads.remove(&name).is_some(),
+            _ => false,
+        }
+    };
+    match state.remove(&name) {
+        Ok(removed) => {
+            info!(
+                "draining and unloading model {} ({})",
+                removed.info.name, removed.info.file_name
+            );
+            Json(json!({ "name": name, "status": "unloading" })).into_response()
+        }
+        Err(_) if failed => Json(json!({ "name": name, "status": "removed" })).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
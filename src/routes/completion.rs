@@ -1,47 +1,92 @@
 use async_stream::stream;
-use axum::extract::State;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
 use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::Json;
-use futures::stream::Stream;
-use oxpilot::cmd::Command::Prompt;
+use oxpilot::cmd::{Command::Prompt, Priority};
 use oxpilot::types::{Choice, Completion, CompletionRequest, Usage};
 use serde_json::{json, to_string};
 use std::convert::Infallible;
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc;
-use tracing::info;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
 
+use crate::routes::Client;
+use crate::shutdown::Cancelled;
 use crate::state::AppState;
 
 // Reference: https://github.com/tokio-rs/axum/blob/main/examples/sse/src/main.rs
 pub async fn completion(
     State(state): State<AppState>,
+    // `/v1/engines/:engine/completions` names the model in the path, `/v1/completions` in the body
+    engine: Option<Path<String>>,
+    // identifies the client, a new completion of the same client cancels its previous one
+    client: Client,
     // `Json<T>` will automatically deserialize the request body to a type `T` as JSON.
     Json(body): Json<CompletionRequest>,
-) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
-    // `stream!` is a macro from [`async_stream`](https://docs.rs/async-stream/0.3.5/async_stream/index.html)
-    // that makes it easy to create a `futures::stream::Stream` from a generator.
-    Sse::new(stream! {
-        let prompt = body.prompt.unwrap_or("".to_string());
-        // the `tx` is a `tokio::sync::mpsc::Sender` that was created in `main.rs`.
-        // we can use the `tx` to send a `Command::Prompt` to the manager task.
-        let tx = state.tx.clone();
-        let (responder, mut receiver) = mpsc::channel(8);
+) -> Response {
+    let requested = body.model.clone().or(engine.map(|Path(engine)| engine));
+    // the model is looked up before the prompt is sent, so that an unknown model is answered with a 404
+    let tx = match state.model(requested.as_deref()) {
+        Ok(model) => model.tx,
+        Err(not_found) => return not_found.into_response(),
+    };
+    let prompt = body.prompt.clone().unwrap_or("".to_string());
+    // the `tx` is a `tokio::sync::mpsc::Sender` that was created in `main.rs`.
+    // we can use the `tx` to send a `Command::Prompt` to the manager task of the requested model.
+    let (responder, mut receiver) = mpsc::channel(8);
+    let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
 
-        // send the `Command::Prompt` to the manager task with responder
-        tx.send(Prompt {
+    // send the `Command::Prompt` to the manager task with responder
+    if let Err(error) = tx
+        .send(Prompt {
             prompt,
             responder,
-            temperature: body.temperature.unwrap_or(1.0),
+            temperature: body.temperature,
+            seed: None,
+            top_p: None,
             max_sampled: body.max_tokens.unwrap_or(4096),
-        }).await.unwrap();
+            decoding: state.decoding,
+            // Copilot completions are useless unless they arrive before the next keystroke
+            priority: body.priority.unwrap_or(Priority::Interactive),
+            session: client.session(body.user.as_deref()),
+            prompt_tokens: Some(prompt_tokens),
+        })
+        .await
+    {
+        error!("failed to send prompt to LLM manager: {}", error);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "LLM manager is not running",
+        )
+            .into_response();
+    }
+    // e.g. 413 when the prompt is longer than the context of the model
+    if let Err(response) = super::prompt_tokens(prompt_tokens_receiver).await {
+        return response;
+    }
 
+    // `stream!` is a macro from [`async_stream`](https://docs.rs/async-stream/0.3.5/async_stream/index.html)
+    // that makes it easy to create a `futures::stream::Stream` from a generator.
+    Sse::new(stream! {
         // the manager task will send the completion back to us via the `responder`.
-        // the receiver will receive the generated `text` from the `responder`.
-        while let Some(text) = receiver.recv().await {
+        // the receiver will receive the generated `text` from the `responder`, until the server shuts down.
+        let mut generation = state.shutdown.generation();
+        loop {
+          let text = match generation.recv(&mut receiver).await {
+            Ok(Some(text)) => text,
+            Ok(None) => break,
+            Err(Cancelled) => {
+              // tell the client why the completion stops short, then end the stream the OpenAI way
+              yield Ok(SseEvent::default().data(json!({ "error": { "message": "server is shutting down", "type": "server_error" } }).to_string()));
+              yield Ok(SseEvent::default().data("[DONE]"));
+              break;
+            }
+          };
           info!("Received completion: {}", text);
           // Let's create one instance of `SseEvent` with the generated `text`, and respond to the SSE client.
-          yield Ok(
+          yield Ok::<_, Infallible>(
             // Create a new `SseEvent` with the default settings.
             // `SseEvent::default().data("Hello, World!")` will return `data: Hello, World!` as the event text chuck.
             SseEvent::default().data(
@@ -56,7 +101,7 @@ pub async fn completion(
                         .duration_since(UNIX_EPOCH)
                         .unwrap()
                         .as_secs(),
-                    model: body.model.clone().unwrap_or("unknown".to_string()),
+                    model: requested.clone().unwrap_or("unknown".to_string()),
                     choices: vec![Choice {
                         text: text.to_string(),
                         index: 0,
@@ -76,4 +121,5 @@ pub async fn completion(
         }
     })
     .keep_alive(KeepAlive::default())
+    .into_response()
 }
@@ -0,0 +1,97 @@
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{header, HeaderMap};
+use axum::Json;
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+use crate::state::AppState;
+
+// Local stand-ins for the auxiliary endpoints that stock Copilot extensions call besides the completions, so that
+// only the base URL needs overriding in the editor.
+
+/// How long the fake Copilot token is valid, the extension refreshes it after `refresh_in` seconds.
+const TOKEN_TTL_SECS: u64 = 30 * 60;
+
+/// `GET /copilot_internal/v2/token` exchanges the GitHub OAuth token for a Copilot token.
+/// The token is never checked by `ox serve`, the `endpoints` point the extension back to this server.
+pub async fn token(headers: HeaderMap) -> Json<Value> {
+    let base_url = base_url(&headers);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    info!("issuing local copilot token, endpoints: {}", base_url);
+    Json(json!({
+        "token": format!("tid=oxpilot;exp={};sku=free_educational;proxy-ep={}", now + TOKEN_TTL_SECS, base_url),
+        "expires_at": now + TOKEN_TTL_SECS,
+        "refresh_in": TOKEN_TTL_SECS - 5 * 60,
+        "endpoints": {
+            "api": base_url,
+            "proxy": base_url,
+            "origin-tracker": base_url,
+            "telemetry": base_url,
+        },
+        "chat_enabled": false,
+        "annotations_enabled": false,
+        "telemetry": "disabled",
+        "public_suggestions": "disabled",
+    }))
+}
+
+/// `POST /telemetry` is a local sink for the telemetry events, they are logged instead of being sent to GitHub.
+/// Events are sent as JSON, either one object or newline-delimited objects.
+pub async fn telemetry(body: Bytes) -> Json<Value> {
+    let body = String::from_utf8_lossy(&body);
+    let mut received = 0;
+    for line in body.lines().filter(|line| !line.trim().is_empty()) {
+        received += 1;
+        match serde_json::from_str::<Value>(line) {
+            Ok(event) => debug!(
+                "telemetry event: {} {}",
+                event["data"]["baseData"]["name"]
+                    .as_str()
+                    .unwrap_or("unknown"),
+                event
+            ),
+            Err(_) => debug!("telemetry event: {}", line),
+        }
+    }
+    info!("received {} telemetry events", received);
+    Json(json!({ "itemsReceived": received, "itemsAccepted": received, "errors": [] }))
+}
+
+/// `GET /models` (and `GET /v1/models`) lists the models served by `ox serve`, the default model first.
+pub async fn models(State(state): State<AppState>) -> Json<Value> {
+    let data: Vec<Value> = state
+        .models()
+        .iter()
+        .map(|served| {
+            json!({
+                "id": served.info.name,
+                "object": "model",
+                "name": served.info.name,
+                "version": served.info.file_name,
+                "owned_by": served.info.repo_id,
+                "capabilities": {
+                    "type": "completion",
+                    "family": served.info.name,
+                },
+            })
+        })
+        .collect();
+    Json(json!({
+        "object": "list",
+        "data": data,
+    }))
+}
+
+/// The base url of this server as seen by the client, e.g. `http://localhost:9090`
+fn base_url(headers: &HeaderMap) -> String {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost:9090");
+    format!("http://{}", host)
+}
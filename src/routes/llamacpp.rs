@@ -0,0 +1,262 @@
+use async_stream::stream;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use oxpilot::cmd::{Command::Prompt, Priority};
+use oxpilot::types::{LlamaCppRequest, LlamaCppResponse, LlamaCppSummary, LlamaCppTimings};
+use oxpilot::utils::stop::StopSequences;
+use serde_json::{json, to_string, Value};
+use std::convert::Infallible;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+use crate::routes::Client;
+use crate::shutdown::Cancelled;
+use crate::state::AppState;
+
+// llama.cpp server compatible endpoints, used by `llama.vim` and a few VS Code extensions.
+// Reference: https://github.com/ggerganov/llama.cpp/blob/master/examples/server/README.md
+
+/// `POST /completion` continues the `prompt`.
+pub async fn completion(
+    State(state): State<AppState>,
+    Json(body): Json<LlamaCppRequest>,
+) -> Response {
+    let prompt = body.prompt.clone().unwrap_or_default();
+    let priority = body.priority.unwrap_or(Priority::Chat);
+    generate(state, prompt, priority, None, body).await
+}
+
+/// `POST /infill` fills in the code between `input_prefix` and `input_suffix`, the prompt is built with the
+/// fill-in-the-middle template of the default model, `ox serve --fim-template` unless the registry sets one.
+pub async fn infill(
+    State(state): State<AppState>,
+    client: Client,
+    Json(body): Json<LlamaCppRequest>,
+) -> Response {
+    // the extra chunks (e.g. other files of the project) go before the prefix so that the model sees them as context
+    let mut prefix: String = body
+        .input_extra
+        .iter()
+        .flatten()
+        .map(|chunk| match &chunk.filename {
+            Some(filename) => format!("{}\n{}\n\n", filename, chunk.text),
+            None => format!("{}\n\n", chunk.text),
+        })
+        .collect();
+    prefix.push_str(body.input_prefix.as_deref().unwrap_or_default());
+    let prompt = state
+        .default_model()
+        .fim_template
+        .format(&prefix, body.input_suffix.as_deref().unwrap_or_default());
+    // infill is used for inline completions, e.g. by `llama.vim`
+    let priority = body.priority.unwrap_or(Priority::Interactive);
+    // `llama.vim` requests an infill on every cursor move, only the latest one is useful
+    generate(state, prompt, priority, client.session(None), body).await
+}
+
+async fn generate(
+    state: AppState,
+    prompt: String,
+    priority: Priority,
+    session: Option<String>,
+    body: LlamaCppRequest,
+) -> Response {
+    let max_sampled = match body.n_predict {
+        Some(n_predict) if n_predict > 0 => n_predict as usize,
+        // `-1` (infinite) or not set
+        _ => 4096,
+    };
+    let started_at = Instant::now();
+    let (responder, mut receiver) = mpsc::channel(8);
+    let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
+    // llama.cpp serves a single model, requests go to the default model
+    let served = state.default_model();
+    if let Err(error) = served
+        .tx
+        .send(Prompt {
+            prompt,
+            responder,
+            temperature: body.temperature,
+            seed: body.seed,
+            top_p: body.top_p,
+            max_sampled,
+            decoding: state.decoding,
+            priority,
+            session,
+            prompt_tokens: Some(prompt_tokens),
+        })
+        .await
+    {
+        error!("failed to send prompt to LLM manager: {}", error);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "LLM manager is not running",
+        )
+            .into_response();
+    }
+    let prompt_tokens = match super::prompt_tokens(prompt_tokens_receiver).await {
+        Ok(prompt_tokens) => prompt_tokens,
+        Err(response) => return response,
+    };
+
+    let mut progress = Progress {
+        model: served.info.name.clone(),
+        max_sampled,
+        started_at,
+        first_token_at: None,
+        tokens_predicted: 0,
+        prompt_tokens,
+        stop: StopSequences::new(body.stop.clone().unwrap_or_default()),
+    };
+
+    let mut generation = state.shutdown.generation();
+    if !body.stream.unwrap_or(false) {
+        let mut content = String::new();
+        loop {
+            let text = match generation.recv(&mut receiver).await {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(Cancelled) => {
+                    return (StatusCode::SERVICE_UNAVAILABLE, Json(shutdown_error()))
+                        .into_response()
+                }
+            };
+            content.push_str(&progress.push(&text));
+            if progress.stop.is_stopped() {
+                break;
+            }
+        }
+        content.push_str(&progress.stop.flush());
+        return Json(LlamaCppResponse {
+            content,
+            stop: true,
+            summary: Some(progress.summary()),
+        })
+        .into_response();
+    }
+
+    Sse::new(stream! {
+        loop {
+            let text = match generation.recv(&mut receiver).await {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(Cancelled) => {
+                    yield Ok(SseEvent::default().data(shutdown_error().to_string()));
+                    return;
+                }
+            };
+            let content = progress.push(&text);
+            if !content.is_empty() {
+                yield event(&LlamaCppResponse {
+                    content,
+                    stop: false,
+                    summary: None,
+                });
+            }
+            // dropping the receiver stops the generation
+            if progress.stop.is_stopped() {
+                break;
+            }
+        }
+        yield event(&LlamaCppResponse {
+            content: progress.stop.flush(),
+            stop: true,
+            summary: Some(progress.summary()),
+        });
+    })
+    .keep_alive(KeepAlive::default())
+    .into_response()
+}
+
+/// Tracks the state of a generation to fill the `stopped_*` flags and `timings` of the last response.
+struct Progress {
+    model: String,
+    max_sampled: usize,
+    started_at: Instant,
+    first_token_at: Option<Instant>,
+    tokens_predicted: usize,
+    prompt_tokens: usize,
+    stop: StopSequences,
+}
+
+impl Progress {
+    fn push(&mut self, text: &str) -> String {
+        if self.first_token_at.is_none() {
+            self.first_token_at = Some(Instant::now());
+        }
+        self.tokens_predicted += 1;
+        self.stop.push(text)
+    }
+
+    fn summary(&self) -> LlamaCppSummary {
+        let first_token_at = self.first_token_at.unwrap_or(self.started_at);
+        // the prompt is evaluated before the first token is sampled
+        let prompt_ms = (first_token_at - self.started_at).as_secs_f64() * 1000.0;
+        let prompt_n = self.prompt_tokens;
+        let predicted_ms = first_token_at.elapsed().as_secs_f64() * 1000.0;
+        let predicted_n = self.tokens_predicted;
+        let stopped_word = self.stop.is_stopped();
+        let stopped_limit = !stopped_word && self.tokens_predicted >= self.max_sampled;
+        info!(
+            "predicted {} tokens in {:.2} ms ({:.2} tokens/s)",
+            predicted_n,
+            predicted_ms,
+            per_second(predicted_n, predicted_ms)
+        );
+        LlamaCppSummary {
+            model: self.model.clone(),
+            tokens_predicted: predicted_n,
+            tokens_evaluated: prompt_n,
+            stopped_eos: !stopped_word && !stopped_limit,
+            stopped_limit,
+            stopped_word,
+            stopping_word: self.stop.matched().unwrap_or_default().to_string(),
+            truncated: false,
+            timings: LlamaCppTimings {
+                prompt_n,
+                prompt_ms,
+                prompt_per_token_ms: if prompt_n > 0 {
+                    prompt_ms / prompt_n as f64
+                } else {
+                    0.0
+                },
+                prompt_per_second: per_second(prompt_n, prompt_ms),
+                predicted_n,
+                predicted_ms,
+                predicted_per_token_ms: if predicted_n > 0 {
+                    predicted_ms / predicted_n as f64
+                } else {
+                    0.0
+                },
+                predicted_per_second: per_second(predicted_n, predicted_ms),
+            },
+        }
+    }
+}
+
+fn per_second(tokens: usize, ms: f64) -> f64 {
+    if ms > 0.0 {
+        tokens as f64 / ms * 1000.0
+    } else {
+        0.0
+    }
+}
+
+/// The error body of llama.cpp server, also sent as the last event of a stream.
+fn shutdown_error() -> Value {
+    json!({
+        "error": {
+            "code": 503,
+            "message": "server is shutting down",
+            "type": "unavailable_error",
+        }
+    })
+}
+
+fn event(response: &LlamaCppResponse) -> Result<SseEvent, Infallible> {
+    Ok(SseEvent::default().data(to_string(response).unwrap()))
+}
@@ -1 +1,107 @@
-pub mod completion;
\ No newline at end of file
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::{header, request::Parts, StatusCode};
+use axum::response::{IntoResponse, Response};
+use oxpilot::cmd::PromptError;
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+pub mod admin;
+pub mod completion;
+pub mod copilot;
+pub mod llamacpp;
+pub mod ollama;
+pub mod tgi;
+pub mod ws;
+
+/// Identifies the client of a request, so that a newer completion of the same client supersedes the older ones,
+/// see `Command::Prompt::session`.
+///
+/// From the most to the least precise: the `X-Client-Id` header, e.g. one id per editor window, the
+/// `VScode-SessionId` header Copilot sends with every request, the API key (the `Authorization` header), and last the
+/// connection (the remote address), which is only known when the server is started with
+/// `into_make_service_with_connect_info`.
+pub struct Client(Option<String>);
+
+/// The header naming the client of a request.
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+
+/// The header Copilot sends with an id of the editor session.
+pub const VSCODE_SESSION_ID_HEADER: &str = "vscode-sessionid";
+
+impl Client {
+    /// The session of the prompt, the `user` field of the request body takes precedence over the client.
+    pub fn session(self, user: Option<&str>) -> Option<String> {
+        match user {
+            Some(user) => Some(format!("user:{}", user)),
+            None => self.0,
+        }
+    }
+}
+
+/// `FromRequestParts` makes `Client` an extractor, like `State` or `HeaderMap`, it doesn't consume the body, so it
+/// can be used together with `Json`.
+/// https://docs.rs/axum/0.7/axum/extract/index.html#implementing-fromrequestparts
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+        };
+        if let Some(id) = header(CLIENT_ID_HEADER) {
+            return Ok(Client(Some(format!("client:{}", id))));
+        }
+        if let Some(id) = header(VSCODE_SESSION_ID_HEADER) {
+            return Ok(Client(Some(format!("vscode:{}", id))));
+        }
+        if let Some(authorization) = parts.headers.get(header::AUTHORIZATION) {
+            // the key is hashed, it ends up in the scheduler and shouldn't be kept around in clear
+            let mut hasher = DefaultHasher::new();
+            authorization.as_bytes().hash(&mut hasher);
+            return Ok(Client(Some(format!("key:{:x}", hasher.finish()))));
+        }
+        Ok(Client(
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(address)| format!("connection:{}", address)),
+        ))
+    }
+}
+
+/// Waits for the manager to encode the prompt, returns the number of tokens of the prompt, or the response to a
+/// rejected prompt: 413 when it doesn't fit in the context of the model, 400 when it can't be encoded.
+pub async fn prompt_tokens(
+    receiver: oneshot::Receiver<Result<usize, PromptError>>,
+) -> Result<usize, Response> {
+    match receiver.await {
+        Ok(Ok(prompt_tokens)) => Ok(prompt_tokens),
+        Ok(Err(error)) => {
+            warn!("rejected prompt: {}", error);
+            let status = match error {
+                PromptError::TooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                PromptError::Encoding(_) => StatusCode::BAD_REQUEST,
+            };
+            Err((status, error.to_string()).into_response())
+        }
+        Err(error) => {
+            error!("LLM manager dropped the prompt: {}", error);
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "LLM manager is not running",
+            )
+                .into_response())
+        }
+    }
+}
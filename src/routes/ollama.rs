@@ -0,0 +1,420 @@
+use async_stream::stream;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::stream::Stream;
+use oxpilot::cmd::{Command::Prompt, Priority};
+use oxpilot::types::{
+    ChatMessage, OllamaChatRequest, OllamaChatResponse, OllamaGenerateRequest,
+    OllamaGenerateResponse, OllamaModel, OllamaModelDetails, OllamaOptions, OllamaShowRequest,
+    OllamaShowResponse, OllamaStats, OllamaTags,
+};
+use oxpilot::utils::stop::StopSequences;
+use oxpilot::utils::template::render_ollama_template;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info};
+
+use crate::shutdown::Cancelled;
+use crate::state::{AppState, ModelInfo, ServedModel};
+
+// Ollama-compatible endpoints, so `ox serve` can stand in for Ollama locally.
+// Reference: https://github.com/ollama/ollama/blob/main/docs/api.md
+
+/// `POST /api/generate`
+pub async fn generate(
+    State(state): State<AppState>,
+    Json(body): Json<OllamaGenerateRequest>,
+) -> Response {
+    let served = match state.model(body.model.as_deref()) {
+        Ok(served) => served,
+        Err(not_found) => return not_found.into_response(),
+    };
+    let prompt = match generate_prompt(&served, &body) {
+        Ok(prompt) => prompt,
+        Err(error) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": error }))).into_response()
+        }
+    };
+    let options = body.options.unwrap_or_default();
+    let model = body.model.unwrap_or(served.info.name.clone());
+    let mut timer = Timer::new(&options);
+    let priority = body.priority.unwrap_or(Priority::Chat);
+    let mut receiver =
+        match send_prompt(&state, &served, prompt, &options, priority, &mut timer).await {
+            Ok(receiver) => receiver,
+            Err(response) => return response,
+        };
+
+    let mut stop = StopSequences::new(options.stop.unwrap_or_default());
+    if !body.stream.unwrap_or(true) {
+        let mut response = String::new();
+        let mut generation = state.shutdown.generation();
+        loop {
+            let text = match generation.recv(&mut receiver).await {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(Cancelled) => {
+                    return (StatusCode::SERVICE_UNAVAILABLE, Json(shutdown_error()))
+                        .into_response()
+                }
+            };
+            timer.tick();
+            response.push_str(&stop.push(&text));
+            // dropping the receiver stops the generation
+            if stop.is_stopped() {
+                break;
+            }
+        }
+        response.push_str(&stop.flush());
+        return Json(OllamaGenerateResponse {
+            model,
+            created_at: now(),
+            response,
+            done: true,
+            stats: Some(timer.stats()),
+        })
+        .into_response();
+    }
+
+    let mut generation = state.shutdown.generation();
+    ndjson(stream! {
+        loop {
+            let text = match generation.recv(&mut receiver).await {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(Cancelled) => {
+                    yield line(&shutdown_error());
+                    return;
+                }
+            };
+            debug!("Received completion: {}", text);
+            timer.tick();
+            let text = stop.push(&text);
+            if !text.is_empty() {
+                yield line(&OllamaGenerateResponse {
+                    model: model.clone(),
+                    created_at: now(),
+                    response: text,
+                    done: false,
+                    stats: None,
+                });
+            }
+            if stop.is_stopped() {
+                break;
+            }
+        }
+        let text = stop.flush();
+        if !text.is_empty() {
+            yield line(&OllamaGenerateResponse {
+                model: model.clone(),
+                created_at: now(),
+                response: text,
+                done: false,
+                stats: None,
+            });
+        }
+        yield line(&OllamaGenerateResponse {
+            model: model.clone(),
+            created_at: now(),
+            response: "".to_string(),
+            done: true,
+            stats: Some(timer.stats()),
+        });
+    })
+}
+
+/// `POST /api/chat`
+pub async fn chat(State(state): State<AppState>, Json(body): Json<OllamaChatRequest>) -> Response {
+    let served = match state.model(body.model.as_deref()) {
+        Ok(served) => served,
+        Err(not_found) => return not_found.into_response(),
+    };
+    let options = body.options.unwrap_or_default();
+    let prompt = served.template.chat(&body.messages);
+    let model = body.model.unwrap_or(served.info.name.clone());
+    let mut timer = Timer::new(&options);
+    let priority = body.priority.unwrap_or(Priority::Chat);
+    let mut receiver =
+        match send_prompt(&state, &served, prompt, &options, priority, &mut timer).await {
+            Ok(receiver) => receiver,
+            Err(response) => return response,
+        };
+
+    let mut stop = StopSequences::new(options.stop.unwrap_or_default());
+    if !body.stream.unwrap_or(true) {
+        let mut content = String::new();
+        let mut generation = state.shutdown.generation();
+        loop {
+            let text = match generation.recv(&mut receiver).await {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(Cancelled) => {
+                    return (StatusCode::SERVICE_UNAVAILABLE, Json(shutdown_error()))
+                        .into_response()
+                }
+            };
+            timer.tick();
+            content.push_str(&stop.push(&text));
+            if stop.is_stopped() {
+                break;
+            }
+        }
+        content.push_str(&stop.flush());
+        return Json(OllamaChatResponse {
+            model,
+            created_at: now(),
+            message: assistant(content),
+            done: true,
+            stats: Some(timer.stats()),
+        })
+        .into_response();
+    }
+
+    let mut generation = state.shutdown.generation();
+    ndjson(stream! {
+        loop {
+            let text = match generation.recv(&mut receiver).await {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(Cancelled) => {
+                    yield line(&shutdown_error());
+                    return;
+                }
+            };
+            debug!("Received chat completion: {}", text);
+            timer.tick();
+            let text = stop.push(&text);
+            if !text.is_empty() {
+                yield line(&OllamaChatResponse {
+                    model: model.clone(),
+                    created_at: now(),
+                    message: assistant(text),
+                    done: false,
+                    stats: None,
+                });
+            }
+            if stop.is_stopped() {
+                break;
+            }
+        }
+        let text = stop.flush();
+        if !text.is_empty() {
+            yield line(&OllamaChatResponse {
+                model: model.clone(),
+                created_at: now(),
+                message: assistant(text),
+                done: false,
+                stats: None,
+            });
+        }
+        yield line(&OllamaChatResponse {
+            model: model.clone(),
+            created_at: now(),
+            message: assistant("".to_string()),
+            done: true,
+            stats: Some(timer.stats()),
+        });
+    })
+}
+
+/// `GET /api/tags` lists the models that are available locally, which are the models loaded by `ox serve`.
+pub async fn tags(State(state): State<AppState>) -> Json<OllamaTags> {
+    Json(OllamaTags {
+        models: state
+            .models()
+            .iter()
+            .map(|served| OllamaModel {
+                name: served.info.name.clone(),
+                model: served.info.name.clone(),
+                modified_at: now(),
+                size: served.info.size,
+                digest: "".to_string(),
+                details: details(&served.info),
+            })
+            .collect(),
+    })
+}
+
+/// `POST /api/show`
+pub async fn show(State(state): State<AppState>, Json(body): Json<OllamaShowRequest>) -> Response {
+    let name = body.model.or(body.name);
+    info!("show model: {}", name.as_deref().unwrap_or_default());
+    let served = match state.model(name.as_deref()) {
+        Ok(served) => served,
+        Err(not_found) => return not_found.into_response(),
+    };
+    Json(OllamaShowResponse {
+        modelfile: format!("FROM {}/{}", served.info.repo_id, served.info.file_name),
+        parameters: "".to_string(),
+        template: served.template.ollama_template().to_string(),
+        details: details(&served.info),
+    })
+    .into_response()
+}
+
+/// The prompt of `/api/generate`: the fill-in-the-middle prompt of the model when there is a `suffix`, otherwise
+/// the prompt formatted with the `template` of the request or of the model, unless the request is `raw`.
+fn generate_prompt(served: &ServedModel, body: &OllamaGenerateRequest) -> Result<String, String> {
+    let prompt = body.prompt.as_deref().unwrap_or_default();
+    // `raw` means the client has already applied the template itself.
+    if body.raw.unwrap_or(false) {
+        if body.suffix.is_some() || body.template.is_some() || body.system.is_some() {
+            return Err("raw mode does not support suffix, template or system".to_string());
+        }
+        return Ok(prompt.to_string());
+    }
+    if let Some(suffix) = &body.suffix {
+        return Ok(served.fim_template.format(prompt, suffix));
+    }
+    if let Some(template) = &body.template {
+        return render_ollama_template(template, body.system.as_deref(), prompt)
+            .map_err(|error| error.to_string());
+    }
+    Ok(match &body.system {
+        Some(system) => served
+            .template
+            .instruct(format!("{}\n\n{}", system, prompt)),
+        None => served.template.instruct(prompt),
+    })
+}
+
+/// Send a `Command::Prompt` to the manager task of the model, returns the receiver of the generated text.
+/// The number of tokens of the prompt is reported to the `timer`, a rejected prompt is answered with an error status.
+async fn send_prompt(
+    state: &AppState,
+    served: &ServedModel,
+    prompt: String,
+    options: &OllamaOptions,
+    priority: Priority,
+    timer: &mut Timer,
+) -> Result<mpsc::Receiver<String>, Response> {
+    let (responder, receiver) = mpsc::channel(8);
+    let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
+    match served
+        .tx
+        .send(Prompt {
+            prompt,
+            responder,
+            temperature: options.temperature,
+            seed: options.seed,
+            top_p: options.top_p,
+            max_sampled: timer.max_sampled,
+            decoding: state.decoding,
+            priority,
+            session: None,
+            prompt_tokens: Some(prompt_tokens),
+        })
+        .await
+    {
+        Ok(_) => {
+            timer.prompt_tokens = super::prompt_tokens(prompt_tokens_receiver).await?;
+            Ok(receiver)
+        }
+        Err(error) => {
+            error!("failed to send prompt to LLM manager: {}", error);
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "LLM manager is not running",
+            )
+                .into_response())
+        }
+    }
+}
+
+/// Ollama streams newline-delimited JSON instead of SSE, one JSON object per line.
+fn ndjson(stream: impl Stream<Item = Result<String, Infallible>> + Send + 'static) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+fn line(value: &impl Serialize) -> Result<String, Infallible> {
+    Ok(format!("{}\n", serde_json::to_string(value).unwrap()))
+}
+
+/// Ollama reports errors as `{"error": "..."}`, also as the last line of a stream.
+fn shutdown_error() -> Value {
+    json!({ "error": "server is shutting down" })
+}
+
+fn assistant(content: String) -> ChatMessage {
+    ChatMessage {
+        role: "assistant".to_string(),
+        content,
+    }
+}
+
+fn details(model: &ModelInfo) -> OllamaModelDetails {
+    OllamaModelDetails {
+        format: model.format.clone(),
+        family: model.architecture.clone(),
+        parameter_size: "".to_string(),
+        quantization_level: model.quantization_level(),
+    }
+}
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true)
+}
+
+/// Collects the timings and counts reported in the final object of a generation.
+/// The prompt is evaluated before the first token is sampled, so the time to the first token is reported as
+/// `prompt_eval_duration`.
+struct Timer {
+    started_at: Instant,
+    first_token_at: Option<Instant>,
+    eval_count: usize,
+    /// `num_predict`, the generation ends with `done_reason: "length"` when it is reached.
+    max_sampled: usize,
+    prompt_tokens: usize,
+}
+
+impl Timer {
+    fn new(options: &OllamaOptions) -> Self {
+        let max_sampled = match options.num_predict {
+            Some(num_predict) if num_predict > 0 => num_predict as usize,
+            // `-1` (infinite) or not set
+            _ => 4096,
+        };
+        Timer {
+            started_at: Instant::now(),
+            first_token_at: None,
+            eval_count: 0,
+            max_sampled,
+            prompt_tokens: 0,
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.first_token_at.is_none() {
+            self.first_token_at = Some(Instant::now());
+        }
+        self.eval_count += 1;
+    }
+
+    fn stats(&self) -> OllamaStats {
+        let first_token_at = self.first_token_at.unwrap_or(self.started_at);
+        let done_reason = if self.eval_count >= self.max_sampled {
+            "length"
+        } else {
+            "stop"
+        };
+        OllamaStats {
+            done_reason: done_reason.to_string(),
+            total_duration: self.started_at.elapsed().as_nanos(),
+            load_duration: 0,
+            prompt_eval_count: self.prompt_tokens,
+            prompt_eval_duration: (first_token_at - self.started_at).as_nanos(),
+            eval_count: self.eval_count,
+            eval_duration: first_token_at.elapsed().as_nanos(),
+        }
+    }
+}
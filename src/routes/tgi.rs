@@ -0,0 +1,228 @@
+use async_stream::stream;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use oxpilot::cmd::{Command::Prompt, Priority};
+use oxpilot::types::{
+    TgiDetails, TgiError, TgiParameters, TgiRequest, TgiResponse, TgiStreamResponse, TgiToken,
+};
+use oxpilot::utils::stop::StopSequences;
+use serde_json::to_string;
+use std::convert::Infallible;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+use crate::routes::Client;
+use crate::shutdown::Cancelled;
+use crate::state::AppState;
+
+// Hugging Face Text Generation Inference (TGI) compatible endpoints, the default protocol of `llm-ls` and `llm.nvim`.
+// Reference: https://huggingface.github.io/text-generation-inference/
+
+/// TGI's default when `max_new_tokens` is not set.
+const DEFAULT_MAX_NEW_TOKENS: usize = 100;
+
+/// `POST /generate` returns the whole generated text at once.
+pub async fn generate(
+    State(state): State<AppState>,
+    client: Client,
+    Json(body): Json<TgiRequest>,
+) -> Response {
+    let inputs = body.inputs;
+    let parameters = body.parameters.unwrap_or_default();
+    let mut receiver = match send_prompt(&state, client, &inputs, &parameters).await {
+        Ok(receiver) => receiver,
+        Err(response) => return response,
+    };
+
+    let mut stop = StopSequences::new(parameters.stop.clone().unwrap_or_default());
+    let mut generated_text = String::new();
+    let mut generated_tokens = 0;
+    let mut generation = state.shutdown.generation();
+    loop {
+        let text = match generation.recv(&mut receiver).await {
+            Ok(Some(text)) => text,
+            Ok(None) => break,
+            Err(Cancelled) => {
+                return (StatusCode::SERVICE_UNAVAILABLE, Json(shutdown_error())).into_response()
+            }
+        };
+        generated_tokens += 1;
+        generated_text.push_str(&stop.push(&text));
+        if stop.is_stopped() {
+            break;
+        }
+    }
+    generated_text.push_str(&stop.flush());
+
+    let details = details(&parameters, &stop, generated_tokens);
+    info!(
+        "generated: {:?} ({:?})",
+        generated_text, details.finish_reason
+    );
+    Json(TgiResponse {
+        generated_text: full_text(&inputs, &parameters, generated_text),
+        details: parameters.details.unwrap_or(false).then_some(details),
+    })
+    .into_response()
+}
+
+/// `POST /generate_stream` streams one SSE event per token, the last event carries the whole generated text and
+/// the details.
+pub async fn generate_stream(
+    State(state): State<AppState>,
+    client: Client,
+    Json(body): Json<TgiRequest>,
+) -> Response {
+    let inputs = body.inputs;
+    let parameters = body.parameters.unwrap_or_default();
+    let mut receiver = match send_prompt(&state, client, &inputs, &parameters).await {
+        Ok(receiver) => receiver,
+        Err(response) => return response,
+    };
+
+    let mut generation = state.shutdown.generation();
+    Sse::new(stream! {
+        let mut stop = StopSequences::new(parameters.stop.clone().unwrap_or_default());
+        let mut generated_text = String::new();
+        let mut generated_tokens = 0;
+        loop {
+            let text = match generation.recv(&mut receiver).await {
+                Ok(Some(text)) => text,
+                Ok(None) => break,
+                Err(Cancelled) => {
+                    // TGI streams errors as an event with `error` and `error_type`
+                    yield Ok(SseEvent::default().data(to_string(&shutdown_error()).unwrap()));
+                    return;
+                }
+            };
+            generated_tokens += 1;
+            let text = stop.push(&text);
+            generated_text.push_str(&text);
+            if !text.is_empty() {
+                yield event(&TgiStreamResponse {
+                    token: token(text),
+                    generated_text: None,
+                    details: None,
+                });
+            }
+            // dropping the receiver stops the generation
+            if stop.is_stopped() {
+                break;
+            }
+        }
+        let text = stop.flush();
+        generated_text.push_str(&text);
+        let details = details(&parameters, &stop, generated_tokens);
+        info!("generated: {:?} ({:?})", generated_text, details.finish_reason);
+        yield event(&TgiStreamResponse {
+            token: token(text),
+            generated_text: Some(full_text(&inputs, &parameters, generated_text)),
+            details: Some(details),
+        });
+    })
+    .keep_alive(KeepAlive::default())
+    .into_response()
+}
+
+/// Send a `Command::Prompt` to the manager task of the default model, TGI requests don't name a model, returns the receiver of the generated text.
+async fn send_prompt(
+    state: &AppState,
+    client: Client,
+    inputs: &str,
+    parameters: &TgiParameters,
+) -> Result<mpsc::Receiver<String>, Response> {
+    let (responder, receiver) = mpsc::channel(8);
+    let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
+    // TGI samples greedily unless `do_sample` is set, a temperature of 0 makes `LogitsProcessor` pick the argmax.
+    let temperature = if is_sampling(parameters) {
+        parameters.temperature
+    } else {
+        Some(0.0)
+    };
+    match state
+        .default_model()
+        .tx
+        .send(Prompt {
+            prompt: inputs.to_string(),
+            responder,
+            temperature,
+            seed: parameters.seed,
+            top_p: parameters.top_p,
+            max_sampled: parameters.max_new_tokens.unwrap_or(DEFAULT_MAX_NEW_TOKENS),
+            decoding: state.decoding,
+            // TGI endpoints are mostly used by editor plugins, e.g. `llm.nvim`, for inline completions
+            priority: parameters.priority.unwrap_or(Priority::Interactive),
+            session: client.session(None),
+            prompt_tokens: Some(prompt_tokens),
+        })
+        .await
+    {
+        Ok(_) => {
+            super::prompt_tokens(prompt_tokens_receiver).await?;
+            Ok(receiver)
+        }
+        Err(error) => {
+            error!("failed to send prompt to LLM manager: {}", error);
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                "LLM manager is not running",
+            )
+                .into_response())
+        }
+    }
+}
+
+fn details(
+    parameters: &TgiParameters,
+    stop: &StopSequences,
+    generated_tokens: usize,
+) -> TgiDetails {
+    let finish_reason = if stop.is_stopped() {
+        "stop_sequence"
+    } else if generated_tokens >= parameters.max_new_tokens.unwrap_or(DEFAULT_MAX_NEW_TOKENS) {
+        "length"
+    } else {
+        "eos_token"
+    };
+    TgiDetails {
+        finish_reason: finish_reason.to_string(),
+        generated_tokens,
+        // the seed only matters when sampling, greedy decoding doesn't use one
+        seed: parameters.seed.filter(|_| is_sampling(parameters)),
+    }
+}
+
+fn is_sampling(parameters: &TgiParameters) -> bool {
+    parameters.do_sample.unwrap_or(false)
+}
+
+fn full_text(inputs: &str, parameters: &TgiParameters, generated_text: String) -> String {
+    if parameters.return_full_text.unwrap_or(false) {
+        format!("{}{}", inputs, generated_text)
+    } else {
+        generated_text
+    }
+}
+
+fn token(text: String) -> TgiToken {
+    TgiToken {
+        id: 0,
+        text,
+        logprob: 0.0,
+        special: false,
+    }
+}
+
+fn shutdown_error() -> TgiError {
+    TgiError {
+        error: "server is shutting down".to_string(),
+        error_type: "shutdown".to_string(),
+    }
+}
+
+fn event(response: &TgiStreamResponse) -> Result<SseEvent, Infallible> {
+    Ok(SseEvent::default().data(to_string(response).unwrap()))
+}
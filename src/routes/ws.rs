@@ -0,0 +1,159 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use oxpilot::cmd::{Command::Prompt, Priority};
+use oxpilot::types::{WsClientMessage, WsServerMessage};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info};
+
+use crate::shutdown::{Cancelled, Generation};
+use crate::state::AppState;
+
+// A bidirectional alternative to the SSE endpoints: the client can cancel the current generation, or start a new one,
+// without dropping the connection. The messages are `WsClientMessage` and `WsServerMessage` as JSON text frames.
+
+/// `GET /ws` upgrades the connection to a WebSocket.
+pub async fn ws(State(state): State<AppState>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| session(socket, state))
+}
+
+/// The generation in progress on a socket, dropping it drops the receiver, which stops the generation.
+struct Running {
+    receiver: mpsc::Receiver<String>,
+    generation: Generation,
+}
+
+/// Serves one socket until the client closes it, one generation at a time.
+async fn session(mut socket: WebSocket, state: AppState) {
+    let mut running: Option<Running> = None;
+    loop {
+        // `select!` waits for whichever comes first, a message of the client or the next text of the generation
+        let sent = tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    // pings are answered by axum, binary frames are not part of the protocol
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => continue,
+                    // the client closed the socket or went away
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                };
+                match serde_json::from_str::<WsClientMessage>(&text) {
+                    Ok(WsClientMessage::Prompt {
+                        prompt,
+                        model,
+                        temperature,
+                        max_tokens,
+                        priority,
+                    }) => {
+                        if running.take().is_some() {
+                            send(&mut socket, cancelled()).await;
+                        }
+                        let started = start(&state, prompt, model, temperature, max_tokens, priority).await;
+                        match started {
+                            Ok(started) => {
+                                running = Some(started);
+                                true
+                            }
+                            Err(message) => send(&mut socket, WsServerMessage::Error { message }).await,
+                        }
+                    }
+                    Ok(WsClientMessage::Cancel) => match running.take() {
+                        Some(_) => send(&mut socket, cancelled()).await,
+                        None => true,
+                    },
+                    Err(error) => {
+                        send(
+                            &mut socket,
+                            WsServerMessage::Error {
+                                message: format!("invalid message: {}", error),
+                            },
+                        )
+                        .await
+                    }
+                }
+            }
+            text = next(&mut running) => match text {
+                Ok(Some(text)) => send(&mut socket, WsServerMessage::Token { text }).await,
+                Ok(None) => {
+                    running = None;
+                    send(&mut socket, WsServerMessage::Done { reason: "stop".to_string() }).await
+                }
+                Err(Cancelled) => {
+                    send(&mut socket, WsServerMessage::Error { message: "server is shutting down".to_string() }).await;
+                    break;
+                }
+            },
+        };
+        if !sent {
+            break;
+        }
+    }
+    debug!("websocket closed");
+}
+
+/// Sends a `Command::Prompt` to the manager task of the model.
+async fn start(
+    state: &AppState,
+    prompt: String,
+    model: Option<String>,
+    temperature: Option<f64>,
+    max_tokens: Option<usize>,
+    priority: Option<Priority>,
+) -> Result<Running, String> {
+    let served = state
+        .model(model.as_deref())
+        .map_err(|not_found| format!("model '{}' not found", not_found.0))?;
+    info!("websocket prompt for model {}", served.info.name);
+    let (responder, receiver) = mpsc::channel(8);
+    let (prompt_tokens, prompt_tokens_receiver) = oneshot::channel();
+    served
+        .tx
+        .send(Prompt {
+            prompt,
+            responder,
+            temperature,
+            seed: None,
+            top_p: None,
+            max_sampled: max_tokens.unwrap_or(4096),
+            decoding: state.decoding,
+            priority: priority.unwrap_or(Priority::Chat),
+            // the client cancels explicitly, sockets of the same client don't supersede each other
+            session: None,
+            prompt_tokens: Some(prompt_tokens),
+        })
+        .await
+        .map_err(|error| {
+            error!("failed to send prompt to LLM manager: {}", error);
+            "LLM manager is not running".to_string()
+        })?;
+    // a prompt too long for the model is reported as an error message, the socket stays open
+    match prompt_tokens_receiver.await {
+        Ok(Ok(_)) => {}
+        Ok(Err(error)) => return Err(error.to_string()),
+        Err(_) => return Err("LLM manager is not running".to_string()),
+    }
+    Ok(Running {
+        receiver,
+        generation: state.shutdown.generation(),
+    })
+}
+
+/// The next text of the generation in progress, never resolves when there is none.
+async fn next(running: &mut Option<Running>) -> Result<Option<String>, Cancelled> {
+    match running {
+        Some(running) => running.generation.recv(&mut running.receiver).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn cancelled() -> WsServerMessage {
+    WsServerMessage::Done {
+        reason: "cancelled".to_string(),
+    }
+}
+
+/// Returns false when the client is gone.
+async fn send(socket: &mut WebSocket, message: WsServerMessage) -> bool {
+    let text = serde_json::to_string(&message).unwrap();
+    socket.send(Message::Text(text)).await.is_ok()
+}
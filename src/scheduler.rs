@@ -0,0 +1,630 @@
+//! The scheduler owns the `LLM` and generates text for every `Command::Prompt` it receives.
+//!
+//! Instead of handling one prompt at a time, several sequences are decoded together: every step evaluates the
+//! pending tokens of all active sequences in one batched forward pass (see `Model::forward_batch`), each
+//! sequence in its own KV cache slot, then samples one token per sequence and sends it to its responder. New prompts
+//! join the batch between steps, so a long chat request no longer blocks the editor completions queued behind it.
+//!
+//! Prompts wait in one queue per `Priority` class, higher classes are admitted first. When all slots are taken, a
+//! sequence of a lower class is preempted (paused) between two tokens to make room, it resumes where it stopped
+//! once a slot is free again.
+//!
+//! A failing sequence, or a slow client, only affects its own sequence: a prompt longer than the context of the model
+//! is rejected when it is received, a failed batch is evaluated again one sequence at a time, and the texts a client
+//! doesn't read fast enough are buffered, up to `MAX_UNSENT`, instead of holding up the batch.
+//!
+//! The forward passes take the CPU for seconds, `Scheduler::run` blocks its thread and must not run on the tokio
+//! workers serving the requests.
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use candle_core::{Result, Tensor};
+use tokio::sync::mpsc::{
+    self,
+    error::{TryRecvError, TrySendError},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::cmd::{Command, Priority, PromptError};
+use crate::llm::LLM;
+use crate::models::quantized_llama::BatchSequence;
+use crate::speculative::{
+    speculate, speculate_with_prompt_lookup, Decoding, Sampler, SpeculativeStats,
+    PROMPT_LOOKUP_NUM_DRAFT_TOKENS,
+};
+use crate::token::token_to_text;
+
+/// The maximum number of texts buffered for a client that doesn't read fast enough, its sequence is paused until the
+/// client catches up.
+const MAX_UNSENT: usize = 256;
+
+/// How long to wait for the clients when every active sequence is paused.
+const PAUSED_WAIT: Duration = Duration::from_millis(10);
+
+/// The sampling settings shared by all requests, from the CLI flags, a prompt may override the temperature, the seed
+/// and `top_p`.
+#[derive(Clone)]
+pub struct SchedulerOptions {
+    pub temperature: f64,
+    pub to_sample: usize,
+    pub seed: u64,
+    pub top_p: Option<f64>,
+    pub repeat_last_n: usize,
+    pub repeat_penalty: f32,
+    /// The maximum number of sequences decoded together, each one takes a KV cache slot.
+    pub max_batch_size: usize,
+}
+
+/// A prompt being generated.
+struct Sequence {
+    /// The KV cache slot, only meaningful while the sequence is active, a slot is assigned on every admission.
+    slot: usize,
+    priority: Priority,
+    session: Option<String>,
+    /// When the prompt was received, `None` once it was admitted for the first time.
+    queued_at: Option<Instant>,
+    prompt_len: usize,
+    /// The prompt and the generated tokens, the last one is not evaluated yet.
+    tokens: Vec<u32>,
+    responder: mpsc::Sender<String>,
+    /// The texts not sent yet because the channel of the responder was full, one per token.
+    unsent: VecDeque<String>,
+    sampler: Sampler,
+    decoding: Decoding,
+    /// The maximum number of tokens sent to the responder.
+    max_sampled: usize,
+    sampled: usize,
+    stats: SpeculativeStats,
+}
+
+impl Sequence {
+    fn generated(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    /// Sends the buffered texts until the channel is full, returns `false` when the client is gone.
+    fn flush(&mut self) -> bool {
+        while let Some(text) = self.unsent.pop_front() {
+            match self.responder.try_send(text) {
+                Ok(()) => {}
+                Err(TrySendError::Full(text)) => {
+                    self.unsent.push_front(text);
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// How long the prompts of a priority class waited before their first token was generated.
+#[derive(Default)]
+struct WaitStats {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+/// What to do with a sequence after a token was sampled.
+enum Next {
+    Continue,
+    Finish,
+}
+
+pub struct Scheduler {
+    llm: LLM,
+    options: SchedulerOptions,
+    /// The tokens in the KV cache of each slot, the cache is kept after a sequence finishes, so that a new prompt
+    /// sharing a prefix with it can reuse it.
+    slots: Vec<Vec<u32>>,
+    /// Whether a slot is used by an active sequence.
+    busy: Vec<bool>,
+    active: Vec<Sequence>,
+    /// The sequences waiting for a slot, new prompts and preempted sequences, by priority class.
+    pending: HashMap<Priority, VecDeque<Sequence>>,
+    waits: HashMap<Priority, WaitStats>,
+}
+
+impl Scheduler {
+    pub fn new(llm: LLM, options: SchedulerOptions) -> Self {
+        // the models without KV cache slots, e.g. Qwen2, decode one sequence at a time, two sequences taking turns
+        // would evaluate their whole context again on every token
+        let max_batch_size = options
+            .max_batch_size
+            .clamp(1, llm.model_weights.max_batch_size());
+        if max_batch_size < options.max_batch_size {
+            info!(
+                "{} decodes {} sequence(s) at a time, ignoring --max-batch-size {}",
+                llm.architecture, max_batch_size, options.max_batch_size
+            );
+        }
+        if llm.draft.is_some() && !llm.model_weights.is_forward_all_batched() {
+            warn!(
+                "{} verifies draft tokens one by one, the draft model is not used",
+                llm.architecture
+            );
+        }
+        Scheduler {
+            llm,
+            options,
+            slots: vec![vec![]; max_batch_size],
+            busy: vec![false; max_batch_size],
+            active: vec![],
+            pending: HashMap::new(),
+            waits: HashMap::new(),
+        }
+    }
+
+    /// Runs until every sender of `rx` is dropped and all active sequences are finished. This blocks the current
+    /// thread, e.g. run it with `std::thread::spawn`, inside a tokio runtime context so that the texts left for slow
+    /// clients can be sent by a task once their sequence finished.
+    pub fn run(mut self, mut rx: mpsc::Receiver<Command>) {
+        let mut is_closed = false;
+        loop {
+            // wait for a prompt when there is nothing to do, otherwise only pick up the prompts already queued
+            if self.active.is_empty() && self.pending.values().all(VecDeque::is_empty) {
+                if is_closed {
+                    break;
+                }
+                match rx.blocking_recv() {
+                    Some(command) => self.enqueue(command),
+                    None => break,
+                }
+            }
+            while !is_closed {
+                match rx.try_recv() {
+                    Ok(command) => self.enqueue(command),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => is_closed = true,
+                }
+            }
+            self.schedule();
+            self.step();
+        }
+        info!("all senders dropped, scheduler exited");
+    }
+
+    /// Tokenizes the prompt and queues it in its priority class, reporting the number of tokens of the prompt, or why it was rejected.
+    fn enqueue(&mut self, command: Command) {
+        let Command::Prompt {
+            prompt,
+            responder,
+            temperature,
+            seed,
+            top_p,
+            max_sampled,
+            decoding,
+            priority,
+            session,
+            prompt_tokens,
+        } = command;
+        debug!("prompt:{}", prompt);
+        if let Some(session) = &session {
+            self.supersede(session);
+        }
+        let report = |result: std::result::Result<usize, PromptError>| {
+            if let Some(prompt_tokens) = prompt_tokens {
+                let _ = prompt_tokens.send(result);
+            }
+        };
+        let tokens = match self.llm.tokenizer.encode(prompt, true) {
+            Ok(encoding) => encoding.get_ids().to_vec(),
+            Err(error) => {
+                error!("failed to encode prompt as tokens: {}", error);
+                report(Err(PromptError::Encoding(error.to_string())));
+                return;
+            }
+        };
+        // the prompt and at least one generated token must fit in the KV cache
+        let max_seq_len = self.llm.model_weights.max_seq_len();
+        if tokens.len() >= max_seq_len {
+            warn!(
+                "rejected a prompt of {} tokens, the model accepts at most {} tokens",
+                tokens.len(),
+                max_seq_len - 1
+            );
+            report(Err(PromptError::TooLong {
+                len: tokens.len(),
+                max: max_seq_len - 1,
+            }));
+            return;
+        }
+        report(Ok(tokens.len()));
+        if tokens.is_empty() {
+            return;
+        }
+        let prompt_len = tokens.len();
+        self.pending
+            .entry(priority)
+            .or_default()
+            .push_back(Sequence {
+                slot: 0,
+                priority,
+                session,
+                queued_at: Some(Instant::now()),
+                prompt_len,
+                tokens,
+                responder,
+                unsent: VecDeque::new(),
+                sampler: Sampler::new(
+                    seed.unwrap_or(self.options.seed),
+                    temperature.unwrap_or(self.options.temperature),
+                    top_p.or(self.options.top_p),
+                )
+                .with_repeat_penalty(self.options.repeat_penalty, self.options.repeat_last_n),
+                decoding,
+                // the generated tokens must fit in the KV cache too
+                max_sampled: max_sampled
+                    .min(self.options.to_sample + 1)
+                    .min(max_seq_len - prompt_len),
+                sampled: 0,
+                stats: SpeculativeStats::default(),
+            });
+    }
+
+    /// Cancels the queued and running sequences of `session`, dropping their responders ends the streams of the
+    /// clients. Under fast typing, at most one completion per client is waiting or running.
+    fn supersede(&mut self, session: &str) {
+        let is_stale = |sequence: &Sequence| sequence.session.as_deref() == Some(session);
+        let mut superseded = 0;
+        for queue in self.pending.values_mut() {
+            let len = queue.len();
+            queue.retain(|sequence| !is_stale(sequence));
+            superseded += len - queue.len();
+        }
+        let (stale, active): (Vec<_>, Vec<_>) = std::mem::take(&mut self.active)
+            .into_iter()
+            .partition(|sequence| is_stale(sequence));
+        self.active = active;
+        superseded += stale.len();
+        for mut sequence in stale {
+            // nobody waits for the texts of a stale prompt
+            sequence.unsent.clear();
+            self.finish(sequence);
+        }
+        if superseded > 0 {
+            debug!("superseded {} stale prompts of {}", superseded, session);
+        }
+    }
+
+    /// Admits the pending sequences from the highest priority class down, preempting the active sequences of lower
+    /// classes when no slot is free.
+    fn schedule(&mut self) {
+        for priority in Priority::ALL {
+            loop {
+                let Some(sequence) = self
+                    .pending
+                    .get_mut(&priority)
+                    .and_then(VecDeque::pop_front)
+                else {
+                    break;
+                };
+                // the client is gone, e.g. a newer completion was requested
+                if sequence.responder.is_closed() {
+                    continue;
+                }
+                if !self.busy.contains(&false) && !self.preempt(priority) {
+                    // no free slot for this class, nor for the lower classes
+                    self.pending
+                        .entry(priority)
+                        .or_default()
+                        .push_front(sequence);
+                    return;
+                }
+                self.admit(sequence);
+            }
+        }
+    }
+
+    /// Pauses the most recently admitted sequence of the lowest class below `priority`, returns `false` if there is
+    /// none. The sequence goes back to the front of its queue, its tokens are kept, so it resumes where it stopped.
+    fn preempt(&mut self, priority: Priority) -> bool {
+        let Some(lowest) = self.active.iter().map(|sequence| sequence.priority).min() else {
+            return false;
+        };
+        if lowest >= priority {
+            return false;
+        }
+        let Some(index) = self
+            .active
+            .iter()
+            .rposition(|sequence| sequence.priority == lowest)
+        else {
+            return false;
+        };
+        let sequence = self.active.remove(index);
+        info!(
+            "preempted a {:?} sequence in slot {} after {} tokens for a {:?} prompt",
+            sequence.priority, sequence.slot, sequence.sampled, priority
+        );
+        self.busy[sequence.slot] = false;
+        self.pending
+            .entry(sequence.priority)
+            .or_default()
+            .push_front(sequence);
+        true
+    }
+
+    /// Activates the sequence in the free slot sharing the longest prefix with it.
+    fn admit(&mut self, mut sequence: Sequence) {
+        let Some(slot) = (0..self.slots.len())
+            .filter(|slot| !self.busy[*slot])
+            .max_by_key(|slot| common_prefix_len(&self.slots[*slot], &sequence.tokens))
+        else {
+            // `schedule` makes room before admitting, wait for the next round just in case
+            self.pending
+                .entry(sequence.priority)
+                .or_default()
+                .push_front(sequence);
+            return;
+        };
+        self.busy[slot] = true;
+        sequence.slot = slot;
+        if let Some(queued_at) = sequence.queued_at.take() {
+            let waited = queued_at.elapsed();
+            let stats = self.waits.entry(sequence.priority).or_default();
+            stats.count += 1;
+            stats.total += waited;
+            stats.max = stats.max.max(waited);
+            info!(
+                "{:?} prompt waited {:.1?} for a slot ({:?} prompts: {}, average wait {:.1?}, max wait {:.1?})",
+                sequence.priority,
+                waited,
+                sequence.priority,
+                stats.count,
+                stats.total / stats.count,
+                stats.max
+            );
+        }
+        debug!(
+            "admitted a {:?} sequence of {} tokens in slot {}, {} active",
+            sequence.priority,
+            sequence.tokens.len(),
+            slot,
+            self.active.len() + 1
+        );
+        self.active.push(sequence);
+    }
+
+    /// Generates at least one token for every active sequence whose client keeps up.
+    fn step(&mut self) {
+        // a client that doesn't read fast enough pauses its own sequence, the others go on
+        let mut paused = vec![];
+        for mut sequence in std::mem::take(&mut self.active) {
+            if !sequence.flush() {
+                self.finish(sequence);
+            } else if sequence.unsent.len() >= MAX_UNSENT {
+                paused.push(sequence);
+            } else {
+                self.active.push(sequence);
+            }
+        }
+        if self.active.is_empty() {
+            if !paused.is_empty() {
+                std::thread::sleep(PAUSED_WAIT);
+            }
+        } else if self.active.len() == 1 && self.is_speculative(&self.active[0]) {
+            self.step_speculative();
+        } else {
+            self.step_batch();
+        }
+        self.active.append(&mut paused);
+    }
+
+    fn step_batch(&mut self) {
+        // only the tokens after the common prefix with the slot's cache are evaluated: the whole prompt of a new
+        // sequence (minus the reused prefix), and the last sampled token of the others
+        let starts: Vec<usize> = self
+            .active
+            .iter()
+            .map(|sequence| {
+                common_prefix_len(&self.slots[sequence.slot], &sequence.tokens)
+                    .min(sequence.tokens.len() - 1)
+            })
+            .collect();
+        let batch: Vec<BatchSequence> = self
+            .active
+            .iter()
+            .zip(&starts)
+            .map(|(sequence, start)| BatchSequence {
+                slot: sequence.slot,
+                tokens: &sequence.tokens[*start..],
+                index_pos: *start,
+            })
+            .collect();
+        let logits = match self.llm.model_weights.forward_batch(&batch) {
+            Ok(logits) => logits,
+            Err(error) => {
+                self.step_one_by_one(error);
+                return;
+            }
+        };
+        for sequence in self.active.iter() {
+            let slot = &mut self.slots[sequence.slot];
+            slot.clear();
+            slot.extend_from_slice(&sequence.tokens);
+        }
+
+        let mut active = Vec::with_capacity(self.active.len());
+        for (index, mut sequence) in std::mem::take(&mut self.active).into_iter().enumerate() {
+            match self.advance(&mut sequence, logits.get(index)) {
+                Next::Continue => active.push(sequence),
+                Next::Finish => self.finish(sequence),
+            }
+        }
+        self.active = active;
+    }
+
+    /// Evaluates the active sequences one at a time after a batched step failed, only the sequences failing on their
+    /// own are dropped.
+    fn step_one_by_one(&mut self, error: candle_core::Error) {
+        warn!(
+            "batched decoding of {} sequences failed, decoding them one at a time: {}",
+            self.active.len(),
+            error
+        );
+        let mut active = Vec::with_capacity(self.active.len());
+        for mut sequence in std::mem::take(&mut self.active) {
+            // the failed batch may have left the cache of the slot half written, evaluate the whole sequence again
+            self.slots[sequence.slot].clear();
+            let batch = [BatchSequence {
+                slot: sequence.slot,
+                tokens: &sequence.tokens,
+                index_pos: 0,
+            }];
+            let next = match self.llm.model_weights.forward_batch(&batch) {
+                Ok(logits) => {
+                    self.slots[sequence.slot].extend_from_slice(&sequence.tokens);
+                    self.advance(&mut sequence, logits.get(0))
+                }
+                Err(error) => {
+                    error!(
+                        "decoding the sequence in slot {} failed, dropping it: {}",
+                        sequence.slot, error
+                    );
+                    Next::Finish
+                }
+            };
+            match next {
+                Next::Continue => active.push(sequence),
+                Next::Finish => self.finish(sequence),
+            }
+        }
+        self.active = active;
+    }
+
+    /// Samples the next token of the sequence from the logits of its last position and pushes it.
+    fn advance(&mut self, sequence: &mut Sequence, logits: Result<Tensor>) -> Next {
+        let token = logits.and_then(|logits| {
+            let logits = sequence.sampler.penalize(&logits, sequence.generated())?;
+            let probabilities = sequence.sampler.probabilities(&logits)?;
+            Ok(sequence.sampler.sample(&probabilities))
+        });
+        match token {
+            Ok(token) => self.push(sequence, token),
+            Err(error) => {
+                error!(
+                    "sampling the sequence in slot {} failed, dropping it: {}",
+                    sequence.slot, error
+                );
+                Next::Finish
+            }
+        }
+    }
+
+    /// A sequence alone in the batch uses speculative decoding when a draft model is configured or it asked for
+    /// prompt lookup, verifying several draft tokens costs about the same as a batched step, unless the model
+    /// evaluates them one by one. Near the end of the context, the draft tokens may not fit in the KV cache anymore.
+    fn is_speculative(&self, sequence: &Sequence) -> bool {
+        if !self.llm.model_weights.is_forward_all_batched() {
+            return false;
+        }
+        let num_draft_tokens = match (sequence.decoding, self.llm.draft.as_ref()) {
+            (Decoding::Standard, Some(draft)) => draft.num_draft_tokens,
+            (Decoding::PromptLookup, _) => PROMPT_LOOKUP_NUM_DRAFT_TOKENS,
+            (Decoding::Standard, None) => return false,
+        };
+        sequence.tokens.len() + num_draft_tokens < self.llm.model_weights.max_seq_len()
+    }
+
+    fn step_speculative(&mut self) {
+        let mut sequence = self.active.remove(0);
+        self.llm.model_weights.select_slot(sequence.slot);
+        let accepted = match (sequence.decoding, self.llm.draft.as_mut()) {
+            (Decoding::Standard, Some(draft)) => speculate(
+                self.llm.model_weights.as_mut(),
+                &mut self.slots[sequence.slot],
+                draft,
+                &sequence.tokens,
+                sequence.prompt_len,
+                &mut sequence.sampler,
+                &mut sequence.stats,
+            ),
+            _ => speculate_with_prompt_lookup(
+                self.llm.model_weights.as_mut(),
+                &mut self.slots[sequence.slot],
+                &sequence.tokens,
+                sequence.prompt_len,
+                &mut sequence.sampler,
+                &mut sequence.stats,
+            ),
+        };
+        self.llm.model_weights.select_slot(0);
+        let accepted = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                error!(
+                    "speculative decoding of the sequence in slot {} failed, dropping it: {}",
+                    sequence.slot, error
+                );
+                self.slots[sequence.slot].clear();
+                self.finish(sequence);
+                return;
+            }
+        };
+        for token in accepted {
+            if let Next::Finish = self.push(&mut sequence, token) {
+                self.finish(sequence);
+                return;
+            }
+        }
+        self.active.push(sequence);
+    }
+
+    /// Appends the sampled token to the sequence and sends its text to the responder. A client that doesn't read
+    /// fast enough doesn't hold up the batch, its texts are buffered until there is room in its channel.
+    fn push(&mut self, sequence: &mut Sequence, token: u32) -> Next {
+        // the EOS token, or the end of turn of instruct models, e.g. `<|im_end|>`
+        if self.llm.special_tokens.is_stop(token) || sequence.sampled >= sequence.max_sampled {
+            return Next::Finish;
+        }
+        sequence.sampled += 1;
+        sequence.tokens.push(token);
+        sequence
+            .unsent
+            .push_back(token_to_text(token, &self.llm.tokenizer));
+        // sending fails when the receiver is dropped, e.g. the client disconnected or a stop sequence was found,
+        // there is no one to generate text for anymore.
+        if !sequence.flush() {
+            return Next::Finish;
+        }
+        Next::Continue
+    }
+
+    /// Frees the slot of the sequence, dropping the responder closes the stream of the client.
+    fn finish(&mut self, sequence: Sequence) {
+        self.busy[sequence.slot] = false;
+        if sequence.stats.drafted > 0 {
+            self.llm.speculative_stats.add(sequence.stats);
+            info!(
+                "speculative decoding accepted {} of {} draft tokens ({:.1}%), {:.1}% since start",
+                sequence.stats.accepted,
+                sequence.stats.drafted,
+                sequence.stats.acceptance_rate() * 100.0,
+                self.llm.speculative_stats.acceptance_rate() * 100.0
+            );
+        }
+        debug!(
+            "sequence in slot {} finished after {} tokens",
+            sequence.slot, sequence.sampled
+        );
+        // the client is slower than the model, the rest of the texts are sent without holding up the batch
+        if !sequence.unsent.is_empty() && !sequence.responder.is_closed() {
+            let Sequence {
+                responder, unsent, ..
+            } = sequence;
+            tokio::spawn(async move {
+                for text in unsent {
+                    if responder.send(text).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
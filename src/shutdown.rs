@@ -0,0 +1,187 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn};
+
+/// How long the cancelled streams have to send their last event before the process exits.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Running,
+    /// No new connections are accepted, the generations in flight run to completion, since the signal at `Instant`.
+    Draining(Instant),
+    /// The shutdown timeout elapsed, the generations in flight are cancelled, since the signal at `Instant`.
+    Cancelled(Instant),
+}
+
+/// Coordinates the graceful shutdown of `ox serve` on Ctrl-C or SIGTERM: `axum::serve` stops accepting connections,
+/// the open streams get `--shutdown-timeout` to finish, then they are cancelled with an error event.
+#[derive(Clone)]
+pub struct Shutdown {
+    phase: Arc<watch::Sender<Phase>>,
+    /// The generations in flight.
+    active: Arc<AtomicUsize>,
+    /// The generations that finished after the signal.
+    drained: Arc<AtomicUsize>,
+    /// The generations cancelled by the timeout.
+    cancelled: Arc<AtomicUsize>,
+}
+
+/// The generation was cancelled because the server is shutting down.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (phase, _) = watch::channel(Phase::Running);
+        Shutdown {
+            phase: Arc::new(phase),
+            active: Arc::new(AtomicUsize::new(0)),
+            drained: Arc::new(AtomicUsize::new(0)),
+            cancelled: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Resolves on Ctrl-C or SIGTERM, passed to `with_graceful_shutdown`.
+    pub async fn signalled(self) {
+        let ctrl_c = async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to install Ctrl-C handler");
+        };
+        #[cfg(unix)]
+        let terminate = async {
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to install SIGTERM handler")
+                .recv()
+                .await;
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate => {},
+        }
+        info!(
+            "shutting down, waiting for {} generations in flight",
+            self.active.load(Ordering::SeqCst)
+        );
+        self.phase.send_replace(Phase::Draining(Instant::now()));
+    }
+
+    /// Cancels the generations still in flight `timeout` after the signal, resolves once they had time to send their
+    /// last event.
+    pub async fn cancel_after(self, timeout: Duration) {
+        let mut phase = self.phase.subscribe();
+        let signalled_at = match phase
+            .wait_for(|phase| matches!(phase, Phase::Draining(_)))
+            .await
+            .map(|phase| *phase)
+        {
+            Ok(Phase::Draining(signalled_at)) => signalled_at,
+            _ => Instant::now(),
+        };
+        tokio::time::sleep(timeout).await;
+        warn!(
+            "shutdown timeout elapsed, cancelling {} generations",
+            self.active.load(Ordering::SeqCst)
+        );
+        self.phase.send_replace(Phase::Cancelled(signalled_at));
+        tokio::time::sleep(CANCEL_GRACE_PERIOD).await;
+    }
+
+    /// Tracks a generation until the returned guard is dropped.
+    pub fn generation(&self) -> Generation {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        Generation {
+            shutdown: self.clone(),
+            phase: self.phase.subscribe(),
+            is_cancelled: false,
+        }
+    }
+
+    /// Logs how the generations in flight ended.
+    pub fn log_summary(&self) {
+        let took = match *self.phase.borrow() {
+            Phase::Running => Duration::ZERO,
+            Phase::Draining(signalled_at) | Phase::Cancelled(signalled_at) => {
+                signalled_at.elapsed()
+            }
+        };
+        info!(
+            "shutdown: {} generations finished, {} cancelled, {} still running, drained in {:.2}s",
+            self.drained.load(Ordering::SeqCst),
+            self.cancelled.load(Ordering::SeqCst),
+            self.active.load(Ordering::SeqCst),
+            took.as_secs_f64()
+        );
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A generation in flight, see `Shutdown::generation`.
+pub struct Generation {
+    shutdown: Shutdown,
+    phase: watch::Receiver<Phase>,
+    is_cancelled: bool,
+}
+
+impl Generation {
+    /// Receives the next text of the generation like `receiver.recv()`, `Err(Cancelled)` once the shutdown timeout
+    /// elapsed, dropping the receiver then stops the generation.
+    pub async fn recv(
+        &mut self,
+        receiver: &mut mpsc::Receiver<String>,
+    ) -> Result<Option<String>, Cancelled> {
+        tokio::select! {
+            text = receiver.recv() => Ok(text),
+            _ = self.phase.wait_for(|phase| matches!(phase, Phase::Cancelled(_))) => {
+                self.is_cancelled = true;
+                Err(Cancelled)
+            }
+        }
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        self.shutdown.active.fetch_sub(1, Ordering::SeqCst);
+        if self.is_cancelled {
+            self.shutdown.cancelled.fetch_add(1, Ordering::SeqCst);
+        } else if *self.phase.borrow() != Phase::Running {
+            self.shutdown.drained.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn generations_are_cancelled_after_timeout() {
+        let shutdown = Shutdown::new();
+        let (tx, mut receiver) = mpsc::channel(8);
+        let mut generation = shutdown.generation();
+        tx.send("fn".to_string()).await.unwrap();
+
+        shutdown.phase.send_replace(Phase::Draining(Instant::now()));
+        tokio::spawn(shutdown.clone().cancel_after(Duration::ZERO));
+        // the text already sent is still received while draining
+        assert_eq!(generation.recv(&mut receiver).await.unwrap().unwrap(), "fn");
+        assert!(generation.recv(&mut receiver).await.is_err());
+        drop(generation);
+
+        assert_eq!(shutdown.active.load(Ordering::SeqCst), 0);
+        assert_eq!(shutdown.cancelled.load(Ordering::SeqCst), 1);
+        assert_eq!(shutdown.drained.load(Ordering::SeqCst), 0);
+    }
+}
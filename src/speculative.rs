@@ -0,0 +1,443 @@
+//! Speculative decoding: a small draft model proposes a few tokens, and the main model verifies all of them in one
+//! forward pass. Verifying k tokens costs about as much as generating one on CPU, since the cost is dominated by
+//! reading the weights from memory, so every accepted draft token is (almost) free.
+//!
+//! Draft tokens are accepted with rejection sampling, which keeps the output distribution identical to sampling
+//! from the main model alone.
+//! See "Fast Inference from Transformers via Speculative Decoding" https://arxiv.org/abs/2211.17192
+use candle_core::{DType, Result, Tensor};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::models::Model;
+use crate::process::{evaluate, prefill};
+
+/// A draft model sharing the tokenizer (and the vocabulary) of the main model.
+pub struct DraftModel {
+    pub model_repo_id: String,
+    pub model_file_name: String,
+    pub model_weights: Box<dyn Model>,
+    /// The tokens in the KV cache of `model_weights`.
+    pub cached_tokens: Vec<u32>,
+    /// How many tokens are proposed before verifying them with the main model.
+    pub num_draft_tokens: usize,
+}
+
+/// How tokens are generated, selectable per command, e.g. `ox commit --decoding prompt-lookup`.
+///
+/// `clap::ValueEnum` lets clap parse the enum from CLI arguments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum)]
+pub enum Decoding {
+    /// One token per forward pass of the main model, or speculative decoding when a draft model is configured.
+    #[default]
+    Standard,
+    /// Speculative decoding without a draft model, the draft tokens are copied from the prompt where the last
+    /// generated tokens appear, e.g. the diff in `ox commit` or the code around the cursor.
+    PromptLookup,
+}
+
+/// Counts drafted and accepted tokens, `accepted / drafted` is the acceptance rate.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SpeculativeStats {
+    pub drafted: usize,
+    pub accepted: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted == 0 {
+            0.0
+        } else {
+            self.accepted as f64 / self.drafted as f64
+        }
+    }
+
+    pub fn add(&mut self, other: SpeculativeStats) {
+        self.drafted += other.drafted;
+        self.accepted += other.accepted;
+    }
+}
+
+/// Turns logits into probabilities with the same temperature and nucleus (top-p) settings as
+/// `candle_transformers::generation::LogitsProcessor`, and samples from them.
+/// Unlike `LogitsProcessor`, the probabilities are exposed, rejection sampling needs them.
+pub struct Sampler {
+    rng: StdRng,
+    temperature: f64,
+    top_p: Option<f64>,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+}
+
+impl Sampler {
+    pub fn new(seed: u64, temperature: f64, top_p: Option<f64>) -> Self {
+        Sampler {
+            rng: StdRng::seed_from_u64(seed),
+            temperature,
+            top_p,
+            repeat_penalty: 1.0,
+            repeat_last_n: 0,
+        }
+    }
+
+    /// Penalizes the last `repeat_last_n` generated tokens, see [`Sampler::penalize`].
+    pub fn with_repeat_penalty(mut self, repeat_penalty: f32, repeat_last_n: usize) -> Self {
+        self.repeat_penalty = repeat_penalty;
+        self.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    /// Applies the repeat penalty to `logits`, `generated` are the tokens generated before them, the prompt is not
+    /// penalized. The draft and the verified positions are penalized the same way, so that they sample from the
+    /// same distribution.
+    pub fn penalize(&self, logits: &Tensor, generated: &[u32]) -> Result<Tensor> {
+        if self.repeat_penalty == 1.0 {
+            return Ok(logits.clone());
+        }
+        let start_at = generated.len().saturating_sub(self.repeat_last_n);
+        candle_transformers::utils::apply_repeat_penalty(
+            logits,
+            self.repeat_penalty,
+            &generated[start_at..],
+        )
+    }
+
+    /// The probability of each token, `logits` is shaped `(vocab_size,)`.
+    /// A temperature of 0 means greedy sampling, all the probability goes to the most likely token.
+    pub fn probabilities(&self, logits: &Tensor) -> Result<Vec<f32>> {
+        let logits: Vec<f32> = logits.to_dtype(DType::F32)?.to_vec1()?;
+        if self.temperature < 1e-7 {
+            let argmax = logits
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(index, _)| index)
+                .unwrap_or(0);
+            let mut probabilities = vec![0f32; logits.len()];
+            probabilities[argmax] = 1.0;
+            return Ok(probabilities);
+        }
+
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let mut probabilities: Vec<f32> = logits
+            .iter()
+            .map(|logit| ((logit - max) / self.temperature as f32).exp())
+            .collect();
+        normalize(&mut probabilities);
+
+        if let Some(top_p) = self.top_p {
+            if top_p < 1.0 {
+                // keep the smallest set of most likely tokens whose cumulative probability exceeds `top_p`
+                let mut indices: Vec<usize> = (0..probabilities.len()).collect();
+                indices.sort_by(|a, b| probabilities[*b].total_cmp(&probabilities[*a]));
+                let mut cumulative = 0f32;
+                for index in indices {
+                    if cumulative >= top_p as f32 {
+                        probabilities[index] = 0.0;
+                    } else {
+                        cumulative += probabilities[index];
+                    }
+                }
+                normalize(&mut probabilities);
+            }
+        }
+        Ok(probabilities)
+    }
+
+    /// Samples a token id from `probabilities`.
+    pub fn sample(&mut self, probabilities: &[f32]) -> u32 {
+        let mut threshold: f32 = self.rng.gen();
+        for (token, probability) in probabilities.iter().enumerate() {
+            threshold -= probability;
+            if threshold <= 0.0 {
+                return token as u32;
+            }
+        }
+        // rounding errors, fall back to the most likely token
+        probabilities
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(token, _)| token as u32)
+            .unwrap_or(0)
+    }
+
+    /// Accepts a draft token with probability `min(1, p / q)`, where `p` and `q` are the probabilities of the token
+    /// under the main and the draft model.
+    pub fn accept(&mut self, p: f32, q: f32) -> bool {
+        if q <= 0.0 {
+            return p > 0.0;
+        }
+        self.rng.gen::<f32>() < p / q
+    }
+}
+
+fn normalize(probabilities: &mut [f32]) {
+    let sum: f32 = probabilities.iter().sum();
+    if sum > 0.0 {
+        probabilities
+            .iter_mut()
+            .for_each(|probability| *probability /= sum);
+    }
+}
+
+/// When a draft token is rejected, the replacement is sampled from `norm(max(0, p - q))`.
+fn residual(p: &[f32], q: &[f32]) -> Vec<f32> {
+    let mut residual: Vec<f32> = p.iter().zip(q).map(|(p, q)| (p - q).max(0.0)).collect();
+    if residual.iter().sum::<f32>() <= 0.0 {
+        // p == q, the rejection can only come from rounding errors, sample from p
+        return p.to_vec();
+    }
+    normalize(&mut residual);
+    residual
+}
+
+/// One round of speculative decoding after `tokens` (the prompt, its first `prompt_len` tokens, and the tokens
+/// generated so far).
+///
+/// The draft model proposes `draft.num_draft_tokens` tokens, then the main model evaluates all of them in a single
+/// forward pass. Returns the accepted draft tokens followed by one token sampled from the main model, so every
+/// round generates at least one token. The KV caches of both models are rewound on the next call, the rejected
+/// tokens are simply not part of the common prefix anymore.
+pub fn speculate(
+    model_weights: &mut dyn Model,
+    cached_tokens: &mut Vec<u32>,
+    draft: &mut DraftModel,
+    tokens: &[u32],
+    prompt_len: usize,
+    sampler: &mut Sampler,
+    stats: &mut SpeculativeStats,
+) -> Result<Vec<u32>> {
+    let prompt_len = prompt_len.min(tokens.len());
+    let num_draft_tokens = draft.num_draft_tokens.max(1);
+    let mut context = tokens.to_vec();
+    let mut draft_probabilities = Vec::with_capacity(num_draft_tokens);
+    for _ in 0..num_draft_tokens {
+        let logits = prefill(draft.model_weights.as_mut(), &mut draft.cached_tokens, &context)?;
+        let logits = sampler.penalize(&logits, &context[prompt_len..])?;
+        let q = sampler.probabilities(&logits)?;
+        context.push(sampler.sample(&q));
+        draft_probabilities.push(q);
+    }
+    verify(
+        model_weights,
+        cached_tokens,
+        tokens,
+        prompt_len,
+        &context[tokens.len()..],
+        Some(&draft_probabilities),
+        sampler,
+        stats,
+    )
+}
+
+/// One round of prompt lookup decoding, the draft tokens are copied from the prompt instead of being generated by
+/// a draft model, see [`lookup`]. Returns at least one token, like [`speculate`].
+pub fn speculate_with_prompt_lookup(
+    model_weights: &mut dyn Model,
+    cached_tokens: &mut Vec<u32>,
+    tokens: &[u32],
+    prompt_len: usize,
+    sampler: &mut Sampler,
+    stats: &mut SpeculativeStats,
+) -> Result<Vec<u32>> {
+    let drafted = lookup(
+        tokens,
+        prompt_len,
+        PROMPT_LOOKUP_MAX_NGRAM,
+        PROMPT_LOOKUP_NUM_DRAFT_TOKENS,
+    );
+    verify(
+        model_weights,
+        cached_tokens,
+        tokens,
+        prompt_len,
+        drafted,
+        None,
+        sampler,
+        stats,
+    )
+}
+
+/// The longest suffix of the generated tokens looked up in the prompt.
+const PROMPT_LOOKUP_MAX_NGRAM: usize = 3;
+/// The maximum number of tokens copied from the prompt per round.
+pub const PROMPT_LOOKUP_NUM_DRAFT_TOKENS: usize = 10;
+
+/// Finds the last `n` tokens of `tokens` (for `n` from `max_ngram` down to 1) in the prompt `tokens[..prompt_len]`
+/// and returns up to `num_draft_tokens` tokens following the most recent match, or nothing when there is no match.
+///
+/// e.g. when rewriting code, the model is likely to continue `let x =` the same way the prompt did.
+pub fn lookup(
+    tokens: &[u32],
+    prompt_len: usize,
+    max_ngram: usize,
+    num_draft_tokens: usize,
+) -> &[u32] {
+    let prompt = &tokens[..prompt_len.min(tokens.len())];
+    for n in (1..=max_ngram.min(tokens.len())).rev() {
+        let ngram = &tokens[tokens.len() - n..];
+        // skip the ngram itself, it is still part of the prompt before anything is generated
+        let found = prompt
+            .windows(n)
+            .enumerate()
+            .rev()
+            .filter(|(start, _)| start + n < tokens.len())
+            .find(|(_, window)| *window == ngram);
+        if let Some((start, _)) = found {
+            let from = start + n;
+            let to = (from + num_draft_tokens).min(prompt.len());
+            if from < to {
+                return &prompt[from..to];
+            }
+        }
+    }
+    &[]
+}
+
+/// Evaluates `tokens` followed by the `drafted` tokens with the main model in one forward pass, and accepts the
+/// draft tokens with rejection sampling.
+///
+/// `draft_probabilities` are the distributions the draft tokens were sampled from, `None` means the draft tokens
+/// were chosen deterministically (e.g. copied from the prompt). In that case a draft token is accepted with
+/// probability `p`, and a rejected token is replaced by a token sampled from `p` without the draft token, which is
+/// still exact.
+///
+/// The repeat penalty at each position covers the tokens generated before it, including the draft tokens accepted
+/// so far, exactly as if the tokens were generated one by one.
+#[allow(clippy::too_many_arguments)]
+fn verify(
+    model_weights: &mut dyn Model,
+    cached_tokens: &mut Vec<u32>,
+    tokens: &[u32],
+    prompt_len: usize,
+    drafted: &[u32],
+    draft_probabilities: Option<&[Vec<f32>]>,
+    sampler: &mut Sampler,
+    stats: &mut SpeculativeStats,
+) -> Result<Vec<u32>> {
+    let mut context = tokens.to_vec();
+    context.extend_from_slice(drafted);
+    // the logits after the last token of `tokens` and after each draft token
+    let logits = evaluate(model_weights, cached_tokens, &context, drafted.len() + 1)?;
+
+    let prompt_len = prompt_len.min(tokens.len());
+    let mut accepted = Vec::with_capacity(drafted.len() + 1);
+    for (index, token) in drafted.iter().enumerate() {
+        let logits = sampler.penalize(
+            &logits.get(index)?,
+            &context[prompt_len..tokens.len() + index],
+        )?;
+        let p = sampler.probabilities(&logits)?;
+        let token = *token as usize;
+        if token >= p.len() {
+            candle_core::bail!("draft token {} is out of the vocabulary", token)
+        }
+        let (is_accepted, residual) = match draft_probabilities {
+            Some(draft_probabilities) => {
+                let q = &draft_probabilities[index];
+                if p.len() != q.len() {
+                    candle_core::bail!(
+                        "the draft model has a different vocabulary size ({}) than the main model ({})",
+                        q.len(),
+                        p.len()
+                    )
+                }
+                (sampler.accept(p[token], q[token]), residual(&p, q))
+            }
+            None => {
+                let mut residual = p.clone();
+                residual[token] = 0.0;
+                if residual.iter().sum::<f32>() <= 0.0 {
+                    residual = p.clone();
+                }
+                normalize(&mut residual);
+                (sampler.accept(p[token], 1.0), residual)
+            }
+        };
+        if is_accepted {
+            accepted.push(token as u32);
+        } else {
+            accepted.push(sampler.sample(&residual));
+            stats.add(SpeculativeStats {
+                drafted: drafted.len(),
+                accepted: index,
+            });
+            return Ok(accepted);
+        }
+    }
+    // all draft tokens were accepted, the main model gives one more token for free
+    let logits = sampler.penalize(&logits.get(drafted.len())?, &context[prompt_len..])?;
+    let p = sampler.probabilities(&logits)?;
+    accepted.push(sampler.sample(&p));
+    stats.add(SpeculativeStats {
+        drafted: drafted.len(),
+        accepted: drafted.len(),
+    });
+    Ok(accepted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn residual_removes_draft_probability() {
+        let p = vec![0.5, 0.3, 0.2];
+        let q = vec![0.1, 0.6, 0.3];
+        let residual = residual(&p, &q);
+        assert!((residual[0] - 1.0).abs() < 1e-6);
+        assert_eq!(residual[1], 0.0);
+        assert_eq!(residual[2], 0.0);
+    }
+
+    #[test]
+    fn lookup_copies_tokens_after_the_match() {
+        // prompt: 1 2 3 4 5 6, generated: 9 2 3
+        let tokens = [1, 2, 3, 4, 5, 6, 9, 2, 3];
+        assert_eq!(lookup(&tokens, 6, 3, 2), &[4, 5]);
+        // the continuation stops at the end of the prompt
+        assert_eq!(lookup(&tokens, 6, 3, 10), &[4, 5, 6]);
+    }
+
+    #[test]
+    fn lookup_prefers_the_longest_ngram() {
+        // `2 3` is followed by 7 in the prompt, `3` alone is most recently followed by 8
+        let tokens = [2, 3, 7, 0, 3, 8, 2, 3];
+        assert_eq!(lookup(&tokens, 6, 2, 1), &[7]);
+        assert_eq!(lookup(&tokens, 6, 1, 1), &[8]);
+    }
+
+    #[test]
+    fn lookup_without_match_is_empty() {
+        let tokens = [1, 2, 3, 4];
+        assert!(lookup(&tokens, 3, 3, 5).is_empty());
+    }
+
+    #[test]
+    fn greedy_probabilities_are_one_hot() {
+        let sampler = Sampler::new(0, 0.0, None);
+        let logits = Tensor::new(&[0.1f32, 2.0, -1.0], &candle_core::Device::Cpu).unwrap();
+        assert_eq!(sampler.probabilities(&logits).unwrap(), vec![0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn repeat_penalty_only_covers_the_last_generated_tokens() {
+        let sampler = Sampler::new(0, 1.0, None).with_repeat_penalty(2.0, 2);
+        let logits = Tensor::new(&[2.0f32, 2.0, -2.0], &candle_core::Device::Cpu).unwrap();
+        let penalized: Vec<f32> = sampler
+            .penalize(&logits, &[0, 1, 2])
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        // token 0 is older than the last 2 generated tokens
+        assert_eq!(penalized, vec![2.0, 1.0, -4.0]);
+    }
+
+    #[test]
+    fn top_p_keeps_most_likely_tokens() {
+        let sampler = Sampler::new(0, 1.0, Some(0.5));
+        let logits = Tensor::new(&[3.0f32, 1.0, 0.0], &candle_core::Device::Cpu).unwrap();
+        let probabilities = sampler.probabilities(&logits).unwrap();
+        assert_eq!(probabilities, vec![1.0, 0.0, 0.0]);
+    }
+}
@@ -1,6 +1,217 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use oxpilot::cmd::Command;
+use oxpilot::models::safetensors;
+use oxpilot::speculative::Decoding;
+use oxpilot::utils::fim::FimTemplate;
+use oxpilot::utils::template::PromptTemplate;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::loader::ModelLoader;
+use crate::shutdown::Shutdown;
 
 #[derive(Clone)]
 pub struct AppState {
+    /// The models served by `ox serve`, the first one is the default model.
+    /// The admin endpoints swap models at runtime, the lock is never held across an `.await`, a request clones the
+    /// `ServedModel` it needs and keeps using it even if the model is swapped in the meantime.
+    models: Arc<RwLock<Vec<ServedModel>>>,
+    /// How tokens are generated for the requests of all endpoints.
+    pub decoding: Decoding,
+    /// Builds the models loaded through the admin endpoints.
+    pub loader: ModelLoader,
+    /// The bearer token of the admin endpoints, they are disabled without one.
+    pub admin_token: Option<String>,
+    /// The models being loaded in the background, or that failed to load, by name.
+    pub loads: Arc<Mutex<HashMap<String, LoadStatus>>>,
+    /// Tracks the generations in flight, and cancels them when the server is shutting down.
+    pub shutdown: Shutdown,
+}
+
+impl AppState {
+    pub fn new(
+        models: Vec<ServedModel>,
+        decoding: Decoding,
+        loader: ModelLoader,
+        admin_token: Option<String>,
+    ) -> Self {
+        AppState {
+            models: Arc::new(RwLock::new(models)),
+            decoding,
+            loader,
+            admin_token,
+            loads: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// The model requested by name, the default model when no name is given.
+    /// Exact names win over the `"*"` alias, so a catch-all model doesn't shadow the others.
+    pub fn model(&self, name: Option<&str>) -> Result<ServedModel, ModelNotFound> {
+        let name = match name {
+            Some(name) if !name.is_empty() => name,
+            _ => return Ok(self.default_model()),
+        };
+        let models = self.models.read().unwrap();
+        models
+            .iter()
+            .find(|model| model.is_named(name))
+            .or_else(|| {
+                models
+                    .iter()
+                    .find(|model| model.aliases.iter().any(|alias| alias == "*"))
+            })
+            .cloned()
+            .ok_or_else(|| ModelNotFound(name.to_string()))
+    }
+
+    pub fn default_model(&self) -> ServedModel {
+        self.models.read().unwrap()[0].clone()
+    }
+
+    /// A snapshot of the served models, the default model first.
+    pub fn models(&self) -> Vec<ServedModel> {
+        self.models.read().unwrap().clone()
+    }
+
+    /// Serves `model` in place of the model named `replace`, or in addition to the others when there is no such
+    /// model. Returns the replaced model, dropping it lets its scheduler finish the prompts it has already received
+    /// and unload the LLM.
+    pub fn swap(&self, model: ServedModel, replace: &str) -> Option<ServedModel> {
+        let mut models = self.models.write().unwrap();
+        match models.iter().position(|served| served.info.name == replace) {
+            Some(index) => Some(std::mem::replace(&mut models[index], model)),
+            None => {
+                models.push(model);
+                None
+            }
+        }
+    }
+
+    /// Stops serving the model named `name`, the last model can't be removed.
+    pub fn remove(&self, name: &str) -> Result<ServedModel, RemoveError> {
+        let mut models = self.models.write().unwrap();
+        let index = models
+            .iter()
+            .position(|served| served.info.name == name)
+            .ok_or_else(|| RemoveError::NotFound(ModelNotFound(name.to_string())))?;
+        if models.len() == 1 {
+            return Err(RemoveError::LastModel(name.to_string()));
+        }
+        Ok(models.remove(index))
+    }
+}
+
+/// The status of a model loaded through the admin endpoints, a model is removed from `AppState::loads` once it's
+/// served.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum LoadStatus {
+    Loading,
+    Failed { error: String },
+}
+
+/// A model loaded by `ox serve` with its own scheduler task.
+#[derive(Clone)]
+pub struct ServedModel {
     pub tx: tokio::sync::mpsc::Sender<Command>,
+    pub info: ModelInfo,
+    /// Other names routed to this model, `"*"` matches any name.
+    pub aliases: Vec<String>,
+    /// How chat messages and instructions are turned into a prompt.
+    pub template: PromptTemplate,
+    /// The fill-in-the-middle template used by the infill endpoints.
+    pub fim_template: FimTemplate,
+}
+
+impl ServedModel {
+    fn is_named(&self, name: &str) -> bool {
+        self.info.name == name
+            || self.info.file_name == name
+            || self.aliases.iter().any(|alias| alias == name)
+    }
+}
+
+/// The requested model is not served, answered with `404 Not Found`.
+#[derive(Debug)]
+pub struct ModelNotFound(pub String);
+
+impl IntoResponse for ModelNotFound {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("model '{}' not found", self.0) })),
+        )
+            .into_response()
+    }
+}
+
+/// Why `AppState::remove` didn't remove a model.
+#[derive(Debug)]
+pub enum RemoveError {
+    NotFound(ModelNotFound),
+    LastModel(String),
+}
+
+impl IntoResponse for RemoveError {
+    fn into_response(self) -> Response {
+        match self {
+            RemoveError::NotFound(not_found) => not_found.into_response(),
+            RemoveError::LastModel(name) => (
+                StatusCode::CONFLICT,
+                Json(json!({ "error": format!("model '{}' is the last model served", name) })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Describes the model served by the manager task, used by the endpoints that list or describe models.
+#[derive(Clone, Debug, Default)]
+pub struct ModelInfo {
+    /// e.g. "mistral-7b-instruct-v0.2.Q4_K_M"
+    pub name: String,
+    pub repo_id: String,
+    pub file_name: String,
+    /// Size of the model file in bytes.
+    pub size: u64,
+    /// The `general.architecture` of the GGUF file, e.g. "llama" or "qwen2".
+    pub architecture: String,
+    /// "gguf", or "safetensors" for unquantized checkpoints.
+    pub format: String,
+}
+
+impl ModelInfo {
+    pub fn new(repo_id: impl Into<String>, file_name: impl Into<String>, size: u64) -> Self {
+        let file_name = file_name.into();
+        let format = if safetensors::is_safetensors(&file_name) {
+            "safetensors"
+        } else {
+            "gguf"
+        };
+        ModelInfo {
+            name: file_name.trim_end_matches(".gguf").to_string(),
+            repo_id: repo_id.into(),
+            file_name,
+            size,
+            architecture: "llama".to_string(),
+            format: format.to_string(),
+        }
+    }
+
+    /// The quantization level is encoded in the GGUF file name by convention,
+    /// e.g. "Q4_K_M" in "mistral-7b-instruct-v0.2.Q4_K_M.gguf"
+    pub fn quantization_level(&self) -> String {
+        self.name
+            .rsplit('.')
+            .next()
+            .filter(|level| level.starts_with('Q') || level.starts_with('F'))
+            .unwrap_or("unknown")
+            .to_string()
+    }
 }
@@ -0,0 +1,181 @@
+//! Randomly initialized models written as GGUF to memory, so that the tests and the benchmarks don't download a
+//! model, and GGUF metadata shared by the tests. The benchmarks are built with `--features bench`.
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use candle_core::quantized::gguf_file::{self, Value};
+use candle_core::quantized::{GgmlDType, QTensor};
+use candle_core::{DType, Device, Result, Tensor};
+
+use crate::models::quantized_llama::ModelWeights;
+
+/// The shape of a tiny llama model, the default is small enough for unit tests.
+pub struct TinyConfig {
+    pub vocab_size: usize,
+    pub embedding_length: usize,
+    pub feed_forward_length: usize,
+    pub head_count: usize,
+    pub head_count_kv: usize,
+    pub block_count: usize,
+    /// The standard deviation of the random weights.
+    pub std: f32,
+}
+
+impl Default for TinyConfig {
+    fn default() -> Self {
+        TinyConfig {
+            vocab_size: 64,
+            embedding_length: 32,
+            feed_forward_length: 64,
+            head_count: 4,
+            head_count_kv: 2,
+            block_count: 2,
+            std: 0.1,
+        }
+    }
+}
+
+pub fn tiny_model(config: &TinyConfig) -> Result<ModelWeights> {
+    let matrix = |rows: usize, columns: usize| -> Result<QTensor> {
+        QTensor::quantize(
+            &Tensor::randn(0f32, config.std, (rows, columns), &Device::Cpu)?,
+            GgmlDType::Q8_0,
+        )
+    };
+    let norm = || -> Result<QTensor> {
+        QTensor::quantize(
+            &Tensor::ones(config.embedding_length, DType::F32, &Device::Cpu)?,
+            GgmlDType::F32,
+        )
+    };
+    let mut tensors = vec![
+        (
+            "token_embd.weight".to_string(),
+            matrix(config.vocab_size, config.embedding_length)?,
+        ),
+        ("output_norm.weight".to_string(), norm()?),
+        (
+            "output.weight".to_string(),
+            matrix(config.vocab_size, config.embedding_length)?,
+        ),
+    ];
+    let head_dim = config.embedding_length / config.head_count;
+    for layer in 0..config.block_count {
+        tensors.push((
+            format!("blk.{layer}.attn_q.weight"),
+            matrix(config.embedding_length, config.embedding_length)?,
+        ));
+        for name in ["attn_k", "attn_v"] {
+            tensors.push((
+                format!("blk.{layer}.{name}.weight"),
+                matrix(head_dim * config.head_count_kv, config.embedding_length)?,
+            ));
+        }
+        tensors.push((
+            format!("blk.{layer}.attn_output.weight"),
+            matrix(config.embedding_length, config.embedding_length)?,
+        ));
+        tensors.push((
+            format!("blk.{layer}.ffn_gate.weight"),
+            matrix(config.feed_forward_length, config.embedding_length)?,
+        ));
+        tensors.push((
+            format!("blk.{layer}.ffn_up.weight"),
+            matrix(config.feed_forward_length, config.embedding_length)?,
+        ));
+        tensors.push((
+            format!("blk.{layer}.ffn_down.weight"),
+            matrix(config.embedding_length, config.feed_forward_length)?,
+        ));
+        tensors.push((format!("blk.{layer}.attn_norm.weight"), norm()?));
+        tensors.push((format!("blk.{layer}.ffn_norm.weight"), norm()?));
+    }
+    let metadata = [
+        (
+            "general.architecture",
+            gguf_file::Value::String("llama".to_string()),
+        ),
+        (
+            "llama.attention.head_count",
+            gguf_file::Value::U32(config.head_count as u32),
+        ),
+        (
+            "llama.attention.head_count_kv",
+            gguf_file::Value::U32(config.head_count_kv as u32),
+        ),
+        (
+            "llama.block_count",
+            gguf_file::Value::U32(config.block_count as u32),
+        ),
+        (
+            "llama.embedding_length",
+            gguf_file::Value::U32(config.embedding_length as u32),
+        ),
+        (
+            "llama.rope.dimension_count",
+            gguf_file::Value::U32(head_dim as u32),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(1e-5),
+        ),
+    ];
+    let metadata: Vec<(&str, &gguf_file::Value)> =
+        metadata.iter().map(|(key, value)| (*key, value)).collect();
+    let tensors: Vec<(&str, &QTensor)> = tensors
+        .iter()
+        .map(|(name, tensor)| (name.as_str(), tensor))
+        .collect();
+
+    let mut file = Cursor::new(vec![]);
+    gguf_file::write(&mut file, &metadata, &tensors)?;
+    file.set_position(0);
+    let content = gguf_file::Content::read(&mut file)?;
+    ModelWeights::from_gguf(content, &mut file)
+}
+
+/// The tokenizer metadata of a tiny ChatML model, `<|im_end|>` ends the turns but the GGUF file only sets the EOS
+/// token.
+pub fn chat_ml_metadata() -> HashMap<String, Value> {
+    let tokens = [
+        "<unk>",
+        "<s>",
+        "</s>",
+        "▁",
+        "h",
+        "i",
+        "<|im_start|>",
+        "<|im_end|>",
+    ];
+    let token_types = [2, 3, 3, 1, 1, 1, 3, 3];
+    HashMap::from([
+        (
+            "tokenizer.ggml.model".to_string(),
+            Value::String("llama".to_string()),
+        ),
+        (
+            "tokenizer.ggml.tokens".to_string(),
+            Value::Array(
+                tokens
+                    .iter()
+                    .map(|token| Value::String(token.to_string()))
+                    .collect(),
+            ),
+        ),
+        (
+            "tokenizer.ggml.scores".to_string(),
+            Value::Array(tokens.iter().map(|_| Value::F32(0.0)).collect()),
+        ),
+        (
+            "tokenizer.ggml.token_type".to_string(),
+            Value::Array(
+                token_types
+                    .iter()
+                    .map(|token_type| Value::I32(*token_type))
+                    .collect(),
+            ),
+        ),
+        ("tokenizer.ggml.bos_token_id".to_string(), Value::U32(1)),
+        ("tokenizer.ggml.eos_token_id".to_string(), Value::U32(2)),
+    ])
+}
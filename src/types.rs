@@ -2,6 +2,9 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
+use crate::cmd::Priority;
+use crate::registry::ModelEntry;
+
 // Acknowledgements:
 // https://github.com/AmineDiro/cria/blob/main/src/routes/completions.rs
 // https://github.com/64bit/async-openai/blob/main/async-openai/src/types/types.rs
@@ -97,4 +100,317 @@ pub struct CompletionRequest {
     pub best_of: Option<usize>,
     pub seed: Option<u64>,
     pub user: Option<String>,
+    /// Not part of the OpenAI API, overrides the priority class of the route, e.g. `"priority": "batch"`.
+    pub priority: Option<Priority>,
+}
+
+/// A message in a chat conversation, shared by the chat-style endpoints.
+/// `role` is one of "system", "user" or "assistant".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+// Ollama API types
+// https://github.com/ollama/ollama/blob/main/docs/api.md
+
+/// The `options` object accepted by Ollama's `/api/generate` and `/api/chat`.
+/// Only the options that we can map onto the generation pipeline are listed, the rest are ignored.
+#[derive(Deserialize, Debug, Default)]
+pub struct OllamaOptions {
+    pub temperature: Option<f64>,
+    /// Maximum number of tokens to predict, `-1` means infinite generation.
+    pub num_predict: Option<i64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub stop: Option<Vec<String>>,
+}
+
+/// The request body for `POST /api/generate`.
+#[derive(Deserialize, Debug)]
+pub struct OllamaGenerateRequest {
+    pub model: Option<String>,
+    pub prompt: Option<String>,
+    pub suffix: Option<String>,
+    pub system: Option<String>,
+    pub template: Option<String>,
+    /// Ollama streams by default, `"stream": false` returns a single response object.
+    pub stream: Option<bool>,
+    /// When `true` no formatting is applied to the prompt.
+    pub raw: Option<bool>,
+    pub options: Option<OllamaOptions>,
+    pub keep_alive: Option<serde_json::Value>,
+    /// Overrides the priority class of the route, see `CompletionRequest::priority`.
+    pub priority: Option<Priority>,
+}
+
+/// The request body for `POST /api/chat`.
+#[derive(Deserialize, Debug)]
+pub struct OllamaChatRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    pub stream: Option<bool>,
+    pub options: Option<OllamaOptions>,
+    pub keep_alive: Option<serde_json::Value>,
+    /// Overrides the priority class of the route, see `CompletionRequest::priority`.
+    pub priority: Option<Priority>,
+}
+
+/// The request body for `POST /api/show`, older clients send `name` instead of `model`.
+#[derive(Deserialize, Debug)]
+pub struct OllamaShowRequest {
+    pub model: Option<String>,
+    pub name: Option<String>,
+}
+
+/// One line of the newline-delimited JSON stream of `/api/generate`.
+/// The last line has `done: true` and carries the timing statistics.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OllamaGenerateResponse {
+    pub model: String,
+    /// RFC 3339 timestamp.
+    pub created_at: String,
+    pub response: String,
+    pub done: bool,
+    #[serde(flatten)]
+    pub stats: Option<OllamaStats>,
+}
+
+/// One line of the newline-delimited JSON stream of `/api/chat`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OllamaChatResponse {
+    pub model: String,
+    pub created_at: String,
+    pub message: ChatMessage,
+    pub done: bool,
+    #[serde(flatten)]
+    pub stats: Option<OllamaStats>,
+}
+
+/// Statistics attached to the final object of a generation, durations are in nanoseconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OllamaStats {
+    pub done_reason: String,
+    pub total_duration: u128,
+    pub load_duration: u128,
+    pub prompt_eval_count: usize,
+    pub prompt_eval_duration: u128,
+    pub eval_count: usize,
+    pub eval_duration: u128,
+}
+
+/// The response body of `GET /api/tags`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OllamaTags {
+    pub models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OllamaModel {
+    pub name: String,
+    pub model: String,
+    pub modified_at: String,
+    pub size: u64,
+    pub digest: String,
+    pub details: OllamaModelDetails,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OllamaModelDetails {
+    pub format: String,
+    pub family: String,
+    pub parameter_size: String,
+    pub quantization_level: String,
+}
+
+/// The response body of `POST /api/show`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OllamaShowResponse {
+    pub modelfile: String,
+    pub parameters: String,
+    pub template: String,
+    pub details: OllamaModelDetails,
+}
+
+// Hugging Face Text Generation Inference (TGI) API types
+// https://huggingface.github.io/text-generation-inference/
+
+/// The `parameters` object of TGI's `/generate` and `/generate_stream`.
+#[derive(Deserialize, Debug, Default)]
+pub struct TgiParameters {
+    pub max_new_tokens: Option<usize>,
+    pub stop: Option<Vec<String>>,
+    /// Whether to return generation details.
+    pub details: Option<bool>,
+    /// Whether to prepend the prompt to the generated text.
+    pub return_full_text: Option<bool>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub do_sample: Option<bool>,
+    pub seed: Option<u64>,
+    pub repetition_penalty: Option<f32>,
+    /// Overrides the priority class of the route, see `CompletionRequest::priority`.
+    pub priority: Option<Priority>,
+}
+
+/// The request body of TGI's `/generate` and `/generate_stream`.
+#[derive(Deserialize, Debug)]
+pub struct TgiRequest {
+    pub inputs: String,
+    pub parameters: Option<TgiParameters>,
+    pub stream: Option<bool>,
+}
+
+/// The response body of `/generate`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TgiResponse {
+    pub generated_text: String,
+    pub details: Option<TgiDetails>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TgiDetails {
+    /// "length", "eos_token" or "stop_sequence"
+    pub finish_reason: String,
+    pub generated_tokens: usize,
+    pub seed: Option<u64>,
+}
+
+/// A token event of `/generate_stream`, `generated_text` and `details` are only set on the last event.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TgiStreamResponse {
+    pub token: TgiToken,
+    pub generated_text: Option<String>,
+    pub details: Option<TgiDetails>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TgiToken {
+    /// The manager task streams text, not token ids, so the id is always 0.
+    pub id: u32,
+    pub text: String,
+    pub logprob: f32,
+    pub special: bool,
+}
+
+/// The error body of TGI, also sent as the last event of `/generate_stream` when the generation fails.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TgiError {
+    pub error: String,
+    pub error_type: String,
+}
+
+// llama.cpp server API types
+// https://github.com/ggerganov/llama.cpp/blob/master/examples/server/README.md
+
+/// The request body of llama.cpp's `/completion` and `/infill`.
+/// `/infill` takes `input_prefix`, `input_suffix` and `input_extra` instead of `prompt`.
+#[derive(Deserialize, Debug, Default)]
+pub struct LlamaCppRequest {
+    pub prompt: Option<String>,
+    pub input_prefix: Option<String>,
+    pub input_suffix: Option<String>,
+    /// Additional context, e.g. other files of the project, inserted before the prefix.
+    pub input_extra: Option<Vec<LlamaCppExtraChunk>>,
+    /// Maximum number of tokens to predict, `-1` means infinite generation.
+    pub n_predict: Option<i64>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub repeat_penalty: Option<f32>,
+    pub stop: Option<Vec<String>>,
+    pub stream: Option<bool>,
+    pub cache_prompt: Option<bool>,
+    /// Overrides the priority class of the route, see `CompletionRequest::priority`.
+    pub priority: Option<Priority>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LlamaCppExtraChunk {
+    pub filename: Option<String>,
+    pub text: String,
+}
+
+/// The response of `/completion` and `/infill`. When streaming, every event has `content` and `stop`, and the last
+/// event (`stop: true`) additionally carries the summary.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaCppResponse {
+    pub content: String,
+    pub stop: bool,
+    #[serde(flatten)]
+    pub summary: Option<LlamaCppSummary>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaCppSummary {
+    pub model: String,
+    pub tokens_predicted: usize,
+    pub tokens_evaluated: usize,
+    pub stopped_eos: bool,
+    pub stopped_limit: bool,
+    pub stopped_word: bool,
+    pub stopping_word: String,
+    pub truncated: bool,
+    pub timings: LlamaCppTimings,
+}
+
+/// Durations are in milliseconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaCppTimings {
+    pub prompt_n: usize,
+    pub prompt_ms: f64,
+    pub prompt_per_token_ms: f64,
+    pub prompt_per_second: f64,
+    pub predicted_n: usize,
+    pub predicted_ms: f64,
+    pub predicted_per_token_ms: f64,
+    pub predicted_per_second: f64,
+}
+
+// WebSocket streaming protocol of `GET /ws`, one JSON object per text frame.
+
+/// A message sent by the client over the WebSocket.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WsClientMessage {
+    /// Starts a generation, cancelling the current one if any.
+    Prompt {
+        prompt: String,
+        /// The model to use, default to the default model.
+        model: Option<String>,
+        temperature: Option<f64>,
+        max_tokens: Option<usize>,
+        priority: Option<Priority>,
+    },
+    /// Stops the current generation now, `"stop"` is accepted too.
+    #[serde(alias = "stop")]
+    Cancel,
+}
+
+/// A message sent by the server over the WebSocket.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WsServerMessage {
+    /// The next text of the current generation.
+    Token {
+        text: String,
+    },
+    /// The current generation ended, `reason` is `"stop"` when the model stopped by itself, `"cancelled"` otherwise.
+    Done {
+        reason: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// The body of `POST /admin/models`, a model entry as in the model registry.
+#[derive(Deserialize, Debug)]
+pub struct AdminLoadRequest {
+    #[serde(flatten)]
+    pub entry: ModelEntry,
+    /// The name of the served model to replace once the new model is ready, default to the name of the new model,
+    /// the model is added to the served models when no model has that name.
+    pub replace: Option<String>,
 }
@@ -1,5 +1,5 @@
 /// Get the diff of the staged files. None if there is no diff/failed to get the diff.
-pub fn commit_then_exit(commit_message: &str, signoff: bool) -> () {
+pub fn commit_then_exit(commit_message: &str, signoff: bool) {
     let mut git = std::process::Command::new("git");
     git.arg("commit").arg("-m").arg(commit_message);
 
@@ -15,5 +15,5 @@ pub async fn get_diff(function_context: bool) -> String {
         git.arg("--function-context");
     }
     let output = git.output().await.expect("failed to execute diff");
-    return String::from_utf8(output.stdout).expect("failed to parse diff stdout");
+    String::from_utf8(output.stdout).expect("failed to parse diff stdout")
 }
@@ -0,0 +1,68 @@
+/// Fill-in-the-middle (FIM) prompt templates, used to ask a code model to complete the code between a prefix and a
+/// suffix, e.g. the code before and after the cursor.
+///
+/// `clap::ValueEnum` lets clap parse the enum from CLI arguments, e.g. `--fim-template code-llama`, and
+/// `serde::Deserialize` from the model registry file with the same names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FimTemplate {
+    /// No FIM tokens, the model continues the prefix, works with any model.
+    #[default]
+    Prefix,
+    /// `<PRE> {prefix} <SUF>{suffix} <MID>`, see https://huggingface.co/codellama/CodeLlama-7b-hf
+    CodeLlama,
+    /// `<fim_prefix>{prefix}<fim_suffix>{suffix}<fim_middle>`, see https://huggingface.co/bigcode/starcoder
+    StarCoder,
+    /// `<｜fim▁begin｜>{prefix}<｜fim▁hole｜>{suffix}<｜fim▁end｜>`, see https://huggingface.co/deepseek-ai/deepseek-coder-6.7b-base
+    DeepSeek,
+}
+
+impl FimTemplate {
+    /// returns the prompt to be sent to the model
+    pub fn format(&self, prefix: &str, suffix: &str) -> String {
+        match self {
+            FimTemplate::Prefix => prefix.to_string(),
+            FimTemplate::CodeLlama => format!("<PRE> {} <SUF>{} <MID>", prefix, suffix),
+            FimTemplate::StarCoder => {
+                format!("<fim_prefix>{}<fim_suffix>{}<fim_middle>", prefix, suffix)
+            }
+            FimTemplate::DeepSeek => format!(
+                "<｜fim▁begin｜>{}<｜fim▁hole｜>{}<｜fim▁end｜>",
+                prefix, suffix
+            ),
+        }
+    }
+}
+
+/// Keep at most the last `max_chars` characters of `text`, without splitting a UTF-8 character.
+pub fn tail(text: &str, max_chars: usize) -> &str {
+    if max_chars == 0 {
+        return "";
+    }
+    match text.char_indices().rev().nth(max_chars - 1) {
+        Some((index, _)) => &text[index..],
+        None => text,
+    }
+}
+
+/// Keep at most the first `max_chars` characters of `text`, without splitting a UTF-8 character.
+pub fn head(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => &text[..index],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn head_and_tail_respect_char_boundaries() {
+        assert_eq!(tail("héllo", 3), "llo");
+        assert_eq!(tail("héllo", 10), "héllo");
+        assert_eq!(tail("héllo", 0), "");
+        assert_eq!(head("héllo", 2), "hé");
+        assert_eq!(head("héllo", 10), "héllo");
+    }
+}
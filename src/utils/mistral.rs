@@ -1,3 +1,5 @@
+use crate::types::ChatMessage;
+
 /// returns a string of the prompt to be sent to the Mistral Instruct Models
 /// e.g. `<s>[INST] {instruction} [/INST]`
 /// see https://huggingface.co/mistralai/Mistral-7B-Instruct-v0.2
@@ -5,3 +7,60 @@ pub fn instruct(instruction: impl Into<String>) -> String {
     let instruction = instruction.into();
     format!("<s>[INST] {} [/INST] ", instruction)
 }
+
+/// returns a string of a multi-turn conversation to be sent to the Mistral Instruct Models
+/// e.g. `<s>[INST] {user} [/INST] {assistant}</s>[INST] {user} [/INST] `
+///
+/// Mistral Instruct has no system role, system messages are prepended to the next user message.
+pub fn chat(messages: &[ChatMessage]) -> String {
+    let mut prompt = String::from("<s>");
+    let mut system = String::new();
+    for message in messages {
+        match message.role.as_str() {
+            "system" => {
+                system.push_str(&message.content);
+                system.push_str("\n\n");
+            }
+            "assistant" => {
+                prompt.push_str(&format!("{}</s>", message.content));
+            }
+            // treat unknown roles as user messages
+            _ => {
+                prompt.push_str(&format!("[INST] {}{} [/INST] ", system, message.content));
+                system.clear();
+            }
+        }
+    }
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_merges_system_into_first_user_message() {
+        let messages = vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: "Be brief.".to_string(),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: "Hi".to_string(),
+            },
+            ChatMessage {
+                role: "assistant".to_string(),
+                content: "Hello!".to_string(),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: "Bye".to_string(),
+            },
+        ];
+        assert_eq!(
+            chat(&messages),
+            "<s>[INST] Be brief.\n\nHi [/INST] Hello!</s>[INST] Bye [/INST] "
+        );
+    }
+}
@@ -1,4 +1,8 @@
 pub mod commit;
 pub mod diff;
+pub mod fim;
 pub mod mistral;
+pub mod models;
 pub mod spinner;
+pub mod stop;
+pub mod template;
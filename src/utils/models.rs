@@ -0,0 +1,114 @@
+//! `ox models`, the subcommands managing the models cached in `~/.oxpilot`, see `cache::Cache`.
+use anyhow::{anyhow, Result};
+use chrono::TimeZone;
+
+use crate::cache::{format_size, Cache, CachedFile, Hub, Verification};
+use crate::cli::ModelsCommand;
+
+pub async fn run(command: &ModelsCommand, cache: &Cache) -> Result<()> {
+    match command {
+        ModelsCommand::List => {
+            let files = cache.list()?;
+            if files.is_empty() {
+                println!("no models in {}", cache.dir().display());
+                return Ok(());
+            }
+            print_files(&files);
+            let total: u64 = files.iter().map(|file| file.size).sum();
+            println!("{} in {}", format_size(total), cache.dir().display());
+        }
+        ModelsCommand::Pull {
+            repo_id,
+            file_name,
+            revision,
+        } => {
+            let hub = Hub {
+                cache_dir: cache.dir().to_path_buf(),
+                offline: false,
+            };
+            for path in hub.pull(repo_id, revision, file_name).await? {
+                println!("{}", path.display());
+            }
+        }
+        ModelsCommand::Rm { repo_id, file_name } => {
+            let removed = cache.remove(repo_id, file_name.as_deref())?;
+            let freed: u64 = removed.iter().map(|file| file.size).sum();
+            println!(
+                "removed {} files, {} freed",
+                removed.len(),
+                format_size(freed)
+            );
+        }
+        ModelsCommand::Verify { repo_id } => {
+            let mut corrupted = 0;
+            for file in cache.list()? {
+                if repo_id
+                    .as_ref()
+                    .is_some_and(|repo_id| repo_id != &file.repo_id)
+                {
+                    continue;
+                }
+                let status = match cache.verify(&file)? {
+                    Verification::Valid => "ok".to_string(),
+                    Verification::Corrupted { expected, actual } => {
+                        corrupted += 1;
+                        format!("CORRUPTED, sha256 {} instead of {}", actual, expected)
+                    }
+                    Verification::Unknown { .. } => "unknown, no sha256 to compare to".to_string(),
+                };
+                println!("{} {}: {}", file.repo_id, file.file_name, status);
+            }
+            if corrupted > 0 {
+                return Err(anyhow!(
+                    "{} corrupted files, remove them with `ox models rm` and pull them again",
+                    corrupted
+                ));
+            }
+        }
+        ModelsCommand::Gc { keep, dry_run } => {
+            let removed = cache.gc(*keep, *dry_run)?;
+            print_files(&removed);
+            let freed: u64 = removed.iter().map(|file| file.size).sum();
+            if *dry_run {
+                println!("{} would be freed", format_size(freed));
+            } else {
+                println!("{} freed", format_size(freed));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// One file per line, in columns.
+fn print_files(files: &[CachedFile]) {
+    let repo_width = files
+        .iter()
+        .map(|file| file.repo_id.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let file_width = files
+        .iter()
+        .map(|file| file.file_name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:repo_width$}  {:file_width$}  {:>10}  LAST USED",
+        "REPO", "FILE", "SIZE"
+    );
+    for file in files {
+        let last_used = file
+            .last_used
+            .and_then(|last_used| chrono::Local.timestamp_opt(last_used as i64, 0).single())
+            .map(|last_used| last_used.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or("never".to_string());
+        println!(
+            "{:repo_width$}  {:file_width$}  {:>10}  {}",
+            file.repo_id,
+            file.file_name,
+            format_size(file.size),
+            last_used
+        );
+    }
+}
@@ -23,21 +23,18 @@ impl SilentableSpinner {
         }
     }
     pub fn update(&mut self, message: impl Into<String>) {
-        match &mut self.spinner {
-            Some(spinner) => spinner.update(spinners::Dots, message.into(), Color::Blue),
-            None => (),
+        if let Some(spinner) = &mut self.spinner {
+            spinner.update(spinners::Dots, message.into(), Color::Blue);
         }
     }
     pub fn success(&mut self, message: &str) {
-        match &mut self.spinner {
-            Some(spinner) => spinner.success(message),
-            None => (),
+        if let Some(spinner) = &mut self.spinner {
+            spinner.success(message);
         }
     }
     pub fn fail(&mut self, message: &str) {
-        match &mut self.spinner {
-            Some(spinner) => spinner.fail(message),
-            None => (),
+        if let Some(spinner) = &mut self.spinner {
+            spinner.fail(message);
         }
     }
 }
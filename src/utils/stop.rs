@@ -0,0 +1,110 @@
+/// Watches the generated text for stop sequences, e.g. `stop: ["\n\n"]` in the request body.
+///
+/// The model streams text token by token and a stop sequence can span several tokens, so text that could be the
+/// beginning of a stop sequence is held back until it is clear whether it is one or not.
+/// ```
+/// use oxpilot::utils::stop::StopSequences;
+/// let mut stop = StopSequences::new(vec!["\n\n".to_string()]);
+/// assert_eq!(stop.push("fn main() {}\n"), "fn main() {}");
+/// assert_eq!(stop.push("\nfn"), "");
+/// assert!(stop.is_stopped());
+/// ```
+#[derive(Debug, Default)]
+pub struct StopSequences {
+    sequences: Vec<String>,
+    held: String,
+    matched: Option<String>,
+}
+
+impl StopSequences {
+    pub fn new(sequences: Vec<String>) -> Self {
+        StopSequences {
+            // an empty stop sequence would stop at the first token
+            sequences: sequences.into_iter().filter(|s| !s.is_empty()).collect(),
+            held: String::new(),
+            matched: None,
+        }
+    }
+
+    /// Push the next generated text, returns the text that is safe to send to the client.
+    /// Once a stop sequence is found, returns the text before it and then always an empty string.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.is_stopped() {
+            return String::new();
+        }
+        self.held.push_str(text);
+
+        let first_match = self
+            .sequences
+            .iter()
+            .filter_map(|sequence| Some((self.held.find(sequence.as_str())?, sequence)))
+            .min_by_key(|(index, _)| *index);
+        if let Some((index, sequence)) = first_match {
+            self.matched = Some(sequence.clone());
+            let text = self.held[..index].to_string();
+            self.held.clear();
+            return text;
+        }
+
+        // hold back the longest suffix that is a prefix of a stop sequence
+        let hold_from = self
+            .held
+            .char_indices()
+            .map(|(index, _)| index)
+            .find(|index| {
+                let suffix = &self.held[*index..];
+                self.sequences
+                    .iter()
+                    .any(|sequence| sequence.starts_with(suffix))
+            })
+            .unwrap_or(self.held.len());
+        let text = self.held[..hold_from].to_string();
+        self.held.drain(..hold_from);
+        text
+    }
+
+    /// Returns the held back text when the generation ends without hitting a stop sequence.
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.matched.is_some()
+    }
+
+    /// The stop sequence that stopped the generation.
+    pub fn matched(&self) -> Option<&str> {
+        self.matched.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_sequence_spanning_tokens() {
+        let mut stop = StopSequences::new(vec!["</code>".to_string()]);
+        assert_eq!(stop.push("let a = 1;<"), "let a = 1;");
+        assert_eq!(stop.push("/co"), "");
+        assert_eq!(stop.push("de> ignored"), "");
+        assert_eq!(stop.matched(), Some("</code>"));
+        assert_eq!(stop.push("more"), "");
+    }
+
+    #[test]
+    fn held_text_is_released_when_not_a_stop_sequence() {
+        let mut stop = StopSequences::new(vec!["\n\n".to_string()]);
+        assert_eq!(stop.push("a\n"), "a");
+        assert_eq!(stop.push("b\n"), "\nb");
+        assert_eq!(stop.flush(), "\n");
+        assert!(!stop.is_stopped());
+    }
+
+    #[test]
+    fn no_stop_sequences() {
+        let mut stop = StopSequences::new(vec!["".to_string()]);
+        assert_eq!(stop.push("hello"), "hello");
+        assert_eq!(stop.flush(), "");
+    }
+}
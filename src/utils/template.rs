@@ -0,0 +1,195 @@
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use crate::types::ChatMessage;
+use crate::utils::mistral;
+
+/// How instructions and conversations are turned into a prompt, each instruct model is fine-tuned on its own format.
+///
+/// `rename_all = "kebab-case"` matches the names used on the command line by `clap::ValueEnum`, e.g. `chat-ml`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptTemplate {
+    /// `[INST] {instruction} [/INST]`, Mistral Instruct and Llama 2 Chat, see `utils::mistral`.
+    #[default]
+    Mistral,
+    /// `<|im_start|>user\n{instruction}<|im_end|>`, e.g. OpenHermes, Qwen or Yi.
+    /// See https://github.com/openai/openai-python/blob/release-v0.28.0/chatml.md
+    ChatMl,
+    /// No template, for base models such as code completion models, messages are joined with blank lines.
+    Raw,
+}
+
+impl PromptTemplate {
+    /// returns the prompt of a single instruction
+    pub fn instruct(&self, instruction: impl Into<String>) -> String {
+        let instruction = instruction.into();
+        match self {
+            PromptTemplate::Mistral => mistral::instruct(instruction),
+            PromptTemplate::ChatMl => format!(
+                "<|im_start|>user\n{}<|im_end|>\n<|im_start|>assistant\n",
+                instruction
+            ),
+            PromptTemplate::Raw => instruction,
+        }
+    }
+
+    /// returns the prompt of a multi-turn conversation, ending where the assistant should answer
+    pub fn chat(&self, messages: &[ChatMessage]) -> String {
+        match self {
+            PromptTemplate::Mistral => mistral::chat(messages),
+            PromptTemplate::ChatMl => {
+                let mut prompt = String::new();
+                for message in messages {
+                    prompt.push_str(&format!(
+                        "<|im_start|>{}\n{}<|im_end|>\n",
+                        message.role, message.content
+                    ));
+                }
+                prompt.push_str("<|im_start|>assistant\n");
+                prompt
+            }
+            PromptTemplate::Raw => messages
+                .iter()
+                .map(|message| message.content.as_str())
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        }
+    }
+
+    /// The template in Ollama's Go template syntax, returned by `/api/show`.
+    pub fn ollama_template(&self) -> &'static str {
+        match self {
+            PromptTemplate::Mistral => "[INST] {{ .Prompt }} [/INST]",
+            PromptTemplate::ChatMl => {
+                "<|im_start|>user\n{{ .Prompt }}<|im_end|>\n<|im_start|>assistant\n"
+            }
+            PromptTemplate::Raw => "{{ .Prompt }}",
+        }
+    }
+}
+
+/// Renders the `template` of an Ollama `/api/generate` request, which replaces the template of the model.
+///
+/// Only a subset of Go's template syntax is supported, the variables `.System` and `.Prompt`, `{{ if }}`,
+/// `{{ else }}` and `{{ end }}` around them, and the `{{-`/`-}}` whitespace trimming. The prompt ends at
+/// `{{ .Response }}`, where the model continues. Any other action is an error, rather than a prompt the model was
+/// never meant to see.
+/// ```
+/// use oxpilot::utils::template::render_ollama_template;
+/// let template = "{{ if .System }}{{ .System }}\n{{ end }}Q: {{ .Prompt }}\nA: {{ .Response }}";
+/// assert_eq!(render_ollama_template(template, None, "1 + 1?").unwrap(), "Q: 1 + 1?\nA: ");
+/// ```
+pub fn render_ollama_template(
+    template: &str,
+    system: Option<&str>,
+    prompt: &str,
+) -> Result<String> {
+    let system = system.unwrap_or_default();
+    let value = |variable: &str| match variable {
+        ".System" => Ok(system),
+        ".Prompt" => Ok(prompt),
+        _ => Err(anyhow!("unsupported template variable `{}`", variable)),
+    };
+    let mut rendered = String::new();
+    // one entry per enclosing `{{ if }}`, the text is only rendered when all of them are true
+    let mut conditions: Vec<bool> = vec![];
+    let mut trim_start = false;
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("unclosed `{{{{` in template"))?;
+        let action = &rest[start + 2..end];
+        let mut text = &rest[..start];
+        if trim_start {
+            text = text.trim_start();
+        }
+        if action.starts_with('-') {
+            text = text.trim_end();
+        }
+        trim_start = action.ends_with('-');
+        rest = &rest[end + 2..];
+
+        let is_rendered = conditions.iter().all(|condition| *condition);
+        if is_rendered {
+            rendered.push_str(text);
+        }
+        let action = action.trim_matches('-').trim();
+        match action.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [".Response"] if is_rendered => return Ok(rendered),
+            [".Response"] => {}
+            [variable] if variable.starts_with('.') => {
+                let value = value(variable)?;
+                if is_rendered {
+                    rendered.push_str(value);
+                }
+            }
+            ["if", variable] => conditions.push(!value(variable)?.is_empty()),
+            ["else"] => match conditions.last_mut() {
+                Some(condition) => *condition = !*condition,
+                None => bail!("`{{{{ else }}}}` without `{{{{ if }}}}` in template"),
+            },
+            ["end"] => {
+                conditions
+                    .pop()
+                    .ok_or_else(|| anyhow!("`{{{{ end }}}}` without `{{{{ if }}}}` in template"))?;
+            }
+            _ => bail!("unsupported template action `{{{{ {} }}}}`", action),
+        }
+    }
+    if !conditions.is_empty() {
+        bail!("`{{{{ if }}}}` without `{{{{ end }}}}` in template");
+    }
+    rendered.push_str(if trim_start { rest.trim_start() } else { rest });
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_ml_ends_with_assistant_turn() {
+        let messages = vec![
+            ChatMessage {
+                role: "system".to_string(),
+                content: "Be brief.".to_string(),
+            },
+            ChatMessage {
+                role: "user".to_string(),
+                content: "Hi".to_string(),
+            },
+        ];
+        assert_eq!(
+            PromptTemplate::ChatMl.chat(&messages),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn raw_leaves_instruction_untouched() {
+        assert_eq!(PromptTemplate::Raw.instruct("fn main() {"), "fn main() {");
+    }
+
+    #[test]
+    fn ollama_template_with_system_and_trimming() {
+        let template = "{{- if .System }}<|im_start|>system\n{{ .System }}<|im_end|>\n{{ end -}}\n<|im_start|>user\n{{ .Prompt }}<|im_end|>\n<|im_start|>assistant\n{{ .Response }}<|im_end|>";
+        assert_eq!(
+            render_ollama_template(template, Some("Be brief."), "Hi").unwrap(),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            render_ollama_template(template, None, "Hi").unwrap(),
+            "<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn ollama_template_rejects_unsupported_actions() {
+        assert!(render_ollama_template("{{ range .Messages }}{{ end }}", None, "Hi").is_err());
+        assert!(render_ollama_template("{{ .Suffix }}", None, "Hi").is_err());
+        assert!(render_ollama_template("{{ if .System }}", None, "Hi").is_err());
+    }
+}